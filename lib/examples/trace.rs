@@ -8,8 +8,10 @@ use failure::err_msg;
 use failure::Error;
 
 use librezip::all_refs::AllRefs;
+use librezip::detect;
 use librezip::serialise_trace;
 use librezip::trace;
+use librezip::zlib;
 use librezip::Block;
 use librezip::CircularBuffer;
 use librezip::Code;
@@ -21,10 +23,22 @@ fn main() -> Result<(), Error> {
     let input = env::args()
         .nth(1)
         .ok_or_else(|| err_msg("first argument: input-path.gz"))?;
-    let mut reader = io::BufReader::new(fs::File::open(input)?);
-    librezip::gzip::discard_header(&mut reader)?;
+    let raw = fs::read(input)?;
+
+    let mut reader = io::Cursor::new(&raw);
+    let window_bits = skip_header(&mut reader)?;
+    let detected = detect::detect_window(librezip::parse_deflate(&mut reader), window_bits)?
+        .ok_or_else(|| err_msg("no compressed blocks"))?;
+    println!(
+        "detected: {:?} (confidence: {:.3})",
+        detected.config, detected.confidence
+    );
+
+    let mut reader = io::Cursor::new(&raw);
+    skip_header(&mut reader)?;
 
     let mut dictionary = CircularBuffer::new();
+    let mut pos = 0;
 
     for (id, block) in librezip::parse_deflate(&mut reader).into_iter().enumerate() {
         let block = block?;
@@ -35,14 +49,15 @@ fn main() -> Result<(), Error> {
             Uncompressed(data) => {
                 println!(" - uncompressed: {} bytes", data.len());
                 dictionary.extend(&data);
+                pos += data.len();
             }
             FixedHuffman(codes) => {
                 println!(" - fixed huffman:");
-                print(&mut dictionary, &codes)?;
+                print(&mut dictionary, &mut pos, &codes, detected.config)?;
             }
             DynamicHuffman { trees, codes } => {
                 println!(" - dynamic huffman: {:?}", trees);
-                print(&mut dictionary, &codes)?;
+                print(&mut dictionary, &mut pos, &codes, detected.config)?;
             }
         }
     }
@@ -50,11 +65,29 @@ fn main() -> Result<(), Error> {
     Ok(())
 }
 
-fn print(dictionary: &mut CircularBuffer, codes: &[Code]) -> Result<(), Error> {
+/// Read the gzip or zlib header, returning the window the stream declares.
+fn skip_header(reader: &mut io::Cursor<&Vec<u8>>) -> Result<u8, Error> {
+    Ok(match reader.get_ref().as_slice() {
+        &[cmf, flg, ..] if zlib::is_header(cmf, flg) => zlib::Header::read(reader)?.window_bits(),
+        _ => {
+            librezip::gzip::GzipHeader::read(reader)?;
+            15
+        }
+    })
+}
+
+fn print(
+    dictionary: &mut CircularBuffer,
+    pos: &mut usize,
+    codes: &[Code],
+    config: Config,
+) -> Result<(), Error> {
     let old_dictionary = &dictionary.vec();
+    let preroll_start = *pos - old_dictionary.len();
 
     let mut decompressed: Vec<u8> = Vec::with_capacity(codes.len());
     librezip::decompressed_codes(&mut decompressed, dictionary, codes)?;
+    *pos += decompressed.len();
 
     if false {
         print!("   * codes: ");
@@ -70,53 +103,23 @@ fn print(dictionary: &mut CircularBuffer, codes: &[Code]) -> Result<(), Error> {
         println!();
     }
 
-    let refs_1 = AllRefs::with_sixteen(old_dictionary, &decompressed, 4);
-    let refs_3 = AllRefs::with_sixteen(old_dictionary, &decompressed, 6);
-    let all_refs = AllRefs::with_sixteen(old_dictionary, &decompressed, ::std::u16::MAX);
-
-    // TODO: all_refs.apply_first_byte_bug_rule();
-
-    if false {
-        println!("refs_1:\n{:?}", refs_1);
-        println!("refs_3:\n{:?}", refs_3);
-        println!("refs_all:\n{:?}", all_refs);
-    }
+    let mut all_refs = AllRefs::with_hash(
+        old_dictionary,
+        &decompressed,
+        ::std::u16::MAX,
+        config.hash_bits,
+        config.max_distance,
+    );
 
-    if true {
-        try_trace(
-            &refs_1,
-            "gzip --fast",
-            Config::gzip(1),
-            codes,
-            &decompressed,
-        );
+    if config.first_byte_bug {
+        all_refs.apply_first_byte_bug_rule(preroll_start);
     }
 
     if false {
-        try_trace(&refs_3, "gzip -3", Config::gzip(3), codes, &decompressed);
-    }
-
-    if true {
-        // TODO: gzip --default doesn't actually use all-refs, but it's close enough for our purpose
-        // TODO: I HOPE.
-        try_trace(
-            &all_refs,
-            "gzip [--default]",
-            Config::gzip_16_default(),
-            codes,
-            &decompressed,
-        );
+        println!("refs_all:\n{:?}", all_refs);
     }
 
-    if false {
-        try_trace(
-            &all_refs,
-            "gzip --best",
-            Config::gzip(9),
-            codes,
-            &decompressed,
-        );
-    }
+    try_trace(&all_refs, "detected", config, codes, &decompressed);
 
     Ok(())
 }
@@ -141,12 +144,13 @@ fn try_trace(all_refs: &AllRefs, name: &str, config: Config, codes: &[Code], dec
                 location_hint,
                 scanner.codes(),
             ),
-            Trace::Actually(correct) => println!(
-                "   {:4}. {:10?} guess: {:?} trace: {:?}",
+            Trace::Actually(_) | Trace::Candidate { .. } => println!(
+                "   {:4}. {:10?} guess: {:?} trace: {:?} ({:?})",
                 scanner.pos,
                 location_hint,
                 scanner.codes(),
-                correct
+                t,
+                c
             ),
         }
 
@@ -41,7 +41,7 @@ fn run(data: &[u8]) {
         Block::Uncompressed(_) => return,
     };
 
-    let slice = librezip::tracer::try_gzip(1, &[], &data, &codes);
+    let slice = librezip::tracer::try_gzip(1, 0, &[], &data, &codes);
 
     if slice.iter().all(|&t| Trace::Correct == t) {
         println!("success");
@@ -21,18 +21,52 @@ pub struct AllRefs<'p, 'd> {
     pub data: &'d [u8],
     map: BackMap,
     limit: u16,
+    max_distance: u16,
+
+    /// Whether the first byte of the stream can't be referenced; see
+    /// `apply_first_byte_bug_rule`.
+    hide_first: bool,
 }
 
 impl<'p, 'd> AllRefs<'p, 'd> {
     pub fn with_sixteen(preroll: &'p [u8], data: &'d [u8], limit: u16) -> Self {
+        Self::with_hash(preroll, data, limit, 15, 32_768)
+    }
+
+    /// `hash_bits` changes which positions collide, and hence share a chain;
+    /// `max_distance` is how far back the compressor is willing to look.
+    pub fn with_hash(
+        preroll: &'p [u8],
+        data: &'d [u8],
+        limit: u16,
+        hash_bits: u8,
+        max_distance: u16,
+    ) -> Self {
         AllRefs {
             preroll,
             data,
             limit,
-            map: BackMap::from_window(preroll, data),
+            max_distance,
+            map: BackMap::from_window(preroll, data, hash_bits),
+            hide_first: false,
         }
     }
 
+    /// gzip and zlib mark the end of a hash chain with position zero, so never reference the
+    /// first byte of the stream. `preroll_start` is how far into the stream the preroll starts;
+    /// unless that's zero, position zero here is just where we started looking, so is left alone.
+    pub fn apply_first_byte_bug_rule(&mut self, preroll_start: usize) {
+        self.hide_first = 0 == preroll_start;
+    }
+
+    /// The earlier positions in `pos`'s hash chain, most recent first.
+    fn chain<'m>(&'m self, pos: usize) -> impl Iterator<Item = usize> + 'm {
+        let hide_first = self.hide_first;
+        self.map
+            .before(pos)
+            .filter(move |&off| !(hide_first && 0 == off))
+    }
+
     pub fn data_len(&self) -> usize {
         self.preroll.len() + self.data.len()
     }
@@ -50,10 +84,14 @@ impl<'p, 'd> AllRefs<'p, 'd> {
     }
 
     /// None if we are out of possible keys, or Some(possibly empty list)
+    ///
+    /// At most `max_chain` entries in the hash chain are examined, including those which only
+    /// share a hash with `pos`, not a key, as that's how the compressors count.
     pub fn at<'m>(
         &'m self,
         pos: usize,
-        obscura: &[Obscure],
+        obscura: &'m [Obscure],
+        max_chain: usize,
     ) -> Option<Box<Iterator<Item = Ref> + 'm>> {
         let key = match self.key(pos) {
             Some(key) => key,
@@ -67,25 +105,51 @@ impl<'p, 'd> AllRefs<'p, 'd> {
         }
 
         Some(Box::new(
-            obscure(
-                self.map.get(key).filter(move |&off| off < pos),
-                obscura.iter().cloned(),
-            )
-            .take(usize(self.limit))
-            .filter(move |&off| pos - off <= 32_768)
-            .filter(move |&off| {
-                self.get(off) == key.b0
-                    && self.get(off + 1) == key.b1
-                    && self.get(off + 2) == key.b2
-            })
-            .map(move |off| {
-                let dist = u16(pos - off).unwrap();
-                let run = self.possible_run_length_at(pos, dist);
-                Ref::new(dist, run)
-            }),
+            obscure(self.chain(pos), obscura)
+                .take(usize(self.limit))
+                .enumerate()
+                // zlib checks the head of the chain against `MAX_DIST`, but the rest against
+                // `limit`, which is one nearer
+                .take_while(move |&(id, off)| {
+                    let dist = pos - off;
+                    let max = usize(self.max_distance);
+                    dist < max || (0 == id && dist == max)
+                })
+                .map(|(_, off)| off)
+                .take(max_chain)
+                .filter(move |&off| {
+                    self.get(off) == key.b0
+                        && self.get(off + 1) == key.b1
+                        && self.get(off + 2) == key.b2
+                })
+                .map(move |off| {
+                    let dist = u16(pos - off).unwrap();
+                    let run = self.possible_run_length_at(pos, dist);
+                    Ref::new(dist, run)
+                }),
         ))
     }
 
+    /// A reference to the run of copies of the previous byte, as zlib's `Z_RLE` looks for.
+    pub fn previous_byte_run(&self, pos: usize) -> Option<Ref> {
+        let key = self.key(pos)?;
+        if 0 == pos {
+            return None;
+        }
+
+        let prev = self.get(pos - 1);
+        if prev != key.b0 || prev != key.b1 || prev != key.b2 {
+            return None;
+        }
+
+        Some(Ref::new(1, self.possible_run_length_at(pos, 1)))
+    }
+
+    /// The previous position in `pos`'s hash chain, whether or not it shares a key.
+    pub fn chained_before(&self, pos: usize) -> Option<usize> {
+        self.chain(pos).next()
+    }
+
     pub fn get(&self, pos: usize) -> u8 {
         if pos < self.preroll.len() {
             self.preroll[pos]
@@ -98,7 +162,9 @@ impl<'p, 'd> AllRefs<'p, 'd> {
         self.get(data_pos - usize(dist))
     }
 
-    fn possible_run_length_at(&self, pos: usize, dist: u16) -> u16 {
+    /// How far the data at `pos` matches the data `dist` back, up to the end of the data,
+    /// or the longest possible run.
+    pub fn possible_run_length_at(&self, pos: usize, dist: u16) -> u16 {
         let upcoming_data_len = u16(258.min(self.data_len() - pos)).unwrap();
         let upcoming_data: Vec<u8> = (0..upcoming_data_len)
             .map(|i| self.get(pos + usize(i)))
@@ -152,6 +218,15 @@ impl Key {
         hash
     }
 
+    /// zlib's `UPDATE_HASH`, applied three times. `hash_bits` is `memLevel + 7`.
+    pub fn zlib_hash(&self, hash_bits: u8) -> u32 {
+        let shift = (hash_bits + 2) / 3;
+        let mask = (1u32 << hash_bits) - 1;
+
+        ((u32::from(self.b0) << (2 * shift)) ^ (u32::from(self.b1) << shift) ^ u32::from(self.b2))
+            & mask
+    }
+
     fn as_array(&self) -> [u8; 3] {
         [self.b0, self.b1, self.b2]
     }
@@ -187,6 +262,7 @@ impl<'p, 'd> fmt::Debug for AllRefs<'p, 'd> {
 
 #[cfg(test)]
 mod tests {
+    use super::AllRefs;
     use super::Key;
 
     use crate::Code;
@@ -206,6 +282,40 @@ mod tests {
         );
     }
 
+    #[test]
+    fn zlib_hash_default_is_sixteen() {
+        for key in &[[3, 1, 1], [b'O', b'o', b'o'], [0xff, 0xfe, 0xfd], [0, 0, 0]] {
+            let key = k(key);
+            assert_eq!(u32::from(key.sixteen_hash_16()), key.zlib_hash(15));
+        }
+
+        // memLevel 9 and 1, respectively
+        assert_eq!(0b1100_1111_0111_1111, k(&[0xff, 0xfe, 0xff]).zlib_hash(16));
+        assert_eq!(0b1100_1111, k(&[0xff, 0xfe, 0xff]).zlib_hash(8));
+    }
+
+    #[test]
+    fn first_byte_bug() {
+        let refs = |all_refs: &AllRefs| -> Vec<Ref> {
+            all_refs.at(1, &[], usize::max_value()).unwrap().collect()
+        };
+
+        let mut all_refs = AllRefs::with_sixteen(&[], b"aaaaaa", u16::max_value());
+        assert_eq!(vec![Ref::new(1, 5)], refs(&all_refs));
+        all_refs.apply_first_byte_bug_rule(0);
+        assert_eq!(Vec::<Ref>::new(), refs(&all_refs));
+
+        // the preroll starts the stream, as after a stored block
+        let mut all_refs = AllRefs::with_sixteen(b"a", b"aaaaa", u16::max_value());
+        all_refs.apply_first_byte_bug_rule(0);
+        assert_eq!(Vec::<Ref>::new(), refs(&all_refs));
+
+        // the preroll's first byte isn't the stream's
+        let mut all_refs = AllRefs::with_sixteen(b"a", b"aaaaa", u16::max_value());
+        all_refs.apply_first_byte_bug_rule(1);
+        assert_eq!(vec![Ref::new(1, 5)], refs(&all_refs));
+    }
+
     fn k(from: &[u8]) -> Key {
         assert_eq!(3, from.len());
         Key {
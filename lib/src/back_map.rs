@@ -5,22 +5,24 @@ use itertools::Itertools;
 
 use crate::all_refs::Key;
 
-const HASH_SIZE: usize = 32 * 1024;
+/// The end of a chain. zlib uses zero, which hides the first byte; see `AllRefs`.
+const NIL: usize = ::std::usize::MAX;
 
 /// This is an efficient way to compute and store a hashtable to an ordered list of positions.
 pub struct BackMap {
     /// A lookup from the current `hash` to the last `pos` we saw that hash at.
-    hash_to_pos: [usize; HASH_SIZE],
+    hash_to_pos: Box<[usize]>,
 
     /// A lookup from a `pos`, to the last `pos` where something had the same hash.
     pos_to_pos: Box<[usize]>,
 }
 
 impl BackMap {
-    pub fn from_window(preroll: &[u8], data: &[u8]) -> BackMap {
+    /// Build the chains as zlib would, with `hash_bits` from `memLevel + 7` (i.e. 15 by default).
+    pub fn from_window(preroll: &[u8], data: &[u8], hash_bits: u8) -> BackMap {
         let mut table = BackMap {
-            hash_to_pos: [0; HASH_SIZE],
-            pos_to_pos: vec![0; preroll.len() + data.len()].into_boxed_slice(),
+            hash_to_pos: vec![NIL; 1 << hash_bits].into_boxed_slice(),
+            pos_to_pos: vec![NIL; preroll.len() + data.len()].into_boxed_slice(),
         };
 
         for (pos, keys) in preroll
@@ -30,7 +32,7 @@ impl BackMap {
             .tuple_windows::<(u8, u8, u8)>()
             .enumerate()
         {
-            let hash = Key::from(keys).sixteen_hash_16();
+            let hash = Key::from(keys).zlib_hash(hash_bits);
             let hash_entry = &mut table.hash_to_pos[usize(hash)];
             let prev_pos = *hash_entry;
             table.pos_to_pos[pos] = prev_pos;
@@ -40,11 +42,16 @@ impl BackMap {
         table
     }
 
-    pub fn get(&self, key: Key) -> Chain {
-        let pos = self.hash_to_pos[usize(key.sixteen_hash_16())];
-
+    /// All the earlier positions which share a hash with `pos`, most recent first.
+    ///
+    /// Starting from `pos`, instead of from the head of the hash's list, saves walking
+    /// through everything which appears later in the file, which is quadratic on repetitive data.
+    pub fn before(&self, pos: usize) -> Chain {
         Chain {
-            next: Some(pos),
+            next: match self.pos_to_pos[pos] {
+                NIL => None,
+                prev => Some(prev),
+            },
             pos_to_pos: &self.pos_to_pos,
         }
     }
@@ -65,7 +72,7 @@ impl<'a> Iterator for Chain<'a> {
         };
 
         match self.pos_to_pos[current] {
-            0 => self.next = None,
+            NIL => self.next = None,
             next => self.next = Some(next),
         };
 
@@ -79,14 +86,14 @@ impl fmt::Debug for BackMap {
             .hash_to_pos
             .iter()
             .enumerate()
-            .filter(|&(_, &pos)| 0 != pos)
+            .filter(|&(_, &pos)| NIL != pos)
         {
             let mut vals = Vec::new();
             let mut current = pos;
             vals.push(current);
             loop {
                 current = self.pos_to_pos[current];
-                if 0 == current {
+                if NIL == current {
                     break;
                 }
 
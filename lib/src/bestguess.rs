@@ -1,10 +1,162 @@
-#[cfg(old_tests)]
+//! A guesser which doesn't pretend to know the compressor: at each position, it ranks every
+//! code which could have been emitted, and the trace is the rank of the one which was.
+//!
+//! The candidates come first, longest (then nearest) first, then the literal, which a lazy
+//! compressor picks when it's about to see something better, then every shorter run of each
+//! candidate, in the same order.
+
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+
+use crate::technique::Scanner;
+use crate::technique::Technique;
+use crate::trace::CANDIDATES;
+use crate::Code;
+use crate::DataLen;
+use crate::Guesser;
+use crate::Ref;
+
+pub struct BestGuess<'t, 'a: 't, 'p: 'a + 't, 'd: 'a + 't> {
+    technique: &'t Technique<'a, 'p, 'd>,
+    scanner: Scanner<'t, 'a, 'p, 'd>,
+}
+
+impl<'t, 'a, 'p, 'd> BestGuess<'t, 'a, 'p, 'd> {
+    pub fn new(technique: &'t Technique<'a, 'p, 'd>) -> Self {
+        BestGuess {
+            technique,
+            scanner: technique.scanner(),
+        }
+    }
+
+    pub fn more_data(&self) -> bool {
+        self.scanner.more_data()
+    }
+
+    /// The references which could start at the current position, in rank order.
+    fn candidates(&self) -> Vec<Ref> {
+        let mut candidates = self.scanner.candidates(CANDIDATES);
+
+        // `Z_RLE` finds this without the hash chains, so it can point at the very first byte
+        if let Some(run) = self.technique.previous_byte_run(self.scanner.pos) {
+            if candidates.iter().all(|r| 1 != r.dist()) {
+                candidates.insert(0, run);
+            }
+        }
+
+        // stable, so equal runs stay nearest first
+        candidates.sort_by_key(|r| u16::MAX - r.run());
+        candidates
+    }
+
+    /// Every code which could be emitted at the current position, most plausible first.
+    pub fn ranked<'s>(&'s self) -> impl Iterator<Item = Code> + 's {
+        let candidates = self.candidates();
+        let shorter = candidates.clone().into_iter().flat_map(|r| {
+            (3..r.run())
+                .rev()
+                .map(move |run| Ref::new(r.dist(), run).into())
+        });
+
+        candidates
+            .into_iter()
+            .map(Code::from)
+            .chain(Some(Code::Literal(
+                self.technique.byte_at(self.scanner.pos),
+            )))
+            .chain(shorter)
+    }
+
+    /// Whether there's anything to choose between, i.e. whether a rank needs recording.
+    pub fn has_choice(&self) -> bool {
+        !self.candidates().is_empty()
+    }
+
+    pub fn feedback(&mut self, code: Code) {
+        self.scanner.feedback(code)
+    }
+}
+
+impl<'t, 'a, 'p, 'd> DataLen for BestGuess<'t, 'a, 'p, 'd> {
+    fn data_len(&self) -> usize {
+        self.scanner.data_len()
+    }
+}
+
+impl<'t, 'a, 'p, 'd> Guesser for BestGuess<'t, 'a, 'p, 'd> {
+    fn codes(&self) -> Vec<Code> {
+        self.ranked().take(1).collect()
+    }
+}
+
+/// The rank of each of the `codes` which had any competition.
+pub fn reduce_entropy(codes: &[Code], technique: &Technique) -> Result<Vec<usize>, Error> {
+    let mut guesser = BestGuess::new(technique);
+    let mut ranks = Vec::new();
+
+    for &code in codes {
+        ensure!(guesser.more_data(), "more codes than data");
+
+        if guesser.has_choice() {
+            let rank = guesser
+                .ranked()
+                .position(|guess| guess == code)
+                .ok_or_else(|| format_err!("{:?} isn't a plausible code here", code))?;
+            ranks.push(rank);
+        } else if Code::Literal(technique.byte_at(guesser.scanner.pos)) != code {
+            bail!("{:?} where only a literal is possible", code);
+        }
+
+        guesser.feedback(code);
+    }
+
+    ensure!(!guesser.more_data(), "codes ended before the data");
+
+    Ok(ranks)
+}
+
+/// The inverse of `reduce_entropy`.
+pub fn increase_entropy(ranks: &[usize], technique: &Technique) -> Result<Vec<Code>, Error> {
+    let mut guesser = BestGuess::new(technique);
+    let mut ranks = ranks.iter();
+    let mut codes = Vec::new();
+
+    while guesser.more_data() {
+        let code = if guesser.has_choice() {
+            let rank = *ranks
+                .next()
+                .ok_or_else(|| format_err!("ran out of ranks"))?;
+            guesser
+                .ranked()
+                .nth(rank)
+                .ok_or_else(|| format_err!("there aren't {} plausible codes", rank + 1))?
+        } else {
+            Code::Literal(technique.byte_at(guesser.scanner.pos))
+        };
+
+        guesser.feedback(code);
+        codes.push(code);
+    }
+
+    ensure!(ranks.next().is_none(), "ranks left over");
+
+    Ok(codes)
+}
+
+#[cfg(test)]
 mod tests {
-    use circles::CircularBuffer;
-    use serialise;
-    use Code;
-    use Code::Literal as L;
-    use Ref;
+    use super::increase_entropy;
+    use super::reduce_entropy;
+    use crate::all_refs::AllRefs;
+    use crate::circles::CircularBuffer;
+    use crate::serialise;
+    use crate::Code;
+    use crate::Code::Literal as L;
+    use crate::Config;
+    use crate::Ref;
+    use crate::Technique;
 
     fn r(dist: u16, run: u16) -> Code {
         Code::Reference(Ref::new(dist, run))
@@ -177,15 +329,16 @@ mod tests {
             serialise::decompressed_codes(&mut data, &mut prebuf, codes).unwrap();
         }
 
-        #[cfg(never)]
-        println!(
-            "data: {:?}, str: {:?}",
-            data,
-            String::from_utf8_lossy(&data)
-        );
+        // only the window is available to the compressor
+        let preroll = &preroll[preroll.len().saturating_sub(32 * 1024)..];
+        let all_refs = AllRefs::with_sixteen(preroll, &data, u16::max_value());
+        let technique = Technique::new(Config::gzip(9), &all_refs);
 
-        let reduced = reduce_entropy(preroll, &data, codes).unwrap();
-        assert_eq!(codes, increase_entropy(preroll, &data, &reduced).as_slice());
+        let reduced = reduce_entropy(codes, &technique).unwrap();
+        assert_eq!(
+            codes,
+            increase_entropy(&reduced, &technique).unwrap().as_slice()
+        );
         reduced
     }
 
@@ -102,7 +102,6 @@ impl<R: Read> BitReader<R> {
         Ok((u16::from(buf[1]) << 8) | u16::from(buf[0]))
     }
 
-    #[allow(unused)]
     pub fn into_inner(self) -> R {
         assert!(self.track.is_none());
         assert_eq!(0, self.position());
@@ -162,7 +161,7 @@ impl<W: Write> BitWriter<W> {
 
     pub fn write_aligned_u16(&mut self, val: u16) -> Result<(), Error> {
         self.inner
-            .write_all(&[u8(val >> 8).unwrap(), u8(val & 0xFF).unwrap()])?;
+            .write_all(&[u8(val & 0xFF).unwrap(), u8(val >> 8).unwrap()])?;
         Ok(())
     }
 
@@ -196,6 +195,25 @@ impl BitVec {
         }
     }
 
+    /// The inverse of `bytes()`: the first `len` bits of `bytes`, which must all be present.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, Error> {
+        ensure!(
+            bytes.len() == (len + WORD_SIZE - 1) / WORD_SIZE,
+            "{} bytes can't hold exactly {} bits",
+            bytes.len(),
+            len
+        );
+
+        let mut bytes = bytes.to_vec();
+        if let Some(last) = bytes.last_mut() {
+            if 0 != len % WORD_SIZE {
+                *last &= (1 << (len % WORD_SIZE)) - 1;
+            }
+        }
+
+        Ok(BitVec { bytes, len })
+    }
+
     pub fn push(&mut self, val: bool) {
         let word = self.len / WORD_SIZE;
         let bit = self.len % WORD_SIZE;
@@ -238,8 +256,7 @@ impl BitVec {
         Some(answer)
     }
 
-    #[allow(unused)]
-    fn bytes(&self) -> &Vec<u8> {
+    pub fn bytes(&self) -> &Vec<u8> {
         &self.bytes
     }
 
@@ -0,0 +1,182 @@
+//! gzip's `flush_block` and zlib's `_tr_flush_block` work out how long the block would be
+//! when stored, with the fixed trees, and with the dynamic trees, then pick the shortest,
+//! so the type of a block can be predicted from its codes.
+//!
+//! miniz doesn't compare the trees: it uses the fixed ones for tiny blocks, and otherwise
+//! always builds dynamic ones, only falling back to storing if they didn't help.
+//!
+//! Zopfli does compare them, but with the fixed trees' cost for a parse of their own; see
+//! `zopfli::block_type`.
+
+use cast::usize;
+
+use crate::serialise::Lengths;
+use crate::split::Splitter;
+use crate::technique::Config;
+use crate::technique::Strategy;
+use crate::trees;
+use crate::zopfli;
+use crate::Code;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlockType {
+    Uncompressed,
+    FixedHuffman,
+    DynamicHuffman,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TypeHint {
+    /// The block is the type the compressor would have picked for these codes.
+    Predicted,
+
+    /// The compressor picked something else, for reasons of its own.
+    Actually(BlockType),
+}
+
+/// miniz uses the fixed trees for blocks covering less than this many bytes.
+const MINIZ_STATIC_BELOW: usize = 48;
+
+/// The type the compressor would pick for a block containing these codes.
+pub fn predict(codes: &[Code], config: &Config) -> BlockType {
+    let strategy = config.strategy;
+    let stored_len: usize = codes.iter().map(|code| usize(code.emitted_bytes())).sum();
+
+    if let Splitter::Zopfli { .. } = config.splitter {
+        // as for miniz, stored blocks are never predicted
+        return zopfli::block_type(codes);
+    }
+
+    if let Splitter::Miniz { .. } = config.splitter {
+        // stored blocks end the huffman stretches, so they're never predicted here
+        return if Strategy::Fixed == strategy || stored_len < MINIZ_STATIC_BELOW {
+            BlockType::FixedHuffman
+        } else {
+            BlockType::DynamicHuffman
+        };
+    }
+
+    // `opt_len` includes the tree header; neither includes the three bit block header
+    let opt_len = trees::predict(codes).len()
+        + trees::lengths(codes)
+            .block_len(codes)
+            .expect("dynamic trees cover the codes they were built from");
+    let static_len = Lengths::fixed()
+        .block_len(codes)
+        .expect("the fixed trees cover everything");
+
+    // in bytes, rounding up after adding the block header
+    let mut opt_lenb = (opt_len + 3 + 7) / 8;
+    let static_lenb = (static_len + 3 + 7) / 8;
+
+    if static_lenb <= opt_lenb || Strategy::Fixed == strategy {
+        opt_lenb = static_lenb;
+    }
+
+    // a stored block has four bytes of lengths, after the header is aligned
+    if stored_len + 4 <= opt_lenb {
+        BlockType::Uncompressed
+    } else if static_lenb == opt_lenb {
+        BlockType::FixedHuffman
+    } else {
+        BlockType::DynamicHuffman
+    }
+}
+
+/// Work out how to recreate the `actual` type of a block with these codes.
+pub fn hint(codes: &[Code], config: &Config, actual: BlockType) -> TypeHint {
+    if predict(codes, config) == actual {
+        TypeHint::Predicted
+    } else {
+        TypeHint::Actually(actual)
+    }
+}
+
+/// The inverse of `hint`.
+pub fn restore(codes: &[Code], config: &Config, hint: TypeHint) -> BlockType {
+    match hint {
+        TypeHint::Predicted => predict(codes, config),
+        TypeHint::Actually(actual) => actual,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ref;
+
+    fn zlib(strategy: Strategy) -> Config {
+        Config::zlib(6, 8, 15, strategy)
+    }
+
+    fn literals(bytes: &[u8]) -> Vec<Code> {
+        bytes.iter().map(|&b| Code::Literal(b)).collect()
+    }
+
+    #[test]
+    fn short_is_fixed() {
+        let codes = literals(b"hello world");
+        assert_eq!(
+            BlockType::FixedHuffman,
+            predict(&codes, &zlib(Strategy::Default))
+        );
+    }
+
+    #[test]
+    fn skewed_is_dynamic() {
+        let mut codes = literals(&b"abba".repeat(300));
+        codes.push(Code::Reference(Ref::new(4, 200)));
+
+        assert_eq!(
+            BlockType::DynamicHuffman,
+            predict(&codes, &zlib(Strategy::Default))
+        );
+        assert_eq!(
+            BlockType::FixedHuffman,
+            predict(&codes, &zlib(Strategy::Fixed))
+        );
+    }
+
+    #[test]
+    fn noise_is_stored() {
+        let all: Vec<u8> = (0..=255).collect();
+        let codes = literals(&all.repeat(8));
+        assert_eq!(
+            BlockType::Uncompressed,
+            predict(&codes, &zlib(Strategy::Default))
+        );
+        assert_eq!(
+            BlockType::Uncompressed,
+            predict(&codes, &zlib(Strategy::Fixed))
+        );
+    }
+
+    #[test]
+    fn hinted() {
+        let codes = literals(b"hello world");
+        let config = zlib(Strategy::Default);
+
+        let hinted = hint(&codes, &config, BlockType::FixedHuffman);
+        assert_eq!(TypeHint::Predicted, hinted);
+        assert_eq!(BlockType::FixedHuffman, restore(&codes, &config, hinted));
+
+        let hinted = hint(&codes, &config, BlockType::DynamicHuffman);
+        assert_eq!(TypeHint::Actually(BlockType::DynamicHuffman), hinted);
+        assert_eq!(BlockType::DynamicHuffman, restore(&codes, &config, hinted));
+    }
+
+    #[test]
+    fn miniz_never_compares() {
+        let codes = literals(b"hello world");
+        let config = Config::miniz(6, Strategy::Default);
+        assert_eq!(BlockType::FixedHuffman, predict(&codes, &config));
+
+        let all: Vec<u8> = (0..=255).collect();
+        let codes = literals(&all.repeat(8));
+        assert_eq!(BlockType::DynamicHuffman, predict(&codes, &config));
+        assert_eq!(
+            BlockType::FixedHuffman,
+            predict(&codes, &Config::miniz(6, Strategy::Fixed))
+        );
+    }
+}
@@ -1,6 +1,5 @@
 use std::io::Write;
 
-use cast::usize;
 use failure::ensure;
 use failure::Error;
 use failure::ResultExt;
@@ -9,7 +8,7 @@ use failure::ResultExt;
 pub struct CircularBuffer {
     data: Vec<u8>,
     idx: usize,
-    valid_cap: u16,
+    valid_cap: usize,
 }
 
 impl CircularBuffer {
@@ -17,12 +16,12 @@ impl CircularBuffer {
         Self::with_capacity(32 * 1024)
     }
 
-    pub fn with_capacity(cap: u16) -> Self {
+    pub fn with_capacity(cap: usize) -> Self {
         assert!(cap > 0);
 
         CircularBuffer {
             idx: 0,
-            data: vec![0; usize(cap)],
+            data: vec![0; cap],
             valid_cap: 0,
         }
     }
@@ -31,7 +30,7 @@ impl CircularBuffer {
         self.data[self.idx] = val;
         self.idx = (self.idx + 1) % self.data.len();
 
-        if (usize(self.valid_cap)) < self.data.len() {
+        if self.valid_cap < self.data.len() {
             self.valid_cap += 1;
         }
     }
@@ -45,7 +44,7 @@ impl CircularBuffer {
     }
 
     // This updates self, whereas run_from and friends do not.
-    pub fn copy<W: Write>(&mut self, dist: u16, len: u16, mut into: W) -> Result<(), Error> {
+    pub fn copy<W: Write>(&mut self, dist: usize, len: usize, mut into: W) -> Result<(), Error> {
         // TODO: optimise
 
         ensure!(
@@ -55,11 +54,8 @@ impl CircularBuffer {
             self.valid_cap
         );
 
-        let mut read_from = (self
-            .idx
-            .wrapping_sub(usize(dist))
-            .wrapping_add(self.data.len()))
-            % self.data.len();
+        let mut read_from =
+            (self.idx.wrapping_sub(dist).wrapping_add(self.data.len())) % self.data.len();
 
         for _ in 0..len {
             let b = self.data[read_from];
@@ -72,12 +68,12 @@ impl CircularBuffer {
     }
 
     #[inline]
-    pub fn get_at_dist(&self, dist: u16) -> u8 {
+    pub fn get_at_dist(&self, dist: usize) -> u8 {
         debug_assert!(
             dist > 0,
             "distances are one-indexed; the most recent inserted value is 1"
         );
-        debug_assert!(usize(self.valid_cap) <= self.data.len());
+        debug_assert!(self.valid_cap <= self.data.len());
         debug_assert!(dist <= self.valid_cap);
 
         let target = self.idx as isize - (dist as isize);
@@ -90,18 +86,18 @@ impl CircularBuffer {
         self.data[idx]
     }
 
-    pub fn capacity(&self) -> u16 {
-        self.data.len() as u16
+    pub fn capacity(&self) -> usize {
+        self.data.len()
     }
 
-    pub fn len(&self) -> u16 {
+    pub fn len(&self) -> usize {
         self.valid_cap
     }
 
     pub fn vec(&self) -> Vec<u8> {
         // TODO: optimise
 
-        let mut ret = Vec::with_capacity(usize(self.valid_cap));
+        let mut ret = Vec::with_capacity(self.valid_cap);
         for pos in (1..1 + self.valid_cap).rev() {
             ret.push(self.get_at_dist(pos));
         }
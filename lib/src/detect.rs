@@ -0,0 +1,433 @@
+//! Work out which `Config` produced a stream, by tracing a sample of it with every
+//! technique we know how to emulate, and keeping the one which needs the least metadata.
+
+use cast::usize;
+use failure::Error;
+
+use crate::lookahead::Lookahead;
+use crate::serialise::decompressed_block;
+use crate::serialise::decompressed_codes;
+use crate::serialise_trace;
+use crate::split;
+use crate::split::BlockEnd;
+use crate::split::Splitter;
+use crate::technique::Config;
+use crate::technique::Strategy;
+use crate::tracer;
+use crate::zopfli;
+use crate::Block;
+use crate::CircularBuffer;
+use crate::Code;
+use crate::Trace;
+
+/// How many codes from the start of the stream each candidate is tried on.
+const SAMPLE_CODES: usize = 32 * 1024;
+
+/// What a block end costs in the metadata, if it's not where the candidate predicted.
+const BLOCK_END_BYTES: usize = 5;
+
+/// The default level, then the rest, cheapest first; earlier candidates win ties.
+const LEVELS: [u8; 9] = [6, 1, 2, 3, 4, 5, 7, 8, 9];
+
+/// pigz's default `--blocksize`.
+const PIGZ_CHUNK: u32 = 128 * 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Detected {
+    pub config: Config,
+
+    /// The proportion of the sample the winner predicted exactly. `1.0` means that the
+    /// stream was almost certainly written by this technique; anything much lower means
+    /// we're just picking the least bad of the candidates.
+    pub confidence: f64,
+}
+
+/// Every technique we know how to emulate, in the order they're tried, except pigz,
+/// which is zlib with chunks, so is only tried if the stream looks chunked,
+/// `gzip --rsyncable`, which is only tried if the stream was flushed, and Zopfli,
+/// which is slow, so is only tried if the trees look like its own.
+pub fn candidates() -> Vec<Config> {
+    let mut ret = Vec::new();
+
+    // gzip's are also Info-ZIP's, which shares its `deflate.c`
+    for &level in &LEVELS {
+        ret.push(Config::gzip(level));
+        ret.push(Config::zlib(level, 8, 15, Strategy::Default));
+
+        // the lazy levels' search limits, but greedy matching, as many other implementations do
+        if level >= 4 {
+            ret.push(Config {
+                lookahead: Lookahead::Greedy,
+                ..Config::zlib(level, 8, 15, Strategy::Default)
+            });
+        }
+    }
+
+    // miniz, which is what `flate2` uses unless it's built against zlib
+    for &level in &LEVELS {
+        ret.push(Config::miniz(level, Strategy::Default));
+    }
+
+    // libdeflate; its near-optimal levels, 10-12, are traced as lazy2, which does no better
+    // on their output than level 9, or zlib, so they're left to whichever of those is closer
+    for &level in &LEVELS {
+        ret.push(Config::libdeflate(level));
+    }
+
+    ret.push(Config::spicy());
+
+    ret
+}
+
+/// Pick the candidate which best explains the start of these blocks,
+/// or `None` if they contain no huffman codes at all.
+pub fn detect<I: Iterator<Item = Result<Block, Error>>>(
+    blocks: I,
+) -> Result<Option<Detected>, Error> {
+    detect_window(blocks, 15)
+}
+
+/// As `detect`, for blocks from a compressor with a window of `1 << window_bits` bytes,
+/// e.g. from a zlib header's `CINFO`; see `Config::with_window_bits`.
+pub fn detect_window<I: Iterator<Item = Result<Block, Error>>>(
+    blocks: I,
+    window_bits: u8,
+) -> Result<Option<Detected>, Error> {
+    let sample = Sample::take(blocks)?;
+    if sample.codes.is_empty() {
+        return Ok(None);
+    }
+
+    let mut best: Option<(usize, usize, Config)> = None;
+    let mut traced: Option<(Config, Vec<Trace>)> = None;
+
+    // pigz is zlib until its first chunk ends, so is only worth trying, before the
+    // rest, if a block starts there
+    let mut tried = Vec::new();
+    if sample.pigz {
+        tried.extend(LEVELS.iter().map(|&level| Config::pigz(level, PIGZ_CHUNK)));
+    }
+
+    // `--rsyncable` is gzip until its first boundary, so, likewise, is only worth trying,
+    // before the rest, if something was flushed
+    if sample.flushed {
+        tried.extend(LEVELS.iter().map(|&level| Config::gzip_rsyncable(level)));
+    }
+
+    // nothing else writes Zopfli's trees, and it's only ever run at its default
+    if sample.zopfli {
+        tried.push(Config::zopfli(zopfli::DEFAULT_ITERATIONS));
+    }
+    tried.extend(candidates());
+
+    for config in tried {
+        let config = config.with_window_bits(window_bits);
+        let ends = sample.block_end_bytes(&config.splitter);
+        let limit = match best {
+            Some((size, _, _)) => match size.checked_sub(ends) {
+                Some(limit) => limit,
+                None => continue,
+            },
+            None => usize::MAX,
+        };
+
+        // gzip and zlib differ only in where they end blocks, so the trace can be reused,
+        // unless one of them restarts at chunks
+        let reusable = traced.as_ref().is_some_and(|&(previous, _)| {
+            config
+                == Config {
+                    splitter: config.splitter,
+                    ..previous
+                }
+                && sample.pieces(&config.splitter) == sample.pieces(&previous.splitter)
+        });
+
+        if !reusable {
+            traced = sample.trace(config, limit).map(|trace| (config, trace));
+        }
+
+        let trace = match traced {
+            Some((_, ref trace)) => trace,
+            None => continue,
+        };
+
+        let size = ends + serialise_trace::write(trace).len();
+        let correct = trace.iter().filter(|&&t| Trace::Correct == t).count();
+
+        if best.is_none_or(|(best_size, _, _)| size < best_size) {
+            best = Some((size, correct, config));
+        }
+
+        // nothing can beat a perfect prediction, as earlier candidates win ties
+        if correct == sample.codes.len() && size == sample.perfect_size() {
+            break;
+        }
+    }
+
+    Ok(best.map(|(_, correct, config)| Detected {
+        config,
+        confidence: correct as f64 / sample.codes.len() as f64,
+    }))
+}
+
+/// The candidate which traces these `codes` in the fewest bytes, if it beats `config` by more
+/// than `margin` bytes. Nothing is tried if `config` already predicts everything. The
+/// `preroll` starts `preroll_start` bytes into the stream.
+///
+/// Candidates keep `config`'s `splitter`, as where blocks end is a property of the whole stream.
+pub fn improve_on(
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    codes: &[Code],
+    margin: usize,
+) -> Option<Config> {
+    // Zopfli's parse depends on the whole master block, so one of its blocks can't be
+    // traced, or bettered, on its own
+    if let Splitter::Zopfli { .. } = config.splitter {
+        return None;
+    }
+
+    let trace = tracer::try_config_within(config, preroll_start, preroll, data, codes, usize::MAX)
+        .expect("unlimited");
+    if trace.iter().all(|&t| Trace::Correct == t) {
+        return None;
+    }
+
+    // the size a candidate must beat
+    let mut to_beat = serialise_trace::write(&trace).len().checked_sub(margin)?;
+    let mut best = None;
+    let mut tried = vec![config];
+
+    for candidate in candidates() {
+        let candidate = Config {
+            splitter: config.splitter,
+            ..candidate
+        };
+
+        if tried.contains(&candidate) {
+            continue;
+        }
+        tried.push(candidate);
+
+        let limit = match to_beat.checked_sub(1) {
+            Some(limit) => limit,
+            None => break,
+        };
+
+        if let Some(trace) =
+            tracer::try_config_within(candidate, preroll_start, preroll, data, codes, limit)
+        {
+            to_beat = serialise_trace::write(&trace).len();
+            best = Some(candidate);
+        }
+    }
+
+    best
+}
+
+fn emitted_bytes(codes: &[Code]) -> usize {
+    codes.iter().map(|code| usize(code.emitted_bytes())).sum()
+}
+
+struct Sample {
+    /// All of the data up to the end of the sample, including anything stored before it.
+    data: Vec<u8>,
+
+    /// Where in `data` the first code starts.
+    start: usize,
+
+    codes: Vec<Code>,
+
+    /// How many codes each block in the sample has.
+    lens: Vec<usize>,
+
+    /// Whether a block with codes starts exactly where pigz's first chunk would end,
+    /// even if that's past the end of the sample.
+    pigz: bool,
+
+    /// Whether the sample was ended by an empty stored block, as when a compressor flushes.
+    flushed: bool,
+
+    /// Whether the first dynamic block's trees are the ones Zopfli would write.
+    zopfli: bool,
+}
+
+/// A piece, as `Sample::split` hands it out: the data before it, and its data, codes and
+/// block lengths.
+type PieceParts<'s> = (&'s [u8], &'s [u8], &'s [Code], &'s [usize]);
+
+/// Part of the sample which is traced in one go: where its data starts, and its first
+/// code and block.
+#[derive(Copy, Clone, Debug, PartialEq)]
+struct Piece {
+    pos: usize,
+    code: usize,
+    block: usize,
+}
+
+impl Sample {
+    /// The first run of huffman blocks, up to `SAMPLE_CODES` codes.
+    fn take<I: Iterator<Item = Result<Block, Error>>>(blocks: I) -> Result<Sample, Error> {
+        let mut dictionary = CircularBuffer::new();
+        let mut sample = Sample {
+            data: Vec::new(),
+            start: 0,
+            codes: Vec::new(),
+            lens: Vec::new(),
+            pigz: false,
+            flushed: false,
+            zopfli: false,
+        };
+
+        let pigz = Splitter::Pigz { chunk: PIGZ_CHUNK };
+        let mut pos = 0;
+        let mut full = false;
+        let mut seen_trees = false;
+
+        for block in blocks {
+            let block = block?;
+            let block_start = pos;
+            pos += match block {
+                Block::Uncompressed(ref data) => data.len(),
+                Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => {
+                    sample.pigz |= !codes.is_empty() && pigz.starts_chunk(block_start);
+                    emitted_bytes(codes)
+                }
+            };
+
+            if full {
+                // keep reading only far enough to see pigz's first chunk end
+                if block_start >= usize(PIGZ_CHUNK) {
+                    break;
+                }
+                continue;
+            }
+
+            if let Block::DynamicHuffman {
+                ref trees,
+                ref codes,
+            } = block
+            {
+                if !seen_trees {
+                    sample.zopfli = *trees == zopfli::trees(codes);
+                    seen_trees = true;
+                }
+            }
+
+            let codes = match block {
+                Block::Uncompressed(_) if sample.codes.is_empty() => {
+                    decompressed_block(&mut sample.data, &mut dictionary, &block)?;
+                    sample.start = sample.data.len();
+                    continue;
+                }
+                Block::Uncompressed(ref stored) => {
+                    sample.flushed = stored.is_empty();
+                    full = true;
+                    continue;
+                }
+                Block::FixedHuffman(codes) | Block::DynamicHuffman { codes, .. } => codes,
+            };
+
+            let wanted = (SAMPLE_CODES - sample.codes.len()).min(codes.len());
+            decompressed_codes(&mut sample.data, &mut dictionary, &codes[..wanted])?;
+            sample.codes.extend_from_slice(&codes[..wanted]);
+            sample.lens.push(wanted);
+
+            full = SAMPLE_CODES == sample.codes.len();
+        }
+
+        Ok(sample)
+    }
+
+    /// Where the sample is split, for this `splitter`, into pieces which are traced separately,
+    /// as the compressor started afresh; see `Splitter::starts_chunk`.
+    fn pieces(&self, splitter: &Splitter) -> Vec<Piece> {
+        let mut ret = vec![Piece {
+            pos: self.start,
+            code: 0,
+            block: 0,
+        }];
+
+        let mut pos = self.start;
+        let mut code = 0;
+        for (block, &len) in self.lens.iter().enumerate() {
+            if 0 != block && 0 != len && splitter.starts_chunk(pos) {
+                ret.push(Piece { pos, code, block });
+            }
+            pos += emitted_bytes(&self.codes[code..code + len]);
+            code += len;
+        }
+
+        ret
+    }
+
+    /// The data, codes, and block lengths of each piece, after the data before it.
+    fn split(&self, splitter: &Splitter) -> Vec<PieceParts<'_>> {
+        let pieces = self.pieces(splitter);
+        let end = Piece {
+            pos: self.data.len(),
+            code: self.codes.len(),
+            block: self.lens.len(),
+        };
+
+        pieces
+            .iter()
+            .zip(pieces.iter().skip(1).chain(Some(&end)))
+            .map(|(piece, next)| {
+                (
+                    &self.data[..piece.pos],
+                    &self.data[piece.pos..next.pos],
+                    &self.codes[piece.code..next.code],
+                    &self.lens[piece.block..next.block],
+                )
+            })
+            .collect()
+    }
+
+    /// The size of the metadata for a candidate which gets everything right.
+    fn perfect_size(&self) -> usize {
+        let mut size = serialise_trace::Size::default();
+        for _ in &self.codes {
+            size.push(Trace::Correct);
+        }
+        size.bytes()
+    }
+
+    /// The metadata needed for the block ends this `splitter` gets wrong.
+    fn block_end_bytes(&self, splitter: &Splitter) -> usize {
+        BLOCK_END_BYTES
+            * self
+                .split(splitter)
+                .into_iter()
+                .flat_map(|(preroll, data, codes, lens)| {
+                    split::hint(splitter, preroll, data, codes, lens)
+                })
+                .filter(|&end| BlockEnd::Predicted != end)
+                .count()
+    }
+
+    /// The trace of the sample with this `config`, or `None` if it's going to need more
+    /// than `limit` bytes.
+    fn trace(&self, config: Config, limit: usize) -> Option<Vec<Trace>> {
+        let mut ret = Vec::with_capacity(self.codes.len());
+        let mut size = serialise_trace::Size::default();
+        for (preroll, data, codes, _) in self.split(&config.splitter) {
+            // each piece's preroll starts the stream, and it only gets what's left of the limit
+            // after the pieces before it
+            let trace =
+                tracer::try_config_within(config, 0, preroll, data, codes, limit - size.bytes())?;
+
+            for &t in &trace {
+                size.push(t);
+            }
+            if size.bytes() > limit {
+                return None;
+            }
+
+            ret.extend(trace);
+        }
+        Some(ret)
+    }
+}
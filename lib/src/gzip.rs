@@ -1,54 +1,409 @@
+use std::io;
 use std::io::Read;
+use std::io::Write;
 
+use byteorder::ByteOrder;
+use byteorder::LittleEndian as LE;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use cast::u16;
+use cast::usize;
+use crc::crc32;
 use failure::ensure;
+use failure::format_err;
 use failure::Error;
 use failure::ResultExt;
 
-pub fn discard_header<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
-    let mut whole_thing = Vec::new();
+use crate::parse::parse_deflate;
+use crate::parse::BlockIter;
+use crate::Block;
 
-    let mut header = [0u8; 10];
-    from.read_exact(&mut header)?;
-    whole_thing.extend(&header);
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const METHOD_DEFLATE: u8 = 8;
 
-    ensure!(0x1f == header[0] && 0x8b == header[1], "invalid magic");
-    ensure!(0x08 == header[2], "unsupported compression method");
+const FLAG_TEXT: u8 = 0;
+const FLAG_HEADER_CRC: u8 = 1;
+const FLAG_EXTRA: u8 = 2;
+const FLAG_NAME: u8 = 3;
+const FLAG_COMMENT: u8 = 4;
 
-    let flags = header[3];
-    ensure!(0 == (flags & 0b1110_0000), "reserved flags bits set");
-    // 4, 5, 6, 7: mtime
-    // 8: extra flags (compression level)
-    // 9: OS
+/// Iterate over every member in a file, e.g. one produced by `cat a.gz b.gz`, or `pigz`,
+/// with `next_member`.
+///
+/// Anything after the last member which doesn't look like the start of another member
+/// ends the iteration, and is available from `trailing_garbage`.
+pub fn members<R: Read>(from: R) -> Members<R> {
+    Members {
+        inner: from,
+        first: true,
+        reading: false,
+        done: false,
+        trailing: Vec::new(),
+    }
+}
+
+pub struct Members<R> {
+    inner: R,
+    first: bool,
+
+    /// A member has been started, but its footer hasn't been read yet.
+    reading: bool,
+
+    done: bool,
+    trailing: Vec<u8>,
+}
+
+/// One gzip member; a file is one or more of these, one after another.
+///
+/// The header is read up front, then iterating reads the blocks, one at a time, so a member
+/// needn't fit in memory. The footer follows them, and must be read, with `footer`, before
+/// the next member can be.
+pub struct Member<'m, R: Read + 'm> {
+    pub header: GzipHeader,
+    blocks: BlockIter<&'m mut R>,
+    reading: &'m mut bool,
+    done: &'m mut bool,
+    trailing: &'m mut Vec<u8>,
+}
+
+impl<R: Read> Members<R> {
+    /// Bytes after the last member, e.g. `tar`'s zero padding. Only complete once
+    /// `next_member` has returned `None`.
+    pub fn trailing_garbage(&self) -> &[u8] {
+        &self.trailing
+    }
+
+    /// Read the next member's header, or `None` if there are no more members.
+    pub fn next_member(&mut self) -> Option<Result<Member<'_, R>, Error>> {
+        if self.done {
+            return None;
+        }
+
+        if self.reading {
+            self.done = true;
+            return Some(Err(format_err!(
+                "the previous member's footer hasn't been read"
+            )));
+        }
+
+        let mut magic = [0u8; 2];
+        let found = match read_up_to(&mut self.inner, &mut magic) {
+            Ok(found) => found,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        // the first member is mandatory, and will fail to parse if it's missing
+        if !self.first {
+            if 0 == found {
+                self.done = true;
+                return None;
+            }
+
+            if MAGIC[..] != magic[..found] {
+                self.done = true;
+                self.trailing.extend(&magic[..found]);
+                return match self.inner.read_to_end(&mut self.trailing) {
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e.into())),
+                };
+            }
+        }
+
+        self.first = false;
+
+        let header = match GzipHeader::read(io::Cursor::new(&magic[..found]).chain(&mut self.inner))
+        {
+            Ok(header) => header,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+
+        self.reading = true;
+
+        Some(Ok(Member {
+            header,
+            blocks: parse_deflate(&mut self.inner),
+            reading: &mut self.reading,
+            done: &mut self.done,
+            trailing: &mut self.trailing,
+        }))
+    }
+}
+
+impl<'m, R: Read> Member<'m, R> {
+    /// Skip any blocks which haven't been read, then read the footer. `None` means the file
+    /// ended before the footer did, so this is the last member, and what there was of the
+    /// footer is in the `trailing_garbage`.
+    pub fn footer(mut self) -> Result<Option<GzipFooter>, Error> {
+        for block in self.by_ref() {
+            block.with_context(|_| "reading DEFLATE stream")?;
+        }
+
+        let mut footer = [0u8; 8];
+        let found = read_up_to(self.blocks.into_inner(), &mut footer)?;
+        *self.reading = false;
+
+        if found < footer.len() {
+            *self.done = true;
+            self.trailing.extend(&footer[..found]);
+            return Ok(None);
+        }
+
+        Ok(Some(GzipFooter::read(&footer[..])?))
+    }
+}
+
+impl<'m, R: Read> Iterator for Member<'m, R> {
+    type Item = Result<Block, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = self.blocks.next();
+        if let Some(Err(_)) = block {
+            *self.done = true;
+        }
+        block
+    }
+}
+
+/// Like `read_exact`, but a short read at the end of the file is fine.
+fn read_up_to<R: Read>(mut from: R, buf: &mut [u8]) -> Result<usize, Error> {
+    let mut found = 0;
+    while found < buf.len() {
+        match from.read(&mut buf[found..]) {
+            Ok(0) => break,
+            Ok(read) => found += read,
+            Err(ref e) if io::ErrorKind::Interrupted == e.kind() => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(found)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GzipHeader {
+    /// `FTEXT`: the compressor thought the data was probably text.
+    pub text: bool,
+    pub mtime: u32,
+
+    /// `XFL`: for `DEFLATE`, `2` is "maximum compression", `4` is "fastest".
+    pub extra_flags: u8,
+    pub os: u8,
+
+    /// The `FEXTRA` field, verbatim, as it might not be well-formed; see `subfields`.
+    pub extra: Option<Vec<u8>>,
+
+    /// `FNAME`, without the terminating null.
+    pub name: Option<Vec<u8>>,
+
+    /// `FCOMMENT`, without the terminating null.
+    pub comment: Option<Vec<u8>>,
+
+    /// `FHCRC`, the bottom half of the CRC32 of the preceding header bytes. Checked on read.
+    pub header_crc: Option<u16>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subfield {
+    pub id: [u8; 2],
+    pub data: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct GzipFooter {
+    pub crc32: u32,
+
+    /// `ISIZE`: the length of the data, mod 2^32.
+    pub isize: u32,
+}
+
+impl GzipHeader {
+    pub fn read<R: Read>(mut from: R) -> Result<GzipHeader, Error> {
+        let mut whole_thing = Vec::new();
+
+        let mut header = [0u8; 10];
+        from.read_exact(&mut header)?;
+        whole_thing.extend(&header);
+
+        ensure!(MAGIC == header[..2], "invalid magic");
+        ensure!(
+            METHOD_DEFLATE == header[2],
+            "unsupported compression method"
+        );
+
+        let flags = header[3];
+        ensure!(0 == (flags & 0b1110_0000), "reserved flags bits set");
+
+        let mtime = LE::read_u32(&header[4..]);
+        let extra_flags = header[8];
+        let os = header[9];
+
+        let extra = if has_bit(flags, FLAG_EXTRA) {
+            let len = from.read_u16::<LE>()?;
+            let mut extra = vec![0u8; usize::from(len)];
+            from.read_exact(&mut extra)?;
+            whole_thing.write_u16::<LE>(len)?;
+            whole_thing.extend(&extra);
+            Some(extra)
+        } else {
+            None
+        };
+
+        let name = if has_bit(flags, FLAG_NAME) {
+            let name = read_null_terminated(&mut from)?;
+            whole_thing.extend(&name);
+            whole_thing.push(0);
+            Some(name)
+        } else {
+            None
+        };
+
+        let comment = if has_bit(flags, FLAG_COMMENT) {
+            let comment = read_null_terminated(&mut from)?;
+            whole_thing.extend(&comment);
+            whole_thing.push(0);
+            Some(comment)
+        } else {
+            None
+        };
+
+        let header_crc = if has_bit(flags, FLAG_HEADER_CRC) {
+            let expected = from.read_u16::<LE>()?;
+            let actual = crc32::checksum_ieee(&whole_thing) as u16;
+            ensure!(
+                expected == actual,
+                "header crc mismatch: expected {:04x}, calculated {:04x}",
+                expected,
+                actual
+            );
+            Some(expected)
+        } else {
+            None
+        };
+
+        Ok(GzipHeader {
+            text: has_bit(flags, FLAG_TEXT),
+            mtime,
+            extra_flags,
+            os,
+            extra,
+            name,
+            comment,
+            header_crc,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        let mut flags = 0u8;
+        if self.text {
+            flags |= 1 << FLAG_TEXT;
+        }
+        if self.header_crc.is_some() {
+            flags |= 1 << FLAG_HEADER_CRC;
+        }
+        if self.extra.is_some() {
+            flags |= 1 << FLAG_EXTRA;
+        }
+        if self.name.is_some() {
+            flags |= 1 << FLAG_NAME;
+        }
+        if self.comment.is_some() {
+            flags |= 1 << FLAG_COMMENT;
+        }
+
+        into.write_all(&MAGIC)?;
+        into.write_u8(METHOD_DEFLATE)?;
+        into.write_u8(flags)?;
+        into.write_u32::<LE>(self.mtime)?;
+        into.write_u8(self.extra_flags)?;
+        into.write_u8(self.os)?;
+
+        if let Some(ref extra) = self.extra {
+            into.write_u16::<LE>(u16(extra.len())?)?;
+            into.write_all(extra)?;
+        }
+
+        for field in self.name.iter().chain(self.comment.iter()) {
+            ensure!(!field.contains(&0), "null inside a null-terminated field");
+            into.write_all(field)?;
+            into.write_u8(0)?;
+        }
+
+        if let Some(crc) = self.header_crc {
+            into.write_u16::<LE>(crc)?;
+        }
+
+        Ok(())
+    }
+
+    /// The `FEXTRA` field split into its subfields, if it's present and well-formed.
+    pub fn subfields(&self) -> Result<Vec<Subfield>, Error> {
+        let mut extra = match self.extra {
+            Some(ref extra) => io::Cursor::new(extra.as_slice()),
+            None => return Ok(Vec::new()),
+        };
+
+        let mut subfields = Vec::new();
+        while usize(extra.position()) < extra.get_ref().len() {
+            let mut id = [0u8; 2];
+            extra.read_exact(&mut id)?;
+            let len = extra.read_u16::<LE>()?;
+            let mut data = vec![0u8; usize::from(len)];
+            extra.read_exact(&mut data)?;
+            subfields.push(Subfield { id, data });
+        }
+
+        Ok(subfields)
+    }
+}
 
-    if has_bit(flags, 2) {
-        // extra
-        let mut buf = [0u8; 2];
-        from.read_exact(&mut buf)?;
-        whole_thing.extend(&buf);
-        let extra_field_length = ((buf[1] as usize) << 8) | (buf[0] as usize);
-        let mut extra_field = vec![0u8; extra_field_length];
-        from.read_exact(&mut extra_field)?;
-        whole_thing.extend(&extra_field);
+impl GzipFooter {
+    pub fn read<R: Read>(mut from: R) -> Result<GzipFooter, Error> {
+        Ok(GzipFooter {
+            crc32: from.read_u32::<LE>()?,
+            isize: from.read_u32::<LE>()?,
+        })
     }
 
-    if has_bit(flags, 3) {
-        // fname
-        read_null_terminated(&mut from, &mut whole_thing)?;
+    pub fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_u32::<LE>(self.crc32)?;
+        into.write_u32::<LE>(self.isize)?;
+        Ok(())
     }
 
-    if has_bit(flags, 4) {
-        // comment
-        read_null_terminated(&mut from, &mut whole_thing)?;
+    /// The footer a member containing exactly this data would have.
+    pub fn of(data: &[u8]) -> GzipFooter {
+        let mut footer = GzipFooter::default();
+        footer.extend(data);
+        footer
     }
 
-    if has_bit(flags, 1) {
-        // CRC
-        let mut buf = [0u8; 2];
-        from.read_exact(&mut buf)?;
-        whole_thing.extend(&buf);
+    /// Update the footer to also cover `data`, as if it was appended to the member.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.crc32 = crc32::update(self.crc32, &crc32::IEEE_TABLE, data);
+        self.isize = self.isize.wrapping_add(data.len() as u32);
     }
 
-    Ok(whole_thing)
+    /// Check this (read) footer matches the one `calculated` from the decompressed data.
+    pub fn check(&self, calculated: &GzipFooter) -> Result<(), Error> {
+        ensure!(
+            self.crc32 == calculated.crc32,
+            "data crc mismatch: expected {:08x}, calculated {:08x}",
+            self.crc32,
+            calculated.crc32
+        );
+        ensure!(
+            self.isize == calculated.isize,
+            "data length mismatch: expected {}, calculated {}",
+            self.isize,
+            calculated.isize
+        );
+        Ok(())
+    }
 }
 
 #[inline]
@@ -56,13 +411,215 @@ fn has_bit(val: u8, bit: u8) -> bool {
     (val & (1 << bit)) == (1 << bit)
 }
 
-fn read_null_terminated<R: Read>(mut from: R, into: &mut Vec<u8>) -> Result<(), Error> {
+fn read_null_terminated<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
+    let mut ret = Vec::new();
     loop {
-        let mut buf = [0u8; 1];
-        from.read_exact(&mut buf)?;
-        into.push(buf[0]);
-        if 0 == buf[0] {
-            return Ok(());
+        let byte = from.read_u8()?;
+        if 0 == byte {
+            return Ok(ret);
         }
+        ret.push(byte);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOL: &[u8] = include_bytes!("../tests/data/lol.gz");
+    const LIKE_LOVE: &[u8] = include_bytes!("../tests/data/like-love.gz");
+
+    fn concat(parts: &[&[u8]]) -> Vec<u8> {
+        parts.iter().flat_map(|part| part.iter()).cloned().collect()
+    }
+
+    fn footer_of(file: &[u8]) -> GzipFooter {
+        GzipFooter::read(&file[file.len() - 8..]).unwrap()
+    }
+
+    fn round_trip(header: &GzipHeader) -> Vec<u8> {
+        let mut written = Vec::new();
+        header.write(&mut written).unwrap();
+        assert_eq!(header, &GzipHeader::read(written.as_slice()).unwrap());
+        written
+    }
+
+    #[test]
+    fn simple_header() {
+        let header = GzipHeader::read(LOL).unwrap();
+        assert_eq!(None, header.header_crc);
+        assert_eq!(
+            &LOL[..round_trip(&header).len()],
+            round_trip(&header).as_slice()
+        );
+    }
+
+    #[test]
+    fn every_header_field() {
+        let mut header = GzipHeader {
+            text: true,
+            mtime: 0x5aba_0000,
+            extra_flags: 2,
+            os: 3,
+            extra: Some(b"AB\x02\x00hiCD\x00\x00".to_vec()),
+            name: Some(b"hello.txt".to_vec()),
+            comment: Some(b"world".to_vec()),
+            header_crc: None,
+        };
+
+        let without_crc = round_trip(&header);
+
+        // the flag is covered by the crc, so write it out with a placeholder first
+        header.header_crc = Some(0);
+        let mut placeholder = Vec::new();
+        header.write(&mut placeholder).unwrap();
+        let covered = &placeholder[..placeholder.len() - 2];
+        header.header_crc = Some(crc32::checksum_ieee(covered) as u16);
+
+        let with_crc = round_trip(&header);
+        assert_eq!(without_crc.len() + 2, with_crc.len());
+
+        assert_eq!(
+            vec![
+                Subfield {
+                    id: *b"AB",
+                    data: b"hi".to_vec(),
+                },
+                Subfield {
+                    id: *b"CD",
+                    data: Vec::new(),
+                },
+            ],
+            header.subfields().unwrap()
+        );
+
+        let mut corrupt = with_crc.clone();
+        corrupt[4] ^= 1;
+        assert!(GzipHeader::read(corrupt.as_slice()).is_err());
+    }
+
+    #[test]
+    fn malformed_subfields() {
+        let header = GzipHeader {
+            text: false,
+            mtime: 0,
+            extra_flags: 0,
+            os: 255,
+            extra: Some(b"AB\x05\x00hi".to_vec()),
+            name: None,
+            comment: None,
+            header_crc: None,
+        };
+
+        round_trip(&header);
+        assert!(header.subfields().is_err());
+    }
+
+    #[test]
+    fn footer() {
+        let mut footer = GzipFooter::of(b"hello ");
+        footer.extend(b"world");
+        assert_eq!(GzipFooter::of(b"hello world"), footer);
+        assert_eq!(0x0d4a_1185, footer.crc32);
+        assert_eq!(11, footer.isize);
+
+        assert!(footer.check(&GzipFooter::of(b"hello world")).is_ok());
+        assert!(footer.check(&GzipFooter::of(b"hello there")).is_err());
+        assert_eq!(GzipFooter::default(), GzipFooter::of(b""));
+    }
+
+    /// The footer of each member, stopping at the first error, and the trailing garbage.
+    fn footers(file: &[u8]) -> (Vec<Result<Option<GzipFooter>, Error>>, Vec<u8>) {
+        let mut it = members(file);
+        let mut footers = Vec::new();
+        while let Some(member) = it.next_member() {
+            footers.push(member.and_then(|member| member.footer()));
+        }
+        (footers, it.trailing_garbage().to_vec())
+    }
+
+    #[test]
+    fn single() {
+        let (footers, garbage) = footers(LOL);
+        assert_eq!(vec![Some(footer_of(LOL))], ok(footers));
+        assert!(garbage.is_empty());
+    }
+
+    #[test]
+    fn concatenated() {
+        let file = concat(&[LOL, LIKE_LOVE, LOL]);
+        let (footers, garbage) = footers(&file);
+        let footers = ok(footers);
+        assert_eq!(3, footers.len());
+        assert_eq!(Some(footer_of(LIKE_LOVE)), footers[1]);
+        assert!(garbage.is_empty());
+    }
+
+    #[test]
+    fn blocks_then_footer() {
+        let file = concat(&[LOL, LIKE_LOVE]);
+        let mut it = members(file.as_slice());
+
+        let mut member = it.next_member().unwrap().unwrap();
+        assert_eq!(GzipHeader::read(LOL).unwrap(), member.header);
+        assert!(member.next().unwrap().is_ok());
+        assert_eq!(Some(footer_of(LOL)), member.footer().unwrap());
+
+        // the footer skips the blocks
+        let member = it.next_member().unwrap().unwrap();
+        assert_eq!(Some(footer_of(LIKE_LOVE)), member.footer().unwrap());
+        assert!(it.next_member().is_none());
+
+        // and has to be read before the next member
+        let mut it = members(file.as_slice());
+        drop(it.next_member().unwrap().unwrap());
+        assert!(it.next_member().unwrap().is_err());
+        assert!(it.next_member().is_none());
+    }
+
+    #[test]
+    fn trailing_garbage() {
+        for garbage in &[
+            &b"\0"[..],
+            b"\0\0\0\0",
+            b"\x1f",
+            b"\x1f\x8a",
+            b"hello world",
+        ] {
+            let file = concat(&[LOL, LIKE_LOVE, garbage]);
+            let (footers, found) = footers(&file);
+            assert_eq!(2, ok(footers).len());
+            assert_eq!(*garbage, found.as_slice());
+        }
+    }
+
+    #[test]
+    fn missing_footer() {
+        // the footer is cut short, so is left as garbage
+        let file = concat(&[LOL, &LOL[..LOL.len() - 3]]);
+        let (footers, garbage) = footers(&file);
+        assert_eq!(vec![Some(footer_of(LOL)), None], ok(footers));
+        assert_eq!(&LOL[LOL.len() - 8..LOL.len() - 3], garbage.as_slice());
+    }
+
+    #[test]
+    fn broken_second_member() {
+        let file = concat(&[LOL, &LOL[..LOL.len() - 9]]);
+        let (footers, _) = footers(&file);
+        let results: Vec<bool> = footers.iter().map(|m| m.is_ok()).collect();
+        assert_eq!(vec![true, false], results);
+    }
+
+    #[test]
+    fn not_gzip() {
+        let mut it = members(&b"hello world"[..]);
+        assert!(it.next_member().unwrap().is_err());
+        assert!(it.next_member().is_none());
+
+        assert!(members(&b""[..]).next_member().unwrap().is_err());
+    }
+
+    fn ok(footers: Vec<Result<Option<GzipFooter>, Error>>) -> Vec<Option<GzipFooter>> {
+        footers.into_iter().map(|footer| footer.unwrap()).collect()
     }
 }
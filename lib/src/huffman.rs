@@ -11,6 +11,15 @@ use crate::bit::BitReader;
 use crate::bit::BitSource;
 use crate::code_tree::CodeTree;
 
+/// The two flavours of the format. Deflate64, zip's method 9, has a 64KiB window, reached
+/// with the two distance codes classic `DEFLATE` reserves, and gives the last length code
+/// sixteen extra bits, instead of it meaning 258.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Deflate,
+    Deflate64,
+}
+
 lazy_static! {
     pub static ref FIXED_LENGTH_TREE: CodeTree = {
         let mut lens = [0u8; 288];
@@ -164,12 +173,34 @@ pub fn extra_run_length(length: u16) -> Option<(u8, u16)> {
     }
 }
 
-/// Returns a run length between 3 and 258 inclusive, all other values are invalid.
-pub fn decode_run_length<R: Read>(reader: &mut BitReader<R>, sym: u16) -> Result<u16, Error> {
+/// The length symbol, and any extra bits, for a run in either format.
+///
+/// Deflate64 could write any run with its last length symbol, but only uses it where
+/// nothing else can; 258 is the last of the previous symbol's range.
+pub fn encode_wide_run_length(format: Format, run: u32) -> (u16, Option<(u8, u16)>) {
+    if Format::Deflate64 == format && run >= 258 {
+        return if 258 == run {
+            (284, Some((5, 31)))
+        } else {
+            (285, Some((16, (run - 3) as u16)))
+        };
+    }
+
+    let run = run as u16;
+    (encode_run_length(run), extra_run_length(run))
+}
+
+/// Returns a run length between 3 and 258 inclusive, or, for Deflate64, 3 and 65538
+/// inclusive, all other values are invalid.
+pub fn decode_run_length<R: Read>(
+    reader: &mut BitReader<R>,
+    sym: u16,
+    format: Format,
+) -> Result<u32, Error> {
     ensure!(sym >= 257 && sym <= 287, "decompressor bug");
 
     if sym <= 264 {
-        return Ok((sym - 254) as u16);
+        return Ok(u32::from(sym - 254));
     }
 
     if sym <= 284 {
@@ -180,11 +211,14 @@ pub fn decode_run_length<R: Read>(reader: &mut BitReader<R>, sym: u16) -> Result
 
         let high_part = (((sym - 265) as u8) % 4 + 4) << extra_bits;
         let low_part = reader.read_part(extra_bits)? as u8;
-        return Ok(u16::from(high_part) + u16::from(low_part) + 3);
+        return Ok(u32::from(high_part) + u32::from(low_part) + 3);
     }
 
     if sym == 285 {
-        return Ok(258);
+        return Ok(match format {
+            Format::Deflate => 258,
+            Format::Deflate64 => u32::from(reader.read_part(16)?) + 3,
+        });
     }
 
     // sym is 286 or 287
@@ -192,7 +226,7 @@ pub fn decode_run_length<R: Read>(reader: &mut BitReader<R>, sym: u16) -> Result
 }
 
 /// Returns: Some(code, bit count, bits); never None (sigh)
-pub fn encode_distance(distance: u16) -> Option<(u8, u8, u16)> {
+pub fn encode_distance(distance: u32) -> Option<(u8, u8, u16)> {
     if distance <= 4 {
         Some((distance as u8 - 1, 0, 0))
     } else {
@@ -209,20 +243,33 @@ pub fn encode_distance(distance: u16) -> Option<(u8, u8, u16)> {
         let half = base / 2;
         let delta = distance - base - 1;
 
+        let extra = (delta % half) as u16;
+
         if distance <= base + half {
-            Some((code, extra_bits, delta % half))
+            Some((code, extra_bits, extra))
         } else {
-            Some((code + 1, extra_bits, delta % half))
+            Some((code + 1, extra_bits, extra))
         }
     }
 }
 
-pub fn decode_distance<R: Read>(reader: &mut BitReader<R>, sym: u16) -> Result<u16, Error> {
+pub fn decode_distance<R: Read>(
+    reader: &mut BitReader<R>,
+    sym: u16,
+    format: Format,
+) -> Result<u32, Error> {
+    let last = match format {
+        Format::Deflate => 29,
+        Format::Deflate64 => 31,
+    };
+
     if sym <= 3 {
-        Ok(sym as u16 + 1)
-    } else if sym <= 29 {
+        Ok(u32::from(sym) + 1)
+    } else if sym <= last {
         let num_extra_bits = (sym / 2 - 1) as u8;
-        Ok((((sym % 2 + 2) as u16) << num_extra_bits) + 1 + reader.read_part(num_extra_bits)?)
+        Ok((u32::from(sym % 2 + 2) << num_extra_bits)
+            + 1
+            + u32::from(reader.read_part(num_extra_bits)?))
     } else if sym <= 31 {
         Err(err_msg("reserved distance symbol"))
     } else {
@@ -3,6 +3,7 @@
 
 extern crate byteorder;
 extern crate cast;
+extern crate crc;
 
 extern crate itertools;
 
@@ -22,41 +23,59 @@ use std::fmt;
 
 pub mod all_refs;
 mod back_map;
-mod bestguess;
+pub mod bestguess;
 mod bit;
+pub mod block_type;
 mod circles;
 mod code_tree;
+pub mod detect;
 // TODO: unused
 pub mod filter;
 pub mod gzip;
 mod huffman;
 mod iters;
+mod libdeflate;
 mod lookahead;
+pub mod metadata;
+mod miniz;
 mod obscure;
 mod parse;
 mod picker;
+mod range_coder;
+pub mod rsyncable;
 // TODO: unused
 pub mod serialise;
 pub mod serialise_trace;
+pub mod split;
 mod technique;
 pub mod trace;
+mod trace_model;
 pub mod tracer;
+pub mod trees;
 mod wams;
+pub mod zip;
+pub mod zlib;
+mod zopfli;
 
 use crate::bit::BitVec;
 
 pub use crate::circles::CircularBuffer;
 pub use crate::parse::parse_deflate;
+pub use crate::parse::parse_deflate64;
 pub use crate::serialise::compressed_block;
+pub use crate::serialise::compressed_block64;
 pub use crate::serialise::decompressed_block;
 pub use crate::serialise::decompressed_codes;
 pub use crate::technique::Config;
+pub use crate::technique::Strategy;
 pub use crate::technique::Technique;
 
+/// A reference back into the data. Wide enough for Deflate64, but everything which
+/// emulates a compressor only deals in classic `DEFLATE`'s, through `dist` and `run`.
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Ref {
-    pub dist: u16,
-    run_minus_3: u8,
+    dist_minus_1: u16,
+    run_minus_3: u16,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -90,38 +109,18 @@ pub trait Guesser: DataLen {
     fn codes(&self) -> Vec<Code>;
 }
 
-#[derive(Debug, Eq, PartialEq)]
-pub struct WindowSettings {
-    window_size: u16,
-
-    /// gzip (including 1.6 and probably onwards) will mis-encode
-    /// "aaaaaa" as "aa{ref one back, run=..}", as the encoder can't
-    /// be bothered to cope with pointers to the 0th character.
-    ///
-    /// Test-case:
-    ///
-    /// ```text
-    /// % yes aaaaaaaaaa | tr -d '\n' | head -c 8453631 | gzip > a.gz
-    /// % cargo run --example dump a.gz | uniq -c
-    ///     1 block 0:
-    ///     1  - dynamic huffman: BitVec: 110: 101111...
-    ///     2     - lit: 0x61: 'a'
-    /// 32765     - backref: 1 byte(s) back, 258 bytes long
-    ///     1 block 1:
-    ///     1  - fixed huffman:
-    ///     1     - backref: 1 byte(s) back, 258 bytes long
-    ///     1     - lit: 0x61: 'a'
-    /// ```
-    ///
-    /// Note the double 'a' at the start.
-    first_byte_bug: bool,
-}
-
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Trace {
     Correct,
     ActuallyLiteral,
     Actually(Ref),
+
+    /// Actually the `index`th of the references the compressor could see, nearest first,
+    /// cut short to `run`, if present, instead of running as far as it can.
+    Candidate {
+        index: u8,
+        run: Option<u16>,
+    },
 }
 
 impl Code {
@@ -142,13 +141,44 @@ impl Ref {
         assert_ge!(dist, 1);
         assert_le!(dist, 32_768);
 
-        let run_minus_3 = (run - 3) as u8;
-        Ref { dist, run_minus_3 }
+        Ref {
+            dist_minus_1: dist - 1,
+            run_minus_3: run - 3,
+        }
+    }
+
+    /// A Deflate64 reference, which can reach 64KiB back, and run for up to 65538 bytes.
+    fn deflate64(dist: u32, run: u32) -> Self {
+        assert_ge!(run, 3);
+        assert_le!(run, 65_538);
+
+        assert_ge!(dist, 1);
+        assert_le!(dist, 65_536);
+
+        Ref {
+            dist_minus_1: (dist - 1) as u16,
+            run_minus_3: (run - 3) as u16,
+        }
+    }
+
+    /// How far back the reference points, which always fits for classic `DEFLATE`.
+    #[inline]
+    pub fn dist(&self) -> u16 {
+        self.dist_minus_1 + 1
     }
 
+    /// How many bytes the reference copies, which always fits for classic `DEFLATE`.
     #[inline]
     pub fn run(&self) -> u16 {
-        u16::from(self.run_minus_3) + 3
+        self.run_minus_3 + 3
+    }
+
+    pub fn wide_dist(&self) -> u32 {
+        u32::from(self.dist_minus_1) + 1
+    }
+
+    pub fn wide_run(&self) -> u32 {
+        u32::from(self.run_minus_3) + 3
     }
 }
 
@@ -162,7 +192,7 @@ impl fmt::Debug for Code {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Code::Literal(byte) => write!(f, "L(0x{:02x} {:?})", byte, byte as char),
-            Code::Reference(r) => write!(f, "R(-{}, {})", r.dist, r.run()),
+            Code::Reference(r) => write!(f, "R(-{}, {})", r.wide_dist(), r.wide_run()),
         }
     }
 }
@@ -173,13 +203,18 @@ impl fmt::Debug for Trace {
             Trace::Correct => write!(f, "✓"),
             Trace::ActuallyLiteral => write!(f, "L"),
             Trace::Actually(r) => write!(f, "{:?}", r),
+            Trace::Candidate { index, run: None } => write!(f, "C[{}]", index),
+            Trace::Candidate {
+                index,
+                run: Some(run),
+            } => write!(f, "C[{}, {}]", index, run),
         }
     }
 }
 
 impl fmt::Debug for Ref {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "R[{}, {}]", self.dist, self.run())
+        write!(f, "R[{}, {}]", self.wide_dist(), self.wide_run())
     }
 }
 
@@ -235,11 +270,11 @@ mod tests {
 
     fn round_trip(orig: &[u8], expected_len: usize) {
         let mut raw = Cursor::new(orig);
-        let header = gzip::discard_header(&mut raw).unwrap();
+        let header = gzip::GzipHeader::read(&mut raw).unwrap();
 
         let mut decompressed = Vec::new();
         let mut recompressed = Cursor::new(Vec::new());
-        recompressed.write_all(&header).unwrap();
+        header.write(&mut recompressed).unwrap();
         let mut recompressed = BitWriter::new(recompressed);
 
         {
@@ -0,0 +1,670 @@
+//! libdeflate compresses the whole input at once, with a different parser for each group of
+//! levels: level one (`deflate_compress_fastest`) remembers the last two positions for each
+//! hash of four bytes, 2-4 are greedy, 5-7 lazy, and 8-9 "lazy2", which looks two bytes ahead.
+//! 10-12 are out of scope: they find matches with a binary tree, not hash chains, then run
+//! an iterative optimal parse against a cost model built from their own huffman trees, and
+//! none of that is reproduced here. They're traced as lazy2, with their own search limits,
+//! which explains them no better than level 9, so expect a correction at most codes.
+//!
+//! The greedy and lazy levels search hash chains of four bytes, plus a table holding only
+//! the last position for each three bytes, which is checked first, and ends the search if
+//! it's out of the window. They also refuse matches shorter than `min_len`, which is picked
+//! for each block from how many different literals it has, and revisited as the block grows.
+//!
+//! Blocks are ended by comparing the statistics of the last few hundred codes with the
+//! rest of the block, which is only done between steps of the parse, i.e. never between
+//! the literal a lazy match displaced and the match, so the lazy parsers' block ends can't
+//! be predicted from the codes alone: we have to search again to find the steps.
+//!
+//! Every position is inserted into the hash tables (except the last few, which are never
+//! searched from), so the chains are built up front, and every search is a pure function of
+//! the position. The first position's hashes are never calculated, and are left at zero.
+//!
+//! Its huffman trees are built differently from zlib's, so are recorded as corrections.
+
+use std::mem;
+
+use cast::u16;
+use cast::u32;
+use cast::usize;
+
+use crate::Code;
+use crate::Ref;
+
+/// How each level parses.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Parse {
+    /// `deflate_compress_fastest`: the longest of two candidates, no heuristics.
+    Fastest,
+
+    /// `deflate_compress_greedy`: the longest match, if it's at least `min_len`.
+    Greedy,
+
+    /// `deflate_compress_lazy`: as `Greedy`, but a match is dropped for a literal, if there's
+    /// a sufficiently better match starting on the next byte.
+    Lazy,
+
+    /// `deflate_compress_lazy2`: as `Lazy`, but also checks two bytes ahead.
+    Lazy2,
+}
+
+/// The parse, `max_search_depth`, and `nice_match_length` for each level. 10-12 are
+/// near-optimal, which isn't emulated, so they're given lazy2 as a stand-in.
+pub fn params(level: u8) -> (Parse, u16, u16) {
+    match level {
+        1 => (Parse::Fastest, BUCKET_SIZE, 32),
+        2 => (Parse::Greedy, 6, 10),
+        3 => (Parse::Greedy, 12, 14),
+        4 => (Parse::Greedy, 16, 30),
+        5 => (Parse::Lazy, 16, 30),
+        6 => (Parse::Lazy, 35, 65),
+        7 => (Parse::Lazy, 100, 130),
+        8 => (Parse::Lazy2, 300, 258),
+        9 => (Parse::Lazy2, 600, 258),
+        10 => (Parse::Lazy2, 35, 75),
+        11 => (Parse::Lazy2, 100, 150),
+        12 => (Parse::Lazy2, 300, 258),
+        _ => panic!("libdeflate levels with compression are between 1 and 12, inclusive"),
+    }
+}
+
+const WINDOW: usize = 32 * 1024;
+
+const MAX_MATCH: usize = 258;
+
+/// The hash matchfinders need five bytes to search, as they hash the next position's four.
+const REQUIRED_BYTES: usize = 5;
+
+/// `ht_matchfinder`'s buckets hold this many positions.
+const BUCKET_SIZE: u16 = 2;
+
+const NOWHERE: u32 = u32::MAX;
+
+/// A block will not be ended within this many bytes of its start, or of the end of the input.
+const MIN_BLOCK_LENGTH: usize = 5000;
+
+/// The block is ended after a step which reaches this many bytes, unless it'd leave less
+/// than a `MIN_BLOCK_LENGTH` block at the end.
+const SOFT_MAX_BLOCK_LENGTH: usize = 300_000;
+const FAST_SOFT_MAX_BLOCK_LENGTH: usize = 65_535;
+
+/// The block is ended after a step which makes it this many matches.
+const SEQ_STORE_LENGTH: usize = 50_000;
+const FAST_SEQ_STORE_LENGTH: usize = 8192;
+
+const OBSERVATIONS_PER_BLOCK_CHECK: u32 = 512;
+const OBSERVATION_TYPES: usize = 8 + 2;
+
+/// `lz_hash`: the top `bits` of a multiplicative hash.
+fn lz_hash(seq: u32, bits: u32) -> usize {
+    usize(seq.wrapping_mul(0x1E35_A7BD) >> (32 - bits))
+}
+
+/// `bsr32`: the index of the highest set bit.
+fn bsr(value: u16) -> i32 {
+    31 - value.leading_zeros() as i32 - 16
+}
+
+/// The hash tables, for the preroll and the data.
+#[derive(Debug)]
+pub struct Matchfinder {
+    bytes: Vec<u8>,
+
+    /// For each position, the previous position in its `hash3_tab` bucket, for `hc_matchfinder`.
+    three: Vec<u32>,
+
+    /// The previous position in its `hash4_tab` bucket or, for `ht_matchfinder`, its bucket.
+    four: Vec<u32>,
+}
+
+impl Matchfinder {
+    pub fn new(preroll: &[u8], data: &[u8], parse: Parse) -> Matchfinder {
+        let mut bytes = Vec::with_capacity(preroll.len() + data.len());
+        bytes.extend_from_slice(preroll);
+        bytes.extend_from_slice(data);
+
+        let fast = Parse::Fastest == parse;
+        let mut three = vec![NOWHERE; bytes.len()];
+        let mut four = vec![NOWHERE; bytes.len()];
+
+        let mut last_three = vec![NOWHERE; 1 << 15];
+        let mut last_four = vec![NOWHERE; if fast { 1 << 15 } else { 1 << 16 }];
+
+        for pos in 0..bytes.len().saturating_sub(REQUIRED_BYTES - 1) {
+            let (hash3, hash4) = if 0 == pos {
+                (0, 0)
+            } else {
+                let seq = le32(&bytes, pos);
+                if fast {
+                    (0, lz_hash(seq, 15))
+                } else {
+                    (lz_hash(seq & 0xff_ffff, 15), lz_hash(seq, 16))
+                }
+            };
+
+            let pos = u32(pos).expect("inputs fit in memory");
+            if !fast {
+                three[usize(pos)] = mem::replace(&mut last_three[hash3], pos);
+            }
+            four[usize(pos)] = mem::replace(&mut last_four[hash4], pos);
+        }
+
+        Matchfinder { bytes, three, four }
+    }
+
+    fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// The position before `pos` in `chain`, if it's inside the window.
+    fn before(&self, chain: &[u32], pos: usize, from: usize) -> Option<usize> {
+        match chain[pos] {
+            NOWHERE => None,
+            prev if from - usize(prev) < WINDOW => Some(usize(prev)),
+            _ => None,
+        }
+    }
+
+    /// How many bytes at `pos` match those at `node`, up to `max_len`.
+    fn match_len(&self, pos: usize, node: usize, max_len: usize) -> usize {
+        (0..max_len)
+            .take_while(|&i| self.bytes[node + i] == self.bytes[pos + i])
+            .count()
+    }
+
+    fn reference(pos: usize, node: usize, len: usize) -> Ref {
+        Ref::new(
+            u16(pos - node).expect("inside the window"),
+            u16(len).expect("at most a match"),
+        )
+    }
+
+    /// `hc_matchfinder_longest_match`: the longest match at `pos` longer than `best_len`,
+    /// looking at up to `depth` entries of the chain, and stopping at `nice` bytes.
+    fn longest_match(&self, pos: usize, best_len: u16, depth: u16, nice: u16) -> Option<Ref> {
+        let max_len = MAX_MATCH.min(self.len() - pos);
+        if max_len < REQUIRED_BYTES {
+            return None;
+        }
+
+        let nice = usize(nice).min(max_len);
+        let mut best_len = usize(best_len);
+        let mut best = None;
+
+        if best_len < 4 {
+            // the chains are only searched if the last position with this three-byte hash
+            // is inside the window, which it would be if there was a match
+            let node = self.before(&self.three, pos, pos)?;
+            if best_len < 3 && self.match_len(pos, node, 3) == 3 {
+                best_len = 3;
+                best = Some(Self::reference(pos, node, 3));
+            }
+        } else if best_len >= nice {
+            return None;
+        }
+
+        let mut node = self.before(&self.four, pos, pos);
+        for _ in 0..depth {
+            let here = match node {
+                Some(here) => here,
+                None => break,
+            };
+
+            let len = self.match_len(pos, here, max_len);
+            if len >= 4 && len > best_len {
+                best_len = len;
+                best = Some(Self::reference(pos, here, len));
+                if best_len >= nice {
+                    break;
+                }
+            }
+
+            node = self.before(&self.four, here, pos);
+        }
+
+        best
+    }
+
+    /// `ht_matchfinder_longest_match`: the longer of the two positions in `pos`'s bucket.
+    fn fastest_match(&self, pos: usize, nice: u16) -> Option<Ref> {
+        let max_len = MAX_MATCH.min(self.len() - pos);
+        let nice = usize(nice).min(max_len);
+
+        let mut best_len = 3;
+        let mut best = None;
+        let mut node = self.before(&self.four, pos, pos);
+
+        for _ in 0..BUCKET_SIZE {
+            let here = match node {
+                Some(here) => here,
+                None => break,
+            };
+
+            let len = self.match_len(pos, here, max_len);
+            if len > best_len {
+                best_len = len;
+                best = Some(Self::reference(pos, here, len));
+                if best_len >= nice {
+                    break;
+                }
+            }
+
+            node = self.before(&self.four, here, pos);
+        }
+
+        best
+    }
+}
+
+fn le32(bytes: &[u8], pos: usize) -> u32 {
+    u32::from(bytes[pos])
+        | u32::from(bytes[pos + 1]) << 8
+        | u32::from(bytes[pos + 2]) << 16
+        | u32::from(bytes[pos + 3]) << 24
+}
+
+/// `choose_min_match_len`: fewer different literals make literals cheaper,
+/// so short matches less worthwhile.
+fn choose_min_len(used_literals: usize, depth: u16) -> u16 {
+    const MIN_LENS: [u16; 80] = [
+        9, 9, 9, 9, 9, 9, 8, 8, 7, 7, 6, 6, 6, 6, 6, 6, //
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, //
+        5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 5, 4, 4, 4, //
+        4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, //
+        4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, 4, //
+    ];
+
+    let min_len = match MIN_LENS.get(used_literals) {
+        Some(&min_len) => min_len,
+        None => return 3,
+    };
+
+    // long matches are hard to find with a shallow search
+    match depth {
+        0..=4 => min_len.min(4),
+        5..=9 => min_len.min(5),
+        10..=15 => min_len.min(7),
+        _ => min_len,
+    }
+}
+
+/// The statistics `should_end_block` compares.
+#[derive(Clone, Debug, Default)]
+struct SplitStats {
+    new_observations: [u32; OBSERVATION_TYPES],
+    observations: [u32; OBSERVATION_TYPES],
+    num_new_observations: u32,
+    num_observations: u32,
+}
+
+impl SplitStats {
+    fn observe(&mut self, code: Code) {
+        let kind = match code {
+            // the top two bits, and the bottom bit
+            Code::Literal(lit) => usize(((lit >> 5) & 0x6) | (lit & 1)),
+            Code::Reference(r) => 8 + if r.run() >= 9 { 1 } else { 0 },
+        };
+        self.new_observations[kind] += 1;
+        self.num_new_observations += 1;
+    }
+
+    fn merge(&mut self) {
+        for (old, new) in self.observations.iter_mut().zip(&mut self.new_observations) {
+            *old += mem::replace(new, 0);
+        }
+        self.num_observations += mem::replace(&mut self.num_new_observations, 0);
+    }
+
+    /// `should_end_block`: have the recent codes drifted far enough from the rest of the block?
+    fn should_end(&mut self, begin: usize, pos: usize, end: usize) -> bool {
+        if self.num_new_observations < OBSERVATIONS_PER_BLOCK_CHECK
+            || pos - begin < MIN_BLOCK_LENGTH
+            || end - pos < MIN_BLOCK_LENGTH
+        {
+            return false;
+        }
+
+        if self.num_observations > 0 {
+            // the sum of the differences in the probabilities, all scaled by both counts
+            let total_delta: u32 = self
+                .observations
+                .iter()
+                .zip(&self.new_observations)
+                .map(|(&old, &new)| {
+                    let expected = old * self.num_new_observations;
+                    let actual = new * self.num_observations;
+                    if actual > expected {
+                        actual - expected
+                    } else {
+                        expected - actual
+                    }
+                })
+                .sum();
+
+            let num_items = self.num_observations + self.num_new_observations;
+            let mut cutoff = self.num_new_observations * 200 / 512 * self.num_observations;
+
+            // short blocks cost a lot of trees, so need to be very different
+            let block_length = u32(pos - begin).expect("blocks are short");
+            if block_length < 10_000 && num_items < 8192 {
+                cutoff += (u64::from(cutoff) * u64::from(8192 - num_items) / 8192) as u32;
+            }
+
+            if total_delta + (block_length / 4096) * self.num_observations >= cutoff {
+                return true;
+            }
+        }
+
+        self.merge();
+        false
+    }
+}
+
+/// What libdeflate knows about the block it's working on.
+#[derive(Clone, Debug)]
+struct Block {
+    begin: usize,
+
+    /// `in_max_block_end`.
+    max_end: usize,
+
+    min_len: u16,
+
+    /// When to next recalculate `min_len`, for the lazy parsers.
+    next_recalc: usize,
+
+    literals: [u32; 256],
+    matches: usize,
+    stats: SplitStats,
+}
+
+/// Where libdeflate is in its parse: which block, and whether it's part way through a step.
+#[derive(Clone, Debug)]
+pub struct State {
+    parse: Parse,
+    depth: u16,
+    nice: u16,
+    block: Block,
+
+    /// A lazy parser has emitted a literal for a match it's going to improve on,
+    /// so the step doesn't end until the match.
+    in_step: bool,
+}
+
+impl State {
+    /// Start a block at `pos`, as if there's been nothing but a block end before it.
+    pub fn new(mf: &Matchfinder, parse: Parse, depth: u16, nice: u16, pos: usize) -> State {
+        State {
+            parse,
+            depth,
+            nice,
+            block: Self::block(mf, parse, depth, pos),
+            in_step: false,
+        }
+    }
+
+    fn block(mf: &Matchfinder, parse: Parse, depth: u16, begin: usize) -> Block {
+        let fast = Parse::Fastest == parse;
+        let soft_max = if fast {
+            FAST_SOFT_MAX_BLOCK_LENGTH
+        } else {
+            SOFT_MAX_BLOCK_LENGTH
+        };
+
+        // `choose_max_block_end`
+        let end = mf.len();
+        let max_end = if end - begin < soft_max + MIN_BLOCK_LENGTH {
+            end
+        } else {
+            begin + soft_max
+        };
+
+        Block {
+            begin,
+            max_end,
+            min_len: if fast {
+                4
+            } else {
+                first_min_len(&mf.bytes[begin..max_end], depth)
+            },
+            next_recalc: begin + (end - begin).min(10_000),
+            literals: [0; 256],
+            matches: 0,
+            stats: SplitStats::default(),
+        }
+    }
+
+    /// A match at `pos` the greedy and lazy parsers would consider taking.
+    fn acceptable(&self, mf: &Matchfinder, pos: usize) -> Option<Ref> {
+        let min_len = self.block.min_len;
+        let nice = self.nice;
+
+        let found = mf.longest_match(pos, min_len - 1, self.depth, nice)?;
+
+        match self.parse {
+            Parse::Greedy if 3 == found.run() && found.dist() > 4096 => None,
+            Parse::Lazy | Parse::Lazy2 if 3 == found.run() && found.dist() > 8192 => None,
+            _ => Some(found),
+        }
+    }
+
+    /// The codes for the step starting at `pos`.
+    pub fn codes(&self, mf: &Matchfinder, pos: usize) -> Vec<Code> {
+        let literal = |pos: usize| Code::Literal(mf.bytes[pos]);
+
+        match self.parse {
+            Parse::Fastest => {
+                if mf.len() - pos < REQUIRED_BYTES {
+                    // the last few bytes are all written as literals, without searching
+                    return (pos..mf.len()).map(literal).collect();
+                }
+
+                vec![match mf.fastest_match(pos, self.nice) {
+                    Some(r) => r.into(),
+                    None => literal(pos),
+                }]
+            }
+
+            Parse::Greedy => vec![match self.acceptable(mf, pos) {
+                Some(r) => r.into(),
+                None => literal(pos),
+            }],
+
+            Parse::Lazy | Parse::Lazy2 => self.lazy(mf, pos),
+        }
+    }
+
+    /// `deflate_compress_lazy_generic`, from the search at `pos`.
+    fn lazy(&self, mf: &Matchfinder, pos: usize) -> Vec<Code> {
+        let mut cur = match self.acceptable(mf, pos) {
+            Some(cur) => cur,
+            None => return vec![Code::Literal(mf.bytes[pos])],
+        };
+
+        // which is better isn't simply which is longer: nearer matches have cheaper distances
+        let better = |cur: Ref, next: Option<Ref>, margin: i32| {
+            next.filter(|next| {
+                next.run() >= cur.run()
+                    && 4 * i32::from(next.run() - cur.run()) + (bsr(cur.dist()) - bsr(next.dist()))
+                        > margin
+            })
+        };
+
+        let mut ret = Vec::with_capacity(3);
+        let mut at = pos;
+
+        loop {
+            // `nice_len` is shortened as the end approaches, as of the last search
+            if usize(cur.run()) >= usize(self.nice).min(mf.len() - at) {
+                break;
+            }
+
+            let next = mf.longest_match(at + 1, cur.run() - 1, self.depth >> 1, self.nice);
+            if let Some(next) = better(cur, next, 2) {
+                ret.push(Code::Literal(mf.bytes[at]));
+                cur = next;
+                at += 1;
+                continue;
+            }
+
+            if Parse::Lazy2 != self.parse {
+                break;
+            }
+
+            let next = mf.longest_match(at + 2, cur.run() - 1, self.depth >> 2, self.nice);
+            if let Some(next) = better(cur, next, 6) {
+                ret.push(Code::Literal(mf.bytes[at]));
+                ret.push(Code::Literal(mf.bytes[at + 1]));
+                cur = next;
+                at += 2;
+                continue;
+            }
+
+            break;
+        }
+
+        ret.push(cur.into());
+        ret
+    }
+
+    /// Account for the `code` at `pos`, returning whether libdeflate would end the block after it.
+    pub fn feedback(&mut self, mf: &Matchfinder, pos: usize, code: Code) -> bool {
+        let fast = Parse::Fastest == self.parse;
+        let lazy = Parse::Lazy == self.parse || Parse::Lazy2 == self.parse;
+
+        let step_ended = match code {
+            Code::Literal(lit) => {
+                self.block.literals[usize(lit)] += 1;
+
+                // a literal only continues the step if there was a match it's about to improve on
+                if lazy && !self.in_step && self.acceptable(mf, pos).is_some() {
+                    self.in_step = true;
+                }
+                !self.in_step
+            }
+            Code::Reference(_) => {
+                self.block.matches += 1;
+                self.in_step = false;
+                true
+            }
+        };
+
+        if !fast {
+            self.block.stats.observe(code);
+        }
+
+        if !step_ended {
+            return false;
+        }
+
+        let pos = pos + usize(code.emitted_bytes());
+        let block = &mut self.block;
+
+        let seq_store = if fast {
+            FAST_SEQ_STORE_LENGTH
+        } else {
+            SEQ_STORE_LENGTH
+        };
+
+        if pos >= block.max_end
+            || block.matches >= seq_store
+            || (!fast && block.stats.should_end(block.begin, pos, mf.len()))
+        {
+            self.block = Self::block(mf, self.parse, self.depth, pos);
+            return true;
+        }
+
+        if lazy && pos >= block.next_recalc {
+            block.min_len = recalculated_min_len(&block.literals, self.depth);
+            block.next_recalc += (mf.len() - block.next_recalc).min(pos - block.begin);
+        }
+
+        false
+    }
+}
+
+/// `calculate_min_match_len`, from the different literals in the first 4KiB of the block.
+fn first_min_len(block: &[u8], depth: u16) -> u16 {
+    // short blocks might end up with the fixed trees, where short matches are worthwhile
+    if block.len() < 512 {
+        return 3;
+    }
+
+    let mut used = [false; 256];
+    for &b in block.iter().take(4096) {
+        used[usize(b)] = true;
+    }
+
+    choose_min_len(used.iter().filter(|&&used| used).count(), depth)
+}
+
+/// `recalculate_min_match_len`, from the literals used so far, ignoring the very rare ones.
+fn recalculated_min_len(literals: &[u32; 256], depth: u16) -> u16 {
+    let cutoff = literals.iter().sum::<u32>() >> 10;
+    choose_min_len(
+        literals.iter().filter(|&&freq| freq > cutoff).count(),
+        depth,
+    )
+}
+
+/// How many of the `codes`, starting at `pos`, libdeflate would put in a block.
+pub fn block_len(
+    mf: &Matchfinder,
+    parse: Parse,
+    depth: u16,
+    nice: u16,
+    mut pos: usize,
+    codes: &[Code],
+) -> usize {
+    let mut state = State::new(mf, parse, depth, nice, pos);
+
+    for (id, &code) in codes.iter().enumerate() {
+        if state.feedback(mf, pos, code) {
+            return id + 1;
+        }
+        pos += usize(code.emitted_bytes());
+    }
+
+    codes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_like_libdeflate() {
+        assert_eq!(0, lz_hash(0, 15));
+        assert_eq!(0x1E35_A7BD >> 17, lz_hash(1, 15));
+        assert_eq!(0x1E35_A7BD >> 16, lz_hash(1, 16));
+        assert_eq!(0, bsr(1));
+        assert_eq!(12, bsr(4096));
+        assert_eq!(15, bsr(32_768));
+    }
+
+    #[test]
+    fn fewer_literals_need_longer_matches() {
+        assert_eq!(3, first_min_len(b"ACGT", 35));
+
+        let dna = b"ACGTTGCA".repeat(100);
+        assert_eq!(9, first_min_len(&dna, 35));
+
+        // a shallow search can't find long matches anyway
+        assert_eq!(7, first_min_len(&dna, 12));
+        assert_eq!(5, first_min_len(&dna, 6));
+
+        let hex: Vec<u8> = (0..1000u32)
+            .flat_map(|i| format!("{:08x}\n", i).into_bytes())
+            .collect();
+        assert_eq!(5, first_min_len(&hex, 35));
+    }
+
+    #[test]
+    fn the_first_position_is_hashed_as_zero() {
+        // "abcd" at zero isn't in its own chain, so only the repeat at five can be found
+        let data = b"abcdeabcdfabcdg";
+        let mf = Matchfinder::new(&[], data, Parse::Greedy);
+        assert_eq!(Some(Ref::new(5, 4)), mf.longest_match(10, 2, 10, 258));
+        assert_eq!(None, mf.longest_match(5, 2, 10, 258));
+    }
+}
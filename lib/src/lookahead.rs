@@ -7,6 +7,15 @@ pub enum Lookahead {
     Greedy,
     Gzip,
     ThreeZip,
+
+    /// As `Gzip`, but a match of 128 or more is taken straight away, even from the lookahead.
+    Miniz,
+
+    /// libdeflate's lazy parse, which compares distances as well as lengths; see `libdeflate`.
+    Libdeflate,
+
+    /// libdeflate's lazy2 parse, which also looks two bytes ahead.
+    Libdeflate2,
 }
 
 impl Lookahead {
@@ -15,6 +24,10 @@ impl Lookahead {
             Lookahead::Greedy => greedy(looker, pos),
             Lookahead::Gzip => gzip(looker, pos),
             Lookahead::ThreeZip => three_zip(looker, pos),
+            Lookahead::Miniz => miniz(looker, pos),
+            Lookahead::Libdeflate | Lookahead::Libdeflate2 => {
+                unreachable!("libdeflate's parse depends on its blocks, so lives in its Scanner")
+            }
         }
     }
 }
@@ -52,6 +65,40 @@ fn gzip<L: Looker>(looker: &L, mut pos: usize) -> Vec<Code> {
     ret
 }
 
+/// `compress_normal`'s lazy matching, which stops looking for something better at 128 bytes.
+fn miniz<L: Looker>(looker: &L, mut pos: usize) -> Vec<Code> {
+    const LONG_ENOUGH: u16 = 128;
+
+    let mut ret = Vec::with_capacity(3);
+
+    let (mut curr_lit, mut curr_ref) = match looker.best_candidate(pos) {
+        (_, Some(start)) if start.run() >= LONG_ENOUGH => return vec![start.into()],
+        (lit, Some(start)) => (lit, start),
+        (b, None) => return vec![Code::Literal(b)],
+    };
+
+    loop {
+        pos += 1;
+        match looker.best_candidate_better_than(pos, Some(curr_ref.run())) {
+            (b, Some(new)) if new.run() > curr_ref.run() => {
+                ret.push(Code::Literal(curr_lit));
+                if new.run() >= LONG_ENOUGH {
+                    ret.push(new.into());
+                    break;
+                }
+                curr_lit = b;
+                curr_ref = new;
+            }
+            (_, None) | (_, Some(_)) => {
+                ret.push(Code::Reference(curr_ref));
+                break;
+            }
+        };
+    }
+
+    ret
+}
+
 fn three_zip<L: Looker>(looker: &L, pos: usize) -> Vec<Code> {
     let (first_literal, first_best) = match looker.best_candidate(pos) {
         // there's a good run, use it
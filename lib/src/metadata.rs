@@ -0,0 +1,1250 @@
+//! The `.rezip` file: everything, other than the uncompressed data itself,
+//! which is needed to rebuild the original `.gz`, or zlib, file bit-for-bit.
+//!
+//! Layout (all integers little endian):
+//!
+//!  * `MAGIC`, then a `VERSION` byte.
+//!  * The `Config` used to generate the traces.
+//!  * A count of gzip members, then, for each: its header, verbatim, its stretches, and its
+//!    footer, unless the file ended first. Each member is compressed afresh, so is traced
+//!    without any of the previous member's data. A zlib stream is stored as one member,
+//!    with its own header, and its Adler-32 as the footer.
+//!  * For the stretches, a count, then a record for each, in order. A stretch is either a stored
+//!    block, or a run of consecutive huffman blocks, which are traced in one pass, as the
+//!    compressor didn't reset anything between them (so pigz's chunks are stretches of their
+//!    own, even if no stored block separates them). For each: the type, how many bytes
+//!    of uncompressed data it covers, then, for huffman stretches, the `Config` it was
+//!    traced with, if that's not the file's, the trace, and a record for each block:
+//!    its type and where it ends, if they're not what was predicted, how the
+//!    dynamic trees differ from the prediction (if any), and whether it's padded.
+//!  * Everything that followed the last member (normally nothing), verbatim.
+//!
+//! Each trace is stored in whichever encoding is smaller: `0`, the runs and raw corrections
+//! of `serialise_trace`, or `1`, range coded against the guesses, which needs the data
+//! to read back.
+
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian as BE;
+use byteorder::ByteOrder;
+use byteorder::LittleEndian as LE;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use cast::u32;
+use cast::usize;
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+use failure::ResultExt;
+
+use crate::bit::BitVec;
+use crate::bit::BitWriter;
+use crate::block_type;
+use crate::block_type::BlockType;
+use crate::block_type::TypeHint;
+use crate::detect;
+use crate::gzip;
+use crate::gzip::GzipFooter;
+use crate::gzip::GzipHeader;
+use crate::lookahead::Lookahead;
+use crate::parse::parse_deflate;
+use crate::picker::Picker;
+use crate::serialise::compressed_block;
+use crate::serialise::decompressed_block;
+use crate::serialise_trace;
+use crate::split;
+use crate::split::BlockEnd;
+use crate::split::Splitter;
+use crate::technique::Config;
+use crate::technique::Strategy;
+use crate::tracer;
+use crate::trees;
+use crate::trees::Trees;
+use crate::wams::LookaheadConfig;
+use crate::wams::WamsOptimisations;
+use crate::zlib;
+use crate::Block;
+use crate::CircularBuffer;
+use crate::Code;
+use crate::Trace;
+
+const MAGIC: &[u8; 5] = b"rezip";
+pub const VERSION: u8 = 15;
+
+const WINDOW: usize = 32 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub config: Config,
+    pub members: Vec<MemberRecord>,
+
+    /// Anything after the last member: garbage, or as much of its footer as there was.
+    pub trailer: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemberRecord {
+    pub header: Header,
+    pub stretches: Vec<Stretch>,
+
+    /// `None` if the file ended before the footer did; see `Metadata::trailer`.
+    pub footer: Option<Footer>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Header {
+    Gzip(GzipHeader),
+
+    /// The whole file is one zlib stream, which is traced with the window it declares.
+    Zlib(zlib::Header),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Footer {
+    Gzip(GzipFooter),
+
+    /// The Adler-32 of the data.
+    Zlib(u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stretch {
+    Uncompressed {
+        len: usize,
+    },
+    Huffman {
+        len: usize,
+
+        /// The technique which explains these blocks, if it's not the file's.
+        config: Option<Config>,
+
+        trace: Vec<Trace>,
+        blocks: Vec<BlockRecord>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockRecord {
+    pub end: BlockEnd,
+    pub block_type: TypeHint,
+
+    /// How the header differs from the prediction, iff this is a dynamic block.
+    pub trees: Option<Trees>,
+
+    /// Whether the block is followed by an empty stored block, which pads a flush to a byte
+    /// without interrupting the stream; see `Splitter::rsyncable`.
+    pub padded: bool,
+}
+
+impl MemberRecord {
+    /// The number of bytes of uncompressed data in this member.
+    pub fn len(&self) -> usize {
+        self.stretches.iter().map(|stretch| stretch.len()).sum()
+    }
+
+    /// Whether this member has no uncompressed data, like an empty gzip file.
+    pub fn is_empty(&self) -> bool {
+        0 == self.len()
+    }
+}
+
+impl Header {
+    pub fn write<W: Write>(&self, into: W) -> Result<(), Error> {
+        match *self {
+            Header::Gzip(ref header) => header.write(into),
+            Header::Zlib(ref header) => header.write(into),
+        }
+    }
+}
+
+impl Footer {
+    /// Read the footer which goes with this `header`.
+    fn read<R: Read>(mut from: R, header: &Header) -> Result<Footer, Error> {
+        Ok(match *header {
+            Header::Gzip(_) => Footer::Gzip(GzipFooter::read(from)?),
+            Header::Zlib(_) => Footer::Zlib(from.read_u32::<BE>()?),
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        match *self {
+            Footer::Gzip(ref footer) => footer.write(into),
+            Footer::Zlib(adler) => Ok(into.write_u32::<BE>(adler)?),
+        }
+    }
+
+    /// Check the footer describes the `data`.
+    pub fn check(&self, data: &[u8]) -> Result<(), Error> {
+        match *self {
+            Footer::Gzip(ref footer) => footer.check(&GzipFooter::of(data)),
+            Footer::Zlib(adler) => {
+                let calculated = zlib::adler32(data);
+                ensure!(
+                    adler == calculated,
+                    "Adler-32 mismatch: expected {:08x}, got {:08x}",
+                    adler,
+                    calculated
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Stretch {
+    /// The number of bytes of uncompressed data this stretch represents.
+    pub fn len(&self) -> usize {
+        match *self {
+            Stretch::Uncompressed { len } | Stretch::Huffman { len, .. } => len,
+        }
+    }
+
+    /// Whether this stretch has no uncompressed data, like an empty stored block.
+    pub fn is_empty(&self) -> bool {
+        0 == self.len()
+    }
+
+    fn block_count(&self) -> usize {
+        match *self {
+            Stretch::Uncompressed { .. } => 1,
+            Stretch::Huffman { ref blocks, .. } => {
+                blocks.len() + blocks.iter().filter(|block| block.padded).count()
+            }
+        }
+    }
+}
+
+/// Read a gzip file, or a zlib stream, returning the metadata needed to rebuild it, and the
+/// uncompressed data of all of its members.
+pub fn reduce<R: Read>(mut from: R, config: Config) -> Result<(Metadata, Vec<u8>), Error> {
+    let mut magic = [0u8; 2];
+    from.read_exact(&mut magic)?;
+    let from = io::Cursor::new(magic).chain(from);
+
+    if zlib::is_header(magic[0], magic[1]) {
+        return reduce_zlib(from, config);
+    }
+
+    let mut data = Vec::new();
+    let mut records = Vec::new();
+    let mut members = gzip::members(from);
+
+    while let Some(member) = members.next_member() {
+        let mut member = member?;
+        let header = Header::Gzip(member.header.clone());
+        let (stretches, member_data) = member_stretches(config, member.by_ref())?;
+
+        records.push(MemberRecord {
+            header,
+            stretches,
+            footer: member.footer()?.map(Footer::Gzip),
+        });
+        data.extend(member_data);
+    }
+
+    Ok((
+        Metadata {
+            config,
+            members: records,
+            trailer: members.trailing_garbage().to_vec(),
+        },
+        data,
+    ))
+}
+
+/// As `reduce`, for a zlib stream, which is traced with the window its header declares.
+fn reduce_zlib<R: Read>(mut from: R, config: Config) -> Result<(Metadata, Vec<u8>), Error> {
+    let header = zlib::Header::read(&mut from)?;
+    ensure!(
+        header.dictionary_id.is_none(),
+        "preset dictionaries aren't supported"
+    );
+    let config = config.with_window_bits(header.window_bits());
+
+    let mut blocks = parse_deflate(&mut from);
+    let (stretches, data) = member_stretches(config, blocks.by_ref())?;
+
+    let mut trailer = Vec::new();
+    blocks.into_inner().read_to_end(&mut trailer)?;
+
+    // as with gzip, a truncated footer is left in the trailer
+    let footer = if trailer.len() >= 4 {
+        let adler = BE::read_u32(&trailer);
+        trailer.drain(..4);
+        Some(Footer::Zlib(adler))
+    } else {
+        None
+    };
+
+    Ok((
+        Metadata {
+            config,
+            members: vec![MemberRecord {
+                header: Header::Zlib(header),
+                stretches,
+                footer,
+            }],
+            trailer,
+        },
+        data,
+    ))
+}
+
+/// The stretches of one member's `blocks`, and the data they decompress to.
+fn member_stretches<I: Iterator<Item = Result<Block, Error>>>(
+    config: Config,
+    blocks: I,
+) -> Result<(Vec<Stretch>, Vec<u8>), Error> {
+    let mut data = Vec::new();
+    let mut stretches = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+
+    // the huffman blocks we've seen since the last stored block, where they started,
+    // and which were padded
+    let mut pending = Vec::new();
+    let mut pending_start = 0;
+    let mut padded = Vec::new();
+
+    for block in blocks {
+        let block = block?;
+        let start = data.len();
+        decompressed_block(&mut data, &mut dictionary, &block)?;
+
+        match block {
+            Block::Uncompressed(ref stored)
+                if stored.is_empty()
+                    && config.splitter.rsyncable()
+                    && padded.last() == Some(&false) =>
+            {
+                *padded.last_mut().expect("just checked") = true;
+            }
+            Block::Uncompressed(_) => {
+                if !pending.is_empty() {
+                    stretches.extend(huffman_stretches(
+                        config,
+                        &data[..start],
+                        pending_start,
+                        &pending,
+                        &padded,
+                    )?);
+                    pending.clear();
+                    padded.clear();
+                }
+
+                stretches.push(Stretch::Uncompressed {
+                    len: data.len() - start,
+                });
+                pending_start = data.len();
+            }
+            huffman => {
+                pending.push(huffman);
+                padded.push(false);
+            }
+        }
+    }
+
+    if !pending.is_empty() {
+        stretches.extend(huffman_stretches(
+            config,
+            &data,
+            pending_start,
+            &pending,
+            &padded,
+        )?);
+    }
+
+    Ok((stretches, data))
+}
+
+/// The codes of a huffman block.
+fn huffman_codes(block: &Block) -> &[Code] {
+    match *block {
+        Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } => codes,
+        Block::Uncompressed(_) => unreachable!("stored blocks end stretches"),
+    }
+}
+
+/// Trace the run of huffman `blocks`, which decompressed to `data[start..]`, and which
+/// were each followed by padding, or not, as in `padded`.
+///
+/// Blocks which another technique explains better than `config`, even after paying for
+/// recording it, are split off into stretches of their own, so each run of blocks
+/// sharing a technique is still traced in one pass. So are the blocks of each chunk,
+/// for techniques which restart every chunk.
+fn huffman_stretches(
+    config: Config,
+    data: &[u8],
+    start: usize,
+    blocks: &[Block],
+    padded: &[bool],
+) -> Result<Vec<Stretch>, Error> {
+    let mut margin = vec![0u8];
+    write_config(&mut margin, &config)?;
+
+    let mut overrides = Vec::with_capacity(blocks.len());
+    let mut ends = Vec::with_capacity(blocks.len());
+    let mut pos = start;
+
+    for block in blocks {
+        let codes = huffman_codes(block);
+        let end = pos
+            + codes
+                .iter()
+                .map(|c| usize(c.emitted_bytes()))
+                .sum::<usize>();
+        overrides.push(detect::improve_on(
+            config,
+            preroll_start(pos),
+            &data[preroll_start(pos)..pos],
+            &data[pos..end],
+            codes,
+            margin.len(),
+        ));
+        ends.push(end);
+        pos = end;
+    }
+
+    let mut ret = Vec::new();
+    let mut first = 0;
+    let mut pos = start;
+
+    for id in 1..=blocks.len() {
+        if id < blocks.len()
+            && overrides[id] == overrides[first]
+            && !starts_chunk(&config, ends[id - 1], &blocks[id])
+        {
+            continue;
+        }
+
+        let end = ends[id - 1];
+        ret.push(huffman_stretch(
+            config,
+            overrides[first],
+            &data[..end],
+            pos,
+            &blocks[first..id],
+            &padded[first..id],
+        ));
+        first = id;
+        pos = end;
+    }
+
+    Ok(ret)
+}
+
+/// Whether this huffman `block`, starting `pos` bytes in, is the first of a new chunk.
+fn starts_chunk(config: &Config, pos: usize, block: &Block) -> bool {
+    !huffman_codes(block).is_empty() && config.splitter.starts_chunk(pos)
+}
+
+/// Trace the huffman `blocks`, which decompressed to `data[start..]`, as one,
+/// with `config_override`, if present, otherwise the file's `config`.
+fn huffman_stretch(
+    config: Config,
+    config_override: Option<Config>,
+    data: &[u8],
+    start: usize,
+    blocks: &[Block],
+    padded: &[bool],
+) -> Stretch {
+    let config = config_override.unwrap_or(config);
+
+    let mut codes = Vec::new();
+    let mut lens = Vec::with_capacity(blocks.len());
+    for block in blocks {
+        let block_codes = huffman_codes(block);
+        codes.extend_from_slice(block_codes);
+        lens.push(block_codes.len());
+    }
+
+    let preroll = &data[preroll_start(start)..start];
+    let stretch_data = &data[start..];
+
+    let ends = split::hint(&config.splitter, preroll, stretch_data, &codes, &lens);
+
+    Stretch::Huffman {
+        len: stretch_data.len(),
+        config: config_override,
+        trace: tracer::try_config(config, preroll_start(start), preroll, stretch_data, &codes),
+        blocks: blocks
+            .iter()
+            .zip(ends)
+            .zip(padded)
+            .map(|((block, end), &padded)| {
+                let (actual, codes, trees) = match *block {
+                    Block::FixedHuffman(ref codes) => (BlockType::FixedHuffman, codes, None),
+                    Block::DynamicHuffman {
+                        ref trees,
+                        ref codes,
+                    } => (
+                        BlockType::DynamicHuffman,
+                        codes,
+                        Some(trees::hint(codes, &config, trees)),
+                    ),
+                    Block::Uncompressed(_) => unreachable!(),
+                };
+
+                BlockRecord {
+                    end,
+                    block_type: block_type::hint(codes, &config, actual),
+                    trees,
+                    padded,
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Rebuild the original gzip file, or zlib stream, from the metadata and the uncompressed data.
+///
+/// Each member's footer is checked against that member's data before anything is written.
+pub fn expand<W: Write>(metadata: &Metadata, data: &[u8], mut into: W) -> Result<(), Error> {
+    let expected_len: usize = metadata.members.iter().map(|member| member.len()).sum();
+    ensure!(
+        expected_len == data.len(),
+        "metadata describes {} bytes of data, but we have {}",
+        expected_len,
+        data.len()
+    );
+
+    let mut start = 0;
+    for (id, member) in metadata.members.iter().enumerate() {
+        let member_data = &data[start..start + member.len()];
+        start += member.len();
+
+        if let Some(footer) = member.footer {
+            footer
+                .check(member_data)
+                .with_context(|_| format!("member {}'s data doesn't match the original", id))?;
+        }
+    }
+
+    let mut start = 0;
+    for member in &metadata.members {
+        let member_data = &data[start..start + member.len()];
+        start += member.len();
+
+        member.header.write(&mut into)?;
+        expand_stretches(metadata.config, &member.stretches, member_data, &mut into)?;
+        if let Some(footer) = member.footer {
+            footer.write(&mut into)?;
+        }
+    }
+
+    into.write_all(&metadata.trailer)?;
+
+    Ok(())
+}
+
+/// Write the `DEFLATE` stream of a member, from its stretches and the `data` they cover.
+fn expand_stretches<W: Write>(
+    file_config: Config,
+    stretches: &[Stretch],
+    data: &[u8],
+    into: W,
+) -> Result<(), Error> {
+    let block_count: usize = stretches.iter().map(|stretch| stretch.block_count()).sum();
+
+    let mut writer = BitWriter::new(into);
+    let mut start = 0usize;
+    let mut written = 0usize;
+
+    for stretch in stretches {
+        let (preroll, stretch_data) = window(data, start, stretch.len())?;
+        let preroll_start = preroll_start(start);
+        start += stretch.len();
+
+        let blocks = match *stretch {
+            Stretch::Uncompressed { .. } => {
+                vec![(Block::Uncompressed(stretch_data.to_vec()), false)]
+            }
+            Stretch::Huffman {
+                config,
+                ref trace,
+                ref blocks,
+                ..
+            } => {
+                let config = config.unwrap_or(file_config);
+                let codes = tracer::restore(config, preroll_start, preroll, stretch_data, trace)?;
+                let ends: Vec<BlockEnd> = blocks.iter().map(|block| block.end).collect();
+                let lens = split::restore(&config.splitter, preroll, stretch_data, &codes, &ends)?;
+
+                let mut codes = codes.as_slice();
+                blocks
+                    .iter()
+                    .zip(lens)
+                    .map(|(record, len)| {
+                        ensure!(
+                            len <= codes.len(),
+                            "block of {} codes, but only {} remain",
+                            len,
+                            codes.len()
+                        );
+                        let (block_codes, rest) = codes.split_at(len);
+                        codes = rest;
+                        let block_type =
+                            block_type::restore(block_codes, &config, record.block_type);
+                        let block = match (block_type, &record.trees) {
+                            (BlockType::FixedHuffman, None) => {
+                                Block::FixedHuffman(block_codes.to_vec())
+                            }
+                            (BlockType::DynamicHuffman, Some(trees)) => Block::DynamicHuffman {
+                                trees: trees::restore(block_codes, &config, trees),
+                                codes: block_codes.to_vec(),
+                            },
+                            (BlockType::Uncompressed, _) => {
+                                bail!("block would be stored, but the trace has no stored blocks")
+                            }
+                            (_, _) => {
+                                bail!("trees must be present for, and only for, dynamic blocks")
+                            }
+                        };
+                        Ok((block, record.padded))
+                    })
+                    .collect::<Result<Vec<(Block, bool)>, Error>>()?
+            }
+        };
+
+        for (block, padded) in blocks {
+            written += 1;
+            writer.write_bit(written == block_count)?;
+            compressed_block(&mut writer, &block)?;
+
+            if padded {
+                written += 1;
+                writer.write_bit(written == block_count)?;
+                compressed_block(&mut writer, &Block::Uncompressed(Vec::new()))?;
+            }
+        }
+    }
+
+    writer.align()
+}
+
+/// Store the `metadata` for the `data`; both are needed to read it back.
+pub fn write<W: Write>(metadata: &Metadata, data: &[u8], mut into: W) -> Result<(), Error> {
+    into.write_all(MAGIC)?;
+    into.write_u8(VERSION)?;
+
+    write_config(&mut into, &metadata.config)?;
+
+    into.write_u32::<LE>(u32(metadata.members.len())?)?;
+    let mut start = 0;
+    for member in &metadata.members {
+        let (_, member_data) = window(data, start, member.len())?;
+        start += member.len();
+
+        let mut header = Vec::new();
+        member.header.write(&mut header)?;
+        write_bytes(&mut into, &header)?;
+
+        write_stretches(&mut into, metadata.config, &member.stretches, member_data)?;
+
+        match member.footer {
+            Some(footer) => {
+                into.write_u8(1)?;
+                footer.write(&mut into)?;
+            }
+            None => into.write_u8(0)?,
+        }
+    }
+
+    write_bytes(&mut into, &metadata.trailer)?;
+
+    Ok(())
+}
+
+/// The stretches of a member, which cover its `data`.
+fn write_stretches<W: Write>(
+    mut into: W,
+    file_config: Config,
+    stretches: &[Stretch],
+    data: &[u8],
+) -> Result<(), Error> {
+    into.write_u32::<LE>(u32(stretches.len())?)?;
+    let mut start = 0;
+    for stretch in stretches {
+        let (preroll, stretch_data) = window(data, start, stretch.len())?;
+        let preroll_start = preroll_start(start);
+        start += stretch.len();
+
+        match *stretch {
+            Stretch::Uncompressed { len } => {
+                into.write_u8(0)?;
+                into.write_u32::<LE>(u32(len)?)?;
+            }
+            Stretch::Huffman {
+                len,
+                ref config,
+                ref trace,
+                ref blocks,
+            } => {
+                into.write_u8(1)?;
+                into.write_u32::<LE>(u32(len)?)?;
+                match *config {
+                    Some(ref config) => {
+                        into.write_u8(1)?;
+                        write_config(&mut into, config)?;
+                    }
+                    None => into.write_u8(0)?,
+                }
+                let config = config.unwrap_or(file_config);
+                write_trace(
+                    &mut into,
+                    config,
+                    preroll_start,
+                    preroll,
+                    stretch_data,
+                    trace,
+                )?;
+
+                into.write_u32::<LE>(u32(blocks.len())?)?;
+                for block in blocks {
+                    into.write_u8(match block.block_type {
+                        TypeHint::Predicted => 0,
+                        TypeHint::Actually(BlockType::FixedHuffman) => 1,
+                        TypeHint::Actually(BlockType::DynamicHuffman) => 2,
+                        TypeHint::Actually(BlockType::Uncompressed) => {
+                            bail!("stored blocks aren't part of huffman stretches")
+                        }
+                    })?;
+                    write_end(&mut into, block.end)?;
+                    match block.trees {
+                        Some(ref trees) => {
+                            into.write_u8(1)?;
+                            write_trees(&mut into, trees)?;
+                        }
+                        None => into.write_u8(0)?,
+                    }
+                    into.write_u8(block.padded as u8)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Load metadata stored by `write`, for the same `data`.
+pub fn read<R: Read>(mut from: R, data: &[u8]) -> Result<Metadata, Error> {
+    let mut magic = [0u8; 5];
+    from.read_exact(&mut magic)?;
+    ensure!(MAGIC == &magic, "invalid magic");
+
+    let version = from.read_u8()?;
+    ensure!(VERSION == version, "unsupported version: {}", version);
+
+    let file_config = read_config(&mut from)?;
+
+    let member_count = from.read_u32::<LE>()?;
+    let mut members = Vec::new();
+    let mut start = 0;
+    for _ in 0..member_count {
+        let header = read_header(&mut from)?;
+        let stretches = read_stretches(&mut from, file_config, &data[start..])?;
+        let footer = if read_bool(&mut from)? {
+            Some(Footer::read(&mut from, &header)?)
+        } else {
+            None
+        };
+
+        let member = MemberRecord {
+            header,
+            stretches,
+            footer,
+        };
+        start += member.len();
+        members.push(member);
+    }
+
+    let trailer = read_bytes(&mut from)?;
+
+    Ok(Metadata {
+        config: file_config,
+        members,
+        trailer,
+    })
+}
+
+/// The stretches of a member, which starts at the start of `data`.
+fn read_stretches<R: Read>(
+    mut from: R,
+    file_config: Config,
+    data: &[u8],
+) -> Result<Vec<Stretch>, Error> {
+    let stretch_count = from.read_u32::<LE>()?;
+    let mut stretches = Vec::new();
+    let mut start = 0;
+    for _ in 0..stretch_count {
+        let kind = from.read_u8()?;
+        let len = usize(from.read_u32::<LE>()?);
+        let (preroll, stretch_data) = window(data, start, len)?;
+        let preroll_start = preroll_start(start);
+        start += len;
+
+        stretches.push(match kind {
+            0 => Stretch::Uncompressed { len },
+            1 => {
+                let config = if read_bool(&mut from)? {
+                    Some(read_config(&mut from)?)
+                } else {
+                    None
+                };
+                let trace = read_trace(
+                    &mut from,
+                    config.unwrap_or(file_config),
+                    preroll_start,
+                    preroll,
+                    stretch_data,
+                )?;
+                let block_count = from.read_u32::<LE>()?;
+                let mut blocks = Vec::new();
+                for _ in 0..block_count {
+                    let block_type = match from.read_u8()? {
+                        0 => TypeHint::Predicted,
+                        1 => TypeHint::Actually(BlockType::FixedHuffman),
+                        2 => TypeHint::Actually(BlockType::DynamicHuffman),
+                        other => bail!("invalid block type: {}", other),
+                    };
+                    let end = read_end(&mut from)?;
+                    let trees = if read_bool(&mut from)? {
+                        Some(read_trees(&mut from)?)
+                    } else {
+                        None
+                    };
+                    let padded = read_bool(&mut from)?;
+
+                    blocks.push(BlockRecord {
+                        end,
+                        block_type,
+                        trees,
+                        padded,
+                    });
+                }
+                ensure!(!blocks.is_empty(), "huffman stretch with no blocks");
+
+                Stretch::Huffman {
+                    len,
+                    config,
+                    trace,
+                    blocks,
+                }
+            }
+            other => bail!("invalid stretch type: {}", other),
+        });
+    }
+
+    Ok(stretches)
+}
+
+/// The window before `start`, and the `len` bytes of `data` from there.
+fn window(data: &[u8], start: usize, len: usize) -> Result<(&[u8], &[u8]), Error> {
+    ensure!(
+        start + len <= data.len(),
+        "metadata describes at least {} bytes of data, but we have {}",
+        start + len,
+        data.len()
+    );
+
+    Ok((
+        &data[preroll_start(start)..start],
+        &data[start..start + len],
+    ))
+}
+
+/// How far into the stream the window before `start` starts.
+fn preroll_start(start: usize) -> usize {
+    start.saturating_sub(WINDOW)
+}
+
+fn write_bytes<W: Write>(mut into: W, bytes: &[u8]) -> Result<(), Error> {
+    into.write_u32::<LE>(u32(bytes.len())?)?;
+    into.write_all(bytes)?;
+    Ok(())
+}
+
+fn read_bytes<R: Read>(mut from: R) -> Result<Vec<u8>, Error> {
+    let len = usize(from.read_u32::<LE>()?);
+    let mut buf = vec![0u8; len];
+    from.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_header<R: Read>(from: R) -> Result<Header, Error> {
+    let bytes = read_bytes(from)?;
+    ensure!(bytes.len() >= 2, "truncated header");
+    let mut reader = io::Cursor::new(&bytes);
+    let header = if zlib::is_header(bytes[0], bytes[1]) {
+        Header::Zlib(zlib::Header::read(&mut reader)?)
+    } else {
+        Header::Gzip(GzipHeader::read(&mut reader)?)
+    };
+    ensure!(
+        bytes.len() as u64 == reader.position(),
+        "trailing bytes after header"
+    );
+    Ok(header)
+}
+
+fn write_trace<W: Write>(
+    mut into: W,
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    trace: &[Trace],
+) -> Result<(), Error> {
+    let runs = serialise_trace::write(trace);
+    let modelled = tracer::encode(config, preroll_start, preroll, data, trace);
+
+    if modelled.len() < runs.len() {
+        into.write_u8(1)?;
+        write_bytes(into, &modelled)
+    } else {
+        into.write_u8(0)?;
+        write_bytes(into, &runs)
+    }
+}
+
+fn read_trace<R: Read>(
+    mut from: R,
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+) -> Result<Vec<Trace>, Error> {
+    let encoding = from.read_u8()?;
+    let bytes = read_bytes(from)?;
+    match encoding {
+        0 => serialise_trace::read(io::Cursor::new(bytes)),
+        1 => tracer::decode(config, preroll_start, preroll, data, &bytes),
+        other => bail!("invalid trace encoding: {}", other),
+    }
+}
+
+fn write_end<W: Write>(mut into: W, end: BlockEnd) -> Result<(), Error> {
+    match end {
+        BlockEnd::Predicted => into.write_u8(0)?,
+        BlockEnd::After(codes) => {
+            into.write_u8(1)?;
+            into.write_u32::<LE>(u32(codes)?)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_end<R: Read>(mut from: R) -> Result<BlockEnd, Error> {
+    Ok(match from.read_u8()? {
+        0 => BlockEnd::Predicted,
+        1 => BlockEnd::After(usize(from.read_u32::<LE>()?)),
+        other => bail!("invalid block end: {}", other),
+    })
+}
+
+fn write_trees<W: Write>(mut into: W, trees: &Trees) -> Result<(), Error> {
+    match *trees {
+        Trees::Predicted => into.write_u8(0)?,
+        Trees::Diverged { shared, ref rest } => {
+            into.write_u8(1)?;
+            into.write_u32::<LE>(u32(shared)?)?;
+            into.write_u32::<LE>(u32(rest.len())?)?;
+            into.write_all(rest.bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_trees<R: Read>(mut from: R) -> Result<Trees, Error> {
+    Ok(match from.read_u8()? {
+        0 => Trees::Predicted,
+        1 => {
+            let shared = usize(from.read_u32::<LE>()?);
+            let bits = usize(from.read_u32::<LE>()?);
+            let mut bytes = vec![0u8; bits.div_ceil(8)];
+            from.read_exact(&mut bytes)?;
+            Trees::Diverged {
+                shared,
+                rest: BitVec::from_bytes(&bytes, bits)?,
+            }
+        }
+        other => bail!("invalid trees hint: {}", other),
+    })
+}
+
+fn write_config<W: Write>(mut into: W, config: &Config) -> Result<(), Error> {
+    into.write_u8(config.first_byte_bug as u8)?;
+
+    into.write_u8(match config.lookahead {
+        Lookahead::Greedy => 0,
+        Lookahead::Gzip => 1,
+        Lookahead::ThreeZip => 2,
+        Lookahead::Miniz => 3,
+        Lookahead::Libdeflate => 4,
+        Lookahead::Libdeflate2 => 5,
+    })?;
+
+    into.write_u8(match config.picker {
+        Picker::Longest => 0,
+        Picker::DropFarThrees => 1,
+        Picker::Zopfli => 2,
+        Picker::Miniz => 3,
+        Picker::MinizFast => 4,
+        Picker::Libdeflate => 5,
+        Picker::LibdeflateFast => 6,
+    })?;
+
+    let wams = &config.wams;
+    into.write_u16::<LE>(wams.quit_search_above_length)?;
+    into.write_u32::<LE>(u32(wams.limit_count_of_distances)?)?;
+
+    match wams.insert_only_below_length {
+        Some(len) => {
+            into.write_u8(1)?;
+            into.write_u16::<LE>(len)?;
+        }
+        None => into.write_u8(0)?,
+    }
+
+    match wams.lookahead {
+        Some(lookahead) => {
+            into.write_u8(1)?;
+            into.write_u16::<LE>(lookahead.apathetic_above_length)?;
+            into.write_u16::<LE>(lookahead.abort_above_length)?;
+        }
+        None => into.write_u8(0)?,
+    }
+
+    into.write_u8(match config.strategy {
+        Strategy::Default => 0,
+        Strategy::Filtered => 1,
+        Strategy::HuffmanOnly => 2,
+        Strategy::Rle => 3,
+        Strategy::Fixed => 4,
+    })?;
+    into.write_u8(config.hash_bits)?;
+    into.write_u16::<LE>(config.max_distance)?;
+
+    match config.splitter {
+        Splitter::Zlib { lit_bufsize } => {
+            into.write_u8(0)?;
+            into.write_u16::<LE>(lit_bufsize)?;
+        }
+        Splitter::Gzip {
+            guess,
+            lazy,
+            rsyncable,
+        } => {
+            into.write_u8(1)?;
+            into.write_u8(guess as u8)?;
+            into.write_u8(lazy as u8)?;
+            into.write_u8(rsyncable as u8)?;
+        }
+        Splitter::Zopfli { iterations } => {
+            into.write_u8(2)?;
+            into.write_u32::<LE>(iterations)?;
+        }
+        Splitter::Miniz { fast } => {
+            into.write_u8(3)?;
+            into.write_u8(fast as u8)?;
+        }
+        Splitter::Libdeflate { level } => {
+            into.write_u8(4)?;
+            into.write_u8(level)?;
+        }
+        Splitter::Pigz { chunk } => {
+            into.write_u8(5)?;
+            into.write_u32::<LE>(chunk)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_config<R: Read>(mut from: R) -> Result<Config, Error> {
+    let first_byte_bug = read_bool(&mut from)?;
+
+    let lookahead = match from.read_u8()? {
+        0 => Lookahead::Greedy,
+        1 => Lookahead::Gzip,
+        2 => Lookahead::ThreeZip,
+        3 => Lookahead::Miniz,
+        4 => Lookahead::Libdeflate,
+        5 => Lookahead::Libdeflate2,
+        other => bail!("invalid lookahead: {}", other),
+    };
+
+    let picker = match from.read_u8()? {
+        0 => Picker::Longest,
+        1 => Picker::DropFarThrees,
+        2 => Picker::Zopfli,
+        3 => Picker::Miniz,
+        4 => Picker::MinizFast,
+        5 => Picker::Libdeflate,
+        6 => Picker::LibdeflateFast,
+        other => bail!("invalid picker: {}", other),
+    };
+
+    let quit_search_above_length = from.read_u16::<LE>()?;
+    let limit_count_of_distances = usize(from.read_u32::<LE>()?);
+
+    let insert_only_below_length = if read_bool(&mut from)? {
+        Some(from.read_u16::<LE>()?)
+    } else {
+        None
+    };
+
+    let lookahead_config = if read_bool(&mut from)? {
+        Some(LookaheadConfig {
+            apathetic_above_length: from.read_u16::<LE>()?,
+            abort_above_length: from.read_u16::<LE>()?,
+        })
+    } else {
+        None
+    };
+
+    let strategy = match from.read_u8()? {
+        0 => Strategy::Default,
+        1 => Strategy::Filtered,
+        2 => Strategy::HuffmanOnly,
+        3 => Strategy::Rle,
+        4 => Strategy::Fixed,
+        other => bail!("invalid strategy: {}", other),
+    };
+
+    let hash_bits = from.read_u8()?;
+    ensure!(
+        (8..=16).contains(&hash_bits),
+        "invalid hash bits: {}",
+        hash_bits
+    );
+
+    let max_distance = from.read_u16::<LE>()?;
+    ensure!(
+        (1..=32_768).contains(&max_distance),
+        "invalid max distance: {}",
+        max_distance
+    );
+
+    let splitter = match from.read_u8()? {
+        0 => {
+            let lit_bufsize = from.read_u16::<LE>()?;
+            ensure!(lit_bufsize >= 1, "invalid lit_bufsize: {}", lit_bufsize);
+            Splitter::Zlib { lit_bufsize }
+        }
+        1 => Splitter::Gzip {
+            guess: read_bool(&mut from)?,
+            lazy: read_bool(&mut from)?,
+            rsyncable: read_bool(&mut from)?,
+        },
+        2 => {
+            let iterations = from.read_u32::<LE>()?;
+            ensure!(iterations >= 1, "invalid Zopfli iterations: {}", iterations);
+            Splitter::Zopfli { iterations }
+        }
+        3 => Splitter::Miniz {
+            fast: read_bool(&mut from)?,
+        },
+        4 => {
+            let level = from.read_u8()?;
+            ensure!(
+                (1..=12).contains(&level),
+                "invalid libdeflate level: {}",
+                level
+            );
+            Splitter::Libdeflate { level }
+        }
+        5 => {
+            let chunk = from.read_u32::<LE>()?;
+            ensure!(chunk >= 32 * 1024, "invalid pigz chunk: {}", chunk);
+            Splitter::Pigz { chunk }
+        }
+        other => bail!("invalid splitter: {}", other),
+    };
+
+    // the parse and the blocks both come from running Zopfli
+    let zopfli_splitter = matches!(splitter, Splitter::Zopfli { .. });
+    ensure!(
+        (Picker::Zopfli == picker) == zopfli_splitter,
+        "Zopfli's picker and splitter only work together"
+    );
+
+    Ok(Config {
+        first_byte_bug,
+        lookahead,
+        picker,
+        wams: WamsOptimisations {
+            quit_search_above_length,
+            limit_count_of_distances,
+            insert_only_below_length,
+            lookahead: lookahead_config,
+        },
+        strategy,
+        hash_bits,
+        max_distance,
+        splitter,
+    })
+}
+
+fn read_bool<R: Read>(mut from: R) -> Result<bool, Error> {
+    match from.read_u8()? {
+        0 => Ok(false),
+        1 => Ok(true),
+        other => Err(format_err!("invalid boolean: {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::expand;
+    use super::reduce;
+    use super::Stretch;
+    use crate::bit::BitWriter;
+    use crate::gzip::GzipFooter;
+    use crate::serialise::compressed_block;
+    use crate::technique::Config;
+    use crate::Block;
+    use crate::Code;
+    use crate::Ref;
+    use crate::Trace;
+
+    #[test]
+    fn first_byte_bug_after_stored() {
+        // a stored block, then a fixed block which, like gzip, can't reference the stream's
+        // first byte, even though it's in the preroll
+        let data = b"abcdefgh".repeat(3);
+        let codes = vec![Code::Literal(b'a'), Code::Reference(Ref::new(8, 15))];
+
+        let mut orig = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        {
+            let mut writer = BitWriter::new(&mut orig);
+            writer.write_bit(false).unwrap();
+            compressed_block(&mut writer, &Block::Uncompressed(data[..8].to_vec())).unwrap();
+            writer.write_bit(true).unwrap();
+            compressed_block(&mut writer, &Block::FixedHuffman(codes)).unwrap();
+            writer.align().unwrap();
+        }
+        GzipFooter::of(&data).write(&mut orig).unwrap();
+
+        let (meta, reduced) = reduce(io::Cursor::new(&orig), Config::gzip(6)).unwrap();
+        assert_eq!(data, reduced);
+        match meta.members[0].stretches.as_slice() {
+            [Stretch::Uncompressed { len: 8 }, Stretch::Huffman {
+                config: None,
+                trace,
+                ..
+            }] => assert!(trace.iter().all(|&t| Trace::Correct == t)),
+            other => panic!("gzip must explain the fixed block: {:?}", other),
+        }
+
+        let mut rebuilt = Vec::new();
+        expand(&meta, &reduced, &mut rebuilt).unwrap();
+        assert_eq!(orig, rebuilt);
+    }
+}
@@ -0,0 +1,232 @@
+//! miniz (and `miniz_oxide`, which `flate2`'s rust backend uses) finds matches in its own way.
+//!
+//! The levels above one (`compress_normal`) use hash chains, like zlib, but count their probes
+//! in groups of three chain entries, skipping anything which can't beat the current match on
+//! its last two bytes. Level one (`compress_fast`) keeps only the last position for each of
+//! 4096 hashes of the next three bytes, and works on the input 4096 bytes at a time.
+//!
+//! Both throw away a match of three bytes which is 8KiB or more away, and the chains store
+//! positions as `u16`s, so a position which is a multiple of 64KiB ends the chain, and a link
+//! to something more than 64KiB back points somewhere much nearer.
+//!
+//! All of this depends on where the stream started, but we only know where the `preroll`
+//! started, so a stretch which starts more than a window into the stream, i.e. one after a
+//! stored block, is traced as if the stream started a window before it, and will need
+//! corrections once the two disagree.
+
+use cast::u16;
+use cast::usize;
+
+use crate::all_refs::AllRefs;
+use crate::Ref;
+
+/// `NUM_PROBES` in miniz, indexed by level; level one uses `compress_fast` instead.
+pub const NUM_PROBES: [u16; 11] = [0, 1, 6, 32, 16, 32, 128, 256, 512, 768, 1500];
+
+/// The window, `LZ_DICT_SIZE`.
+const DICT_SIZE: usize = 32 * 1024;
+
+const MAX_MATCH: usize = 258;
+
+/// `COMP_FAST_LOOKAHEAD_SIZE`: `compress_fast` reads this much input, then compresses it all.
+const FAST_LOOKAHEAD: usize = 4096;
+
+const FAST_HASH_SIZE: usize = 4096;
+
+/// Three bytes this far away, or further, are worse than three literals.
+const FAR_THREE: u16 = 8 * 1024;
+
+/// The best match at `pos` longer than `better_than`, as `compress_normal` would find it,
+/// making at most `probes`, i.e. `NUM_PROBES`, trips around the chain.
+pub fn find_match(
+    all_refs: &AllRefs,
+    pos: usize,
+    probes: usize,
+    better_than: Option<u16>,
+) -> Option<Ref> {
+    // the lookahead is topped up to a full match before every search,
+    // and the window shrinks to make room for it
+    let lookahead = MAX_MATCH.min(all_refs.data_len() - pos);
+    let max_dist = pos.min(DICT_SIZE - lookahead);
+
+    let mut match_len = usize(better_than.unwrap_or(2));
+    if lookahead <= match_len {
+        return None;
+    }
+
+    let mut probes_left = 1 + if match_len < 32 {
+        (probes + 2) / 3
+    } else {
+        ((probes >> 2) + 2) / 3
+    };
+
+    let mut best = None;
+    let mut probe = pos;
+
+    loop {
+        // walk up to three entries looking for one which matches at the end of the current best
+        let found = 'found: loop {
+            probes_left -= 1;
+            if 0 == probes_left {
+                return best;
+            }
+
+            for _ in 0..3 {
+                let next = match all_refs.chained_before(probe) {
+                    // the chain stores positions as `u16`s, and zero is the end
+                    Some(next) if 0 != next % 0x1_0000 => next,
+                    _ => return best,
+                };
+
+                let dist = (pos - next) % 0x1_0000;
+                if 0 == dist || dist > max_dist {
+                    return best;
+                }
+
+                probe = pos - dist;
+                if all_refs.get(probe + match_len - 1) == all_refs.get(pos + match_len - 1)
+                    && all_refs.get(probe + match_len) == all_refs.get(pos + match_len)
+                {
+                    break 'found probe;
+                }
+            }
+        };
+
+        if all_refs.get(found) != all_refs.get(pos)
+            || all_refs.get(found + 1) != all_refs.get(pos + 1)
+        {
+            continue;
+        }
+
+        let dist = u16(pos - found).expect("inside the window");
+        let run = usize(all_refs.possible_run_length_at(pos, dist));
+        if run > match_len {
+            match_len = run;
+            best = Some(Ref::new(dist, u16(run).expect("at most a match")));
+            if match_len >= lookahead {
+                return best;
+            }
+        }
+    }
+}
+
+/// Whether `compress_normal` would throw away the match it found at `pos`.
+///
+/// It compares the distance with the position in the circular window, instead of with
+/// anything meaningful, so drops matches from the very start of each 32KiB of input.
+pub fn dropped(pos: usize, found: Ref) -> bool {
+    (3 == found.run() && found.dist() >= FAR_THREE) || pos % DICT_SIZE == usize(found.dist())
+}
+
+/// `compress_fast`'s single-entry hash table.
+#[derive(Clone, Debug)]
+pub struct FastTable {
+    last: Vec<u16>,
+}
+
+impl FastTable {
+    /// We don't know where the codes started in the `preroll`, so pretend they started everywhere.
+    pub fn new(all_refs: &AllRefs) -> FastTable {
+        let mut table = FastTable {
+            last: vec![0; FAST_HASH_SIZE],
+        };
+
+        for pos in 0..all_refs.preroll.len() {
+            table.insert(all_refs, pos);
+        }
+
+        table
+    }
+
+    /// Record that a code started at `pos`.
+    pub fn insert(&mut self, all_refs: &AllRefs, pos: usize) {
+        if fast_chunk(all_refs, pos).1 < 4 {
+            // the tail of each chunk is written as literals, without looking at the table
+            return;
+        }
+
+        // truncated, as it is in miniz
+        self.last[fast_hash(all_refs, pos)] = pos as u16;
+    }
+
+    /// The match `compress_fast` would take at `pos`, if any.
+    pub fn find_match(&self, all_refs: &AllRefs, pos: usize) -> Option<Ref> {
+        let (size, lookahead) = fast_chunk(all_refs, pos);
+        if lookahead < 4 {
+            return None;
+        }
+
+        let last = usize(self.last[fast_hash(all_refs, pos)]);
+        let dist = (pos - last) % 0x1_0000;
+        if 0 == dist || dist > size {
+            return None;
+        }
+
+        let probe = pos - dist;
+        if (0..3).any(|i| all_refs.get(probe + i) != all_refs.get(pos + i)) {
+            return None;
+        }
+
+        let dist = u16(dist).expect("inside the window");
+        let run = all_refs
+            .possible_run_length_at(pos, dist)
+            .min(u16(lookahead).expect("at most a chunk"));
+
+        if 3 == run && dist >= FAR_THREE {
+            return None;
+        }
+
+        Some(Ref::new(dist, run))
+    }
+}
+
+/// How far back `compress_fast` can see from `pos`, and how much of its chunk is left.
+///
+/// Each chunk starts with the window shrunk to leave room for the whole chunk, which it
+/// then grows back into as the chunk is compressed.
+fn fast_chunk(all_refs: &AllRefs, pos: usize) -> (usize, usize) {
+    let start = pos - pos % FAST_LOOKAHEAD;
+    let len = FAST_LOOKAHEAD.min(all_refs.data_len() - start);
+    let size = DICT_SIZE.min((DICT_SIZE - len).min(start) + pos - start);
+    (size, start + len - pos)
+}
+
+/// `compress_fast`'s hash of the three bytes at `pos`, read as a little-endian number.
+fn fast_hash(all_refs: &AllRefs, pos: usize) -> usize {
+    let trigram = u32::from(all_refs.get(pos))
+        | u32::from(all_refs.get(pos + 1)) << 8
+        | u32::from(all_refs.get(pos + 2)) << 16;
+    usize((trigram ^ (trigram >> 17)) % FAST_HASH_SIZE as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_far_threes_and_the_window_position() {
+        assert!(!dropped(100, Ref::new(50, 3)));
+        assert!(dropped(100_000, Ref::new(FAR_THREE, 3)));
+        assert!(!dropped(100_000, Ref::new(FAR_THREE, 4)));
+
+        // the distance happens to equal the position in the circular window
+        assert!(dropped(32 * 1024 + 7, Ref::new(7, 10)));
+        assert!(!dropped(32 * 1024 + 7, Ref::new(8, 10)));
+    }
+
+    #[test]
+    fn fast_chunks_grow_back_into_the_window() {
+        let data = vec![0u8; 40_000];
+        let all_refs = AllRefs::with_sixteen(&[], &data, u16::MAX);
+
+        assert_eq!((0, 4096), fast_chunk(&all_refs, 0));
+        assert_eq!((10, 4086), fast_chunk(&all_refs, 10));
+
+        // the window is full, less the chunk
+        assert_eq!((DICT_SIZE - 4096, 4096), fast_chunk(&all_refs, 32_768));
+        assert_eq!((DICT_SIZE - 96, 96), fast_chunk(&all_refs, 32_768 + 4000));
+
+        // the last chunk is short, so the window is bigger
+        assert_eq!((DICT_SIZE - 3136, 3136), fast_chunk(&all_refs, 36_864));
+    }
+}
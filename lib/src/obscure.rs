@@ -2,29 +2,30 @@ use crate::Obscure;
 
 type Int = usize;
 
-// TODO: O(n^2) -> O(n) by zipping or using the sorted property or something
-// from will be reverse-sorted (5, 4, 2, 1). by will be forward sorted (1, 7), (12, 3), ...
-pub fn obscure<'i, F: 'i, B>(from: F, by: B) -> Box<Iterator<Item = Int> + 'i>
+/// Drop the items of `from` which fall strictly inside one of the `by` ranges.
+///
+/// `by` must be sorted by start, and the ranges mustn't overlap, which is true of the runs of
+/// the codes, as we see them, so only the last range starting before an item can contain it.
+pub fn obscure<'i, F: 'i>(from: F, by: &'i [Obscure]) -> Box<Iterator<Item = Int> + 'i>
 where
     F: Iterator<Item = Int>,
-    B: Iterator<Item = Obscure>,
 {
-    let by: Vec<Obscure> = by.collect();
-
-    //let from: Vec<Int> = from.collect();
-    //assert_reverse_sorted(&from);
-    //let from = from.into_iter();
-
-    Box::new(from.filter(move |item| !contains(&by, *item)))
+    Box::new(from.filter(move |item| !contains(by, *item)))
 }
 
 fn contains(haystack: &[Obscure], needle: Int) -> bool {
-    for &(start, len) in haystack {
-        if needle > start && needle < (start + len as Int) {
-            #[cfg(feature = "tracing")]
-            println!("S{},{} obscures {}", start, len, needle);
-            return true;
-        }
+    let before = match haystack.binary_search_by_key(&needle, |&(start, _)| start) {
+        // a range starting at the needle doesn't obscure it, and its predecessor ends before it
+        Ok(_) => return false,
+        Err(0) => return false,
+        Err(after) => after - 1,
+    };
+
+    let (start, len) = haystack[before];
+    if needle < start + len as Int {
+        #[cfg(feature = "tracing")]
+        println!("S{},{} obscures {}", start, len, needle);
+        return true;
     }
 
     false
@@ -39,7 +40,17 @@ mod tests {
     fn obscured() {
         assert_eq!(
             &[6, 2],
-            obscure([6, 4, 2].iter().cloned(), [(3, 2)].iter().cloned())
+            obscure([6, 4, 2].iter().cloned(), &[(3, 2)])
+                .collect::<Vec<Int>>()
+                .as_slice()
+        );
+    }
+
+    #[test]
+    fn several_ranges() {
+        assert_eq!(
+            &[10, 9, 5, 4, 1, 0],
+            obscure((0..=10).rev(), &[(1, 3), (5, 4)])
                 .collect::<Vec<Int>>()
                 .as_slice()
         );
@@ -8,20 +8,41 @@ use crate::bit::BitCollector;
 use crate::bit::BitReader;
 use crate::code_tree::CodeTree;
 use crate::huffman;
+use crate::huffman::Format;
 use crate::Block;
 use crate::Code;
 use crate::Ref;
 
 pub fn parse_deflate<R: Read>(bytes: R) -> BlockIter<R> {
+    parse(bytes, Format::Deflate)
+}
+
+/// As `parse_deflate`, for zip's method 9. The blocks' references can reach 64KiB back,
+/// so need a `CircularBuffer` that big to decompress, and must be written back out with
+/// `compressed_block64`.
+pub fn parse_deflate64<R: Read>(bytes: R) -> BlockIter<R> {
+    parse(bytes, Format::Deflate64)
+}
+
+pub fn parse<R: Read>(bytes: R, format: Format) -> BlockIter<R> {
     BlockIter {
         inner: BitReader::new(bytes),
         end: false,
+        format,
     }
 }
 
 pub struct BlockIter<R: Read> {
     inner: BitReader<R>,
     end: bool,
+    format: Format,
+}
+
+impl<R: Read> BlockIter<R> {
+    /// The reader, positioned just after the stream, once the iterator has returned `None`.
+    pub fn into_inner(self) -> R {
+        self.inner.into_inner()
+    }
 }
 
 impl<R: Read> Iterator for BlockIter<R> {
@@ -40,11 +61,11 @@ impl<R: Read> Iterator for BlockIter<R> {
             Err(e) => return Some(Err(e)),
         };
 
-        Some(read_block(&mut self.inner))
+        Some(read_block(&mut self.inner, self.format))
     }
 }
 
-fn read_block<R: Read>(reader: &mut BitReader<R>) -> Result<Block, Error> {
+fn read_block<R: Read>(reader: &mut BitReader<R>, format: Format) -> Result<Block, Error> {
     match reader.read_part(2)? {
         0 => {
             reader.align()?;
@@ -54,6 +75,7 @@ fn read_block<R: Read>(reader: &mut BitReader<R>) -> Result<Block, Error> {
             reader,
             &huffman::FIXED_LENGTH_TREE,
             Some(&huffman::FIXED_DISTANCE_TREE),
+            format,
         )
         .map(Block::FixedHuffman),
         2 => {
@@ -63,7 +85,7 @@ fn read_block<R: Read>(reader: &mut BitReader<R>) -> Result<Block, Error> {
                 (huffman::read_codes(&mut tracker)?, tracker.into_data())
             };
 
-            scan_huffman_data(reader, &length, distance.as_ref())
+            scan_huffman_data(reader, &length, distance.as_ref(), format)
                 .map(|codes| Block::DynamicHuffman { trees, codes })
         }
         3 => bail!("reserved block type"),
@@ -75,6 +97,7 @@ fn scan_huffman_data<R: Read>(
     reader: &mut BitReader<R>,
     length: &CodeTree,
     distance: Option<&CodeTree>,
+    format: Format,
 ) -> Result<Vec<Code>, Error> {
     let mut ret = Vec::new();
 
@@ -95,18 +118,30 @@ fn scan_huffman_data<R: Read>(
         }
 
         // length and distance encoding
-        let run = huffman::decode_run_length(reader, sym)?;
+        let run = huffman::decode_run_length(reader, sym, format)?;
 
         let dist_sym = match distance {
             Some(dist_code) => dist_code.decode_symbol(reader)?,
             None => bail!("length symbol encountered but no table"),
         };
 
-        let dist = huffman::decode_distance(reader, dist_sym)?;
-
-        ensure!(dist >= 1 && dist <= 32_786, "invalid distance");
-
-        ret.push(Code::Reference(Ref::new(dist, run)));
+        let dist = huffman::decode_distance(reader, dist_sym, format)?;
+
+        ret.push(Code::Reference(match format {
+            Format::Deflate => {
+                ensure!(dist >= 1 && dist <= 32_768, "invalid distance");
+                Ref::new(dist as u16, run as u16)
+            }
+            Format::Deflate64 => {
+                // we only write runs one way, so can't reproduce any other
+                ensure!(
+                    sym == huffman::encode_wide_run_length(format, run).0,
+                    "unusual encoding of a run of {}",
+                    run
+                );
+                Ref::deflate64(dist, run)
+            }
+        }));
     }
 
     Ok(ret)
@@ -5,6 +5,21 @@ use crate::Ref;
 pub enum Picker {
     Longest,
     DropFarThrees,
+
+    /// Zopfli's optimal parse, of the whole master block; see `zopfli`.
+    Zopfli,
+
+    /// miniz's `compress_normal`, which walks the chain itself; see `miniz::find_match`.
+    Miniz,
+
+    /// miniz's `compress_fast`, which only remembers one position per hash.
+    MinizFast,
+
+    /// libdeflate's `hc_matchfinder`, which searches its own chains; see `libdeflate`.
+    Libdeflate,
+
+    /// libdeflate's `ht_matchfinder`, which only remembers two positions per hash.
+    LibdeflateFast,
 }
 
 impl Picker {
@@ -12,6 +27,13 @@ impl Picker {
         match *self {
             Picker::Longest => longest(candidates, cap),
             Picker::DropFarThrees => drop_far_threes(candidates, cap),
+            Picker::Zopfli => unreachable!("Zopfli doesn't pick from the candidates"),
+            Picker::Miniz | Picker::MinizFast => {
+                unreachable!("miniz doesn't pick from the candidates")
+            }
+            Picker::Libdeflate | Picker::LibdeflateFast => {
+                unreachable!("libdeflate doesn't pick from the candidates")
+            }
         }
     }
 }
@@ -21,7 +43,7 @@ fn longest<I: Iterator<Item = Ref>>(candidates: I, cap: u16) -> Option<Ref> {
 }
 
 fn drop_far_threes<I: Iterator<Item = Ref>>(candidates: I, cap: u16) -> Option<Ref> {
-    longest(candidates, cap).filter(|r| r.run() > 3 || r.dist <= 4096)
+    longest(candidates, cap).filter(|r| r.run() > 3 || r.dist() <= 4096)
 }
 
 #[cfg(test)]
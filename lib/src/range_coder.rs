@@ -0,0 +1,250 @@
+//! An adaptive binary range coder, as used by LZMA: each bit is coded with a probability,
+//! which is then nudged towards the bit that was actually seen.
+
+use cast::usize;
+
+const PROB_BITS: u32 = 15;
+const PROB_ONE: u16 = 1 << PROB_BITS;
+const MOVE_BITS: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+/// The chance, out of `PROB_ONE`, that the next bit is a zero.
+#[derive(Copy, Clone, Debug)]
+pub struct Prob(u16);
+
+impl Default for Prob {
+    fn default() -> Self {
+        Prob(PROB_ONE / 2)
+    }
+}
+
+impl Prob {
+    fn bound(self, range: u32) -> u32 {
+        (range >> PROB_BITS) * u32::from(self.0)
+    }
+
+    fn update(&mut self, bit: bool) {
+        if bit {
+            self.0 -= self.0 >> MOVE_BITS;
+        } else {
+            self.0 += (PROB_ONE - self.0) >> MOVE_BITS;
+        }
+    }
+}
+
+pub struct Encoder {
+    low: u64,
+    range: u32,
+    cache: u8,
+    cache_size: usize,
+    out: Vec<u8>,
+}
+
+impl Encoder {
+    pub fn new() -> Encoder {
+        Encoder {
+            low: 0,
+            range: u32::MAX,
+            cache: 0,
+            cache_size: 1,
+            out: Vec::new(),
+        }
+    }
+
+    pub fn bit(&mut self, prob: &mut Prob, bit: bool) {
+        let bound = prob.bound(self.range);
+        if bit {
+            self.low += u64::from(bound);
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        prob.update(bit);
+        self.normalise();
+    }
+
+    /// The low `bits` of `value`, most significant first, each with an even chance.
+    pub fn direct(&mut self, value: u32, bits: u8) {
+        for bit in (0..bits).rev() {
+            self.range >>= 1;
+            if 0 != (value >> bit) & 1 {
+                self.low += u64::from(self.range);
+            }
+            self.normalise();
+        }
+    }
+
+    /// `value`, which must fit in the bits of the `tree`, using a probability for each prefix.
+    pub fn tree(&mut self, tree: &mut [Prob], value: usize) {
+        let bits = tree_bits(tree);
+        let mut node = 1;
+        for bit in (0..bits).rev() {
+            let bit = 0 != (value >> bit) & 1;
+            self.bit(&mut tree[node], bit);
+            node = (node << 1) | bit as usize;
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        self.out
+    }
+
+    fn normalise(&mut self) {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if self.low < 0xff00_0000 || self.low > u64::from(u32::MAX) {
+            let carry = (self.low >> 32) as u8;
+            let mut temp = self.cache;
+            while 0 != self.cache_size {
+                self.out.push(temp.wrapping_add(carry));
+                temp = 0xff;
+                self.cache_size -= 1;
+            }
+            self.cache = (self.low >> 24) as u8;
+        }
+
+        self.cache_size += 1;
+        self.low = (self.low & 0x00ff_ffff) << 8;
+    }
+}
+
+pub struct Decoder<'b> {
+    bytes: &'b [u8],
+    pos: usize,
+    range: u32,
+    code: u32,
+}
+
+impl<'b> Decoder<'b> {
+    pub fn new(bytes: &'b [u8]) -> Decoder<'b> {
+        let mut decoder = Decoder {
+            bytes,
+            pos: 0,
+            range: u32::MAX,
+            code: 0,
+        };
+
+        for _ in 0..5 {
+            decoder.code = (decoder.code << 8) | u32::from(decoder.next_byte());
+        }
+
+        decoder
+    }
+
+    pub fn bit(&mut self, prob: &mut Prob) -> bool {
+        let bound = prob.bound(self.range);
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        prob.update(bit);
+        self.normalise();
+        bit
+    }
+
+    pub fn direct(&mut self, bits: u8) -> u32 {
+        let mut value = 0;
+        for _ in 0..bits {
+            self.range >>= 1;
+            let bit = self.code >= self.range;
+            if bit {
+                self.code -= self.range;
+            }
+            value = (value << 1) | u32::from(bit);
+            self.normalise();
+        }
+        value
+    }
+
+    pub fn tree(&mut self, tree: &mut [Prob]) -> usize {
+        let bits = tree_bits(tree);
+        let mut node = 1;
+        for _ in 0..bits {
+            let bit = self.bit(&mut tree[node]);
+            node = (node << 1) | bit as usize;
+        }
+        node - (1 << bits)
+    }
+
+    /// Whether we had to make up bytes past the end of the input, which is never
+    /// necessary for anything the `Encoder` wrote.
+    pub fn overran(&self) -> bool {
+        self.pos > self.bytes.len()
+    }
+
+    fn normalise(&mut self) {
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | u32::from(self.next_byte());
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes.get(self.pos).cloned().unwrap_or(0);
+        self.pos += 1;
+        byte
+    }
+}
+
+/// A tree for `bits` bit values; the first entry is unused.
+pub fn tree(bits: u8) -> Vec<Prob> {
+    vec![Prob::default(); 1 << bits]
+}
+
+fn tree_bits(tree: &[Prob]) -> usize {
+    assert!(tree.len().is_power_of_two());
+    usize(tree.len().trailing_zeros())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut probs = [Prob::default(); 2];
+        let mut values = tree(6);
+
+        let mut encoder = Encoder::new();
+        for i in 0..10_000u32 {
+            encoder.bit(&mut probs[0], 0 == i % 7);
+            encoder.bit(&mut probs[1], true);
+            encoder.direct(i, 15);
+            encoder.tree(&mut values, usize(i % 64));
+        }
+        let bytes = encoder.finish();
+
+        let mut probs = [Prob::default(); 2];
+        let mut values = tree(6);
+
+        let mut decoder = Decoder::new(&bytes);
+        for i in 0..10_000u32 {
+            assert_eq!(0 == i % 7, decoder.bit(&mut probs[0]));
+            assert!(decoder.bit(&mut probs[1]));
+            assert_eq!(i, decoder.direct(15));
+            assert_eq!(usize(i % 64), decoder.tree(&mut values));
+        }
+        assert!(!decoder.overran());
+    }
+
+    #[test]
+    fn predictable_is_small() {
+        let mut prob = Prob::default();
+        let mut encoder = Encoder::new();
+        for _ in 0..100_000 {
+            encoder.bit(&mut prob, false);
+        }
+        assert_lt!(encoder.finish().len(), 100);
+    }
+}
@@ -0,0 +1,187 @@
+//! gzip's `--rsyncable` ends a block wherever a rolling sum of the input is a multiple of
+//! `RSYNC_WIN`, so a change to the input only changes the output up to the next such
+//! boundary, instead of to the end of the file, and `rsync` can skip the rest.
+//!
+//! `Config::gzip_rsyncable` predicts those blocks; `stored` writes them out uncompressed.
+
+use std::io::Read;
+use std::io::Write;
+use std::iter;
+
+use failure::err_msg;
+use failure::Error;
+
+use crate::bit::BitWriter;
+use crate::gzip;
+use crate::gzip::GzipFooter;
+use crate::serialise::compressed_block;
+use crate::serialise::decompressed_block;
+use crate::Block;
+use crate::CircularBuffer;
+
+/// How many bytes the sum covers, and what it must be a multiple of.
+pub const RSYNC_WIN: usize = 4096;
+
+/// The most a stored block can hold.
+const MAX_STORED: usize = 0xffff;
+
+/// The positions of the bytes gzip would like to end a block after: those where the sum of
+/// the `RSYNC_WIN` bytes up to and including them is a multiple of `RSYNC_WIN`. The sum isn't
+/// checked until the window is full, so the first possible is `RSYNC_WIN` itself.
+pub fn boundaries(data: &[u8]) -> Vec<usize> {
+    let mut sum = 0usize;
+    let mut ret = Vec::new();
+
+    for (pos, &byte) in data.iter().enumerate() {
+        sum = sum.wrapping_add(usize::from(byte));
+        if pos < RSYNC_WIN {
+            continue;
+        }
+
+        sum = sum.wrapping_sub(usize::from(data[pos - RSYNC_WIN]));
+        if 0 == sum % RSYNC_WIN {
+            ret.push(pos);
+        }
+    }
+
+    ret
+}
+
+/// Re-encode every member of a gzip file as stored blocks, keeping its header and footer.
+/// Blocks end after every one of the `boundaries`, and wherever a stored block is full.
+///
+/// This is no smaller, but, like `gzip --rsyncable`, a small change to the input only
+/// changes the output near the change, so it `rsync`s well, or can be compressed by
+/// something which can see further back. Anything after the last member is kept.
+pub fn stored<R: Read, W: Write>(from: R, mut into: W) -> Result<(), Error> {
+    let mut members = gzip::members(from);
+
+    while let Some(member) = members.next_member() {
+        let mut member = member?;
+
+        let mut data = Vec::new();
+        let mut dictionary = CircularBuffer::new();
+        for block in member.by_ref() {
+            decompressed_block(&mut data, &mut dictionary, &block?)?;
+        }
+
+        let header = member.header.clone();
+        let footer = member
+            .footer()?
+            .ok_or_else(|| err_msg("truncated file: no footer"))?;
+        footer.check(&GzipFooter::of(&data))?;
+
+        header.write(&mut into)?;
+
+        {
+            let mut writer = BitWriter::new(&mut into);
+            let chunks = chunks(&data);
+            for (id, chunk) in chunks.iter().enumerate() {
+                writer.write_bit(id + 1 == chunks.len())?;
+                compressed_block(&mut writer, &Block::Uncompressed(chunk.to_vec()))?;
+            }
+            writer.align()?;
+        }
+
+        footer.write(&mut into)?;
+    }
+
+    into.write_all(members.trailing_garbage())?;
+
+    Ok(())
+}
+
+/// The data, cut after each of the `boundaries`, and into pieces which fit in a stored block.
+/// There's always at least one piece, as a stream must have a final block, even if it's empty.
+fn chunks(data: &[u8]) -> Vec<&[u8]> {
+    let mut ret = Vec::new();
+    let mut start = 0;
+
+    let ends = boundaries(data).into_iter().map(|pos| pos + 1);
+    for end in ends.chain(iter::once(data.len())) {
+        while end - start > MAX_STORED {
+            ret.push(&data[start..start + MAX_STORED]);
+            start += MAX_STORED;
+        }
+
+        if end > start {
+            ret.push(&data[start..end]);
+            start = end;
+        }
+    }
+
+    if ret.is_empty() {
+        ret.push(data);
+    }
+
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::gzip::GzipHeader;
+    use crate::parse::parse_deflate;
+
+    /// The lengths of the stored blocks, checking everything else survived.
+    fn stored_lens(orig: &[u8], data: &[u8]) -> Vec<usize> {
+        let mut out = Vec::new();
+        stored(Cursor::new(orig), &mut out).unwrap();
+
+        let mut reader = Cursor::new(&out);
+        let header = GzipHeader::read(&mut reader).unwrap();
+        assert_eq!(GzipHeader::read(Cursor::new(orig)).unwrap(), header);
+
+        let mut decompressed = Vec::new();
+        let mut lens = Vec::new();
+        for block in parse_deflate(&mut reader) {
+            match block.unwrap() {
+                Block::Uncompressed(chunk) => {
+                    lens.push(chunk.len());
+                    decompressed.extend(chunk);
+                }
+                _ => panic!("only stored blocks"),
+            }
+        }
+        assert_eq!(data, decompressed.as_slice());
+
+        let footer = GzipFooter::read(&mut reader).unwrap();
+        footer.check(&GzipFooter::of(data)).unwrap();
+        assert_eq!(out.len() as u64, reader.position());
+
+        lens
+    }
+
+    #[test]
+    fn empty() {
+        // `gzip -9n < /dev/null`
+        let orig = [
+            0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 2, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        assert_eq!(vec![0], stored_lens(&orig, &[]));
+    }
+
+    #[test]
+    fn full_blocks() {
+        // an odd and an even byte always sum to something odd, so there are no boundaries
+        let data = b"01".repeat(100_000);
+        assert!(boundaries(&data).is_empty());
+        let orig = include_bytes!("../tests/data/zero-one.gz");
+        assert_eq!(
+            vec![MAX_STORED, MAX_STORED, MAX_STORED, 200_000 - 3 * MAX_STORED],
+            stored_lens(orig, &data)
+        );
+    }
+
+    #[test]
+    fn boundaries_of_constant_data() {
+        // the sum is a multiple of the window everywhere it's checked
+        assert_eq!(
+            (RSYNC_WIN..RSYNC_WIN + 10).collect::<Vec<_>>(),
+            boundaries(&[7; RSYNC_WIN + 10])
+        );
+        assert!(boundaries(&[7; RSYNC_WIN]).is_empty());
+    }
+}
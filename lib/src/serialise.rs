@@ -10,9 +10,12 @@ use crate::bit::BitWriter;
 use crate::circles::CircularBuffer;
 use crate::code_tree::CodeTree;
 use crate::huffman;
+use crate::huffman::Format;
 use crate::Block;
 use crate::Code;
 
+const END_OF_BLOCK: usize = 256;
+
 pub fn decompressed_block<W: Write>(
     mut into: W,
     dictionary: &mut CircularBuffer,
@@ -47,7 +50,7 @@ pub fn decompressed_codes<W: Write>(
                 into.write_all(&[byte])?
             }
             Reference(r) => {
-                dictionary.copy(r.dist, r.run(), &mut into)?;
+                dictionary.copy(usize(r.wide_dist()), usize(r.wide_run()), &mut into)?;
             }
         }
     }
@@ -56,6 +59,19 @@ pub fn decompressed_codes<W: Write>(
 }
 
 pub fn compressed_block<W: Write>(into: &mut BitWriter<W>, block: &Block) -> Result<(), Error> {
+    compressed(into, block, Format::Deflate)
+}
+
+/// As `compressed_block`, for blocks from `parse_deflate64`.
+pub fn compressed_block64<W: Write>(into: &mut BitWriter<W>, block: &Block) -> Result<(), Error> {
+    compressed(into, block, Format::Deflate64)
+}
+
+fn compressed<W: Write>(
+    into: &mut BitWriter<W>,
+    block: &Block,
+    format: Format,
+) -> Result<(), Error> {
     use self::Block::*;
 
     match *block {
@@ -71,6 +87,7 @@ pub fn compressed_block<W: Write>(into: &mut BitWriter<W>, block: &Block) -> Res
                 &huffman::FIXED_LENGTH_TREE,
                 Some(&huffman::FIXED_DISTANCE_TREE),
                 codes,
+                format,
             )
         }
         DynamicHuffman {
@@ -80,7 +97,7 @@ pub fn compressed_block<W: Write>(into: &mut BitWriter<W>, block: &Block) -> Res
             into.write_bits_val(2, 2)?;
             into.write_vec(trees)?;
             let (length, distance) = huffman::read_codes(&mut trees.iter())?;
-            compressed_codes(into, &length, distance.as_ref(), codes)
+            compressed_codes(into, &length, distance.as_ref(), codes, format)
         }
     }
 }
@@ -110,6 +127,12 @@ impl Lengths {
         }
     }
 
+    /// The lengths used by fixed huffman blocks.
+    pub fn fixed() -> Self {
+        Self::new(&huffman::FIXED_LENGTH_TREE, &huffman::FIXED_DISTANCE_TREE)
+    }
+
+    /// The bits needed to write this code, including any extra bits.
     pub fn length(&self, code: &Code) -> Option<u8> {
         match *code {
             Code::Literal(byte) => self.length[usize::from(byte)],
@@ -121,16 +144,28 @@ impl Lengths {
                     None => return None,
                 };
 
-                let (code, bit_count, _) = huffman::encode_distance(r.dist).unwrap();
+                let run_extra_bits = huffman::extra_run_length(run).map_or(0, |(bits, _)| bits);
+
+                let (code, bit_count, _) = huffman::encode_distance(r.wide_dist()).unwrap();
                 let distance_symbol_len = match self.distance[usize::from(code)] {
                     Some(len) => len,
                     None => return None,
                 };
 
-                Some(run_symbol_len + distance_symbol_len + bit_count)
+                Some(run_symbol_len + run_extra_bits + distance_symbol_len + bit_count)
             }
         }
     }
+
+    /// The bits needed for the body of a block of these codes, including the end of block
+    /// marker, but not the block header. `None` if any of the codes can't be written.
+    pub fn block_len(&self, codes: &[Code]) -> Option<usize> {
+        let mut total = usize::from(self.length[END_OF_BLOCK]?);
+        for code in codes {
+            total += usize::from(self.length(code)?);
+        }
+        Some(total)
+    }
 }
 
 fn compressed_codes<W: Write>(
@@ -138,6 +173,7 @@ fn compressed_codes<W: Write>(
     length_tree: &CodeTree,
     distance_tree: Option<&CodeTree>,
     codes: &[Code],
+    format: Format,
 ) -> Result<(), Error> {
     let length_tree = length_tree.invert();
     let distance_tree = distance_tree.map(|tree| tree.invert());
@@ -156,8 +192,8 @@ fn compressed_codes<W: Write>(
                 )?;
             }
             Reference(r) => {
-                encode_run(into, &length_tree, r.run())?;
-                encode_distance(into, distance_tree.as_ref(), r.dist)?;
+                encode_run(into, &length_tree, r.wide_run(), format)?;
+                encode_distance(into, distance_tree.as_ref(), r.wide_dist())?;
             }
         }
     }
@@ -171,15 +207,13 @@ fn compressed_codes<W: Write>(
 fn encode_run<W: Write>(
     into: &mut BitWriter<W>,
     length_tree: &[Option<BitVec>],
-    run: u16,
+    run: u32,
+    format: Format,
 ) -> Result<(), Error> {
-    into.write_vec(
-        length_tree[huffman::encode_run_length(run) as usize]
-            .as_ref()
-            .unwrap(),
-    )?;
+    let (symbol, extra) = huffman::encode_wide_run_length(format, run);
+    into.write_vec(length_tree[usize(symbol)].as_ref().unwrap())?;
 
-    if let Some((bits, val)) = huffman::extra_run_length(run) {
+    if let Some((bits, val)) = extra {
         into.write_bits_val(bits, val)?;
     }
 
@@ -189,7 +223,7 @@ fn encode_run<W: Write>(
 fn encode_distance<W: Write>(
     into: &mut BitWriter<W>,
     tree: Option<&Vec<Option<BitVec>>>,
-    dist: u16,
+    dist: u32,
 ) -> Result<(), Error> {
     if let Some((code, bits, val)) = huffman::encode_distance(dist) {
         let distance_tree = tree
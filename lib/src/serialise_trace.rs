@@ -15,6 +15,14 @@ use itertools::Itertools;
 use crate::Ref;
 use crate::Trace;
 
+// Each entry starts with a u16: `0` for a literal, the distance of an absolute reference
+// (followed by its run), then the candidates, the cut short candidates (followed by their runs),
+// and, finally, runs of `Correct`.
+const CANDIDATE_OFFSET: u16 = 32_769;
+const CUT_SHORT_OFFSET: u16 = CANDIDATE_OFFSET + 256;
+const CORRECTS_OFFSET: u16 = CUT_SHORT_OFFSET + 255;
+const MAX_CORRECTS: u16 = u16::MAX - CORRECTS_OFFSET;
+
 pub fn verify(traces: &[Trace]) -> Vec<u8> {
     let data = write(traces);
     assert_eq!(
@@ -26,6 +34,34 @@ pub fn verify(traces: &[Trace]) -> Vec<u8> {
     data
 }
 
+/// How many bytes `write` will need, kept up to date one entry at a time.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Size {
+    bytes: usize,
+    corrects: usize,
+}
+
+impl Size {
+    pub fn push(&mut self, trace: Trace) {
+        match trace {
+            Trace::Correct => {
+                if 0 == self.corrects % usize(MAX_CORRECTS) {
+                    self.bytes += 2;
+                }
+                self.corrects += 1;
+                return;
+            }
+            Trace::ActuallyLiteral | Trace::Candidate { run: None, .. } => self.bytes += 2,
+            Trace::Actually(_) | Trace::Candidate { run: Some(_), .. } => self.bytes += 3,
+        }
+        self.corrects = 0;
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
 pub fn write(traces: &[Trace]) -> Vec<u8> {
     let mut ret = Vec::with_capacity(traces.len());
     let mut traces = traces.into_iter().peekable();
@@ -37,23 +73,35 @@ pub fn write(traces: &[Trace]) -> Vec<u8> {
                 traces.next();
             }
             Trace::Actually(r) => {
-                ret.write_u16::<LE>(r.dist).expect("writing to vector");
+                ret.write_u16::<LE>(r.dist()).expect("writing to vector");
                 ret.push((r.run() - 3) as u8);
                 traces.next();
             }
+            Trace::Candidate { index, run: None } => {
+                ret.write_u16::<LE>(CANDIDATE_OFFSET + u16::from(index))
+                    .expect("writing to vector");
+                traces.next();
+            }
+            Trace::Candidate {
+                index,
+                run: Some(run),
+            } => {
+                ret.write_u16::<LE>(CUT_SHORT_OFFSET + u16::from(index))
+                    .expect("writing to vector");
+                ret.push((run - 3) as u8);
+                traces.next();
+            }
             Trace::Correct => {
                 let mut corrects = traces.peeking_take_while(|x| Trace::Correct == **x).count();
-                let representation_offset = 32_768;
-                let max_representable = u16::MAX - representation_offset;
-                while corrects > usize(max_representable) {
-                    ret.write_u16::<LE>(representation_offset + max_representable)
+                while corrects > usize(MAX_CORRECTS) {
+                    ret.write_u16::<LE>(CORRECTS_OFFSET + MAX_CORRECTS)
                         .expect("writing to a vector");
-                    corrects -= usize(max_representable);
+                    corrects -= usize(MAX_CORRECTS);
                 }
 
                 assert_ne!(0, corrects);
 
-                ret.write_u16::<LE>(representation_offset + u16(corrects).unwrap())
+                ret.write_u16::<LE>(CORRECTS_OFFSET + u16(corrects).unwrap())
                     .expect("writing to a vector");
             }
         }
@@ -74,12 +122,23 @@ pub fn read<R: Read>(mut data: R) -> Result<Vec<Trace>, Error> {
 
         if 0 == first {
             ret.push(Trace::ActuallyLiteral);
-        } else if first <= 32_768 {
+        } else if first < CANDIDATE_OFFSET {
             let dist = first;
             let run_minus_3 = data.read_u8()?;
             ret.push(Trace::Actually(Ref::new(dist, u16::from(run_minus_3) + 3)));
+        } else if first < CUT_SHORT_OFFSET {
+            ret.push(Trace::Candidate {
+                index: (first - CANDIDATE_OFFSET) as u8,
+                run: None,
+            });
+        } else if first <= CORRECTS_OFFSET {
+            let run_minus_3 = data.read_u8()?;
+            ret.push(Trace::Candidate {
+                index: (first - CUT_SHORT_OFFSET) as u8,
+                run: Some(u16::from(run_minus_3) + 3),
+            });
         } else {
-            let count = first - 32_768;
+            let count = first - CORRECTS_OFFSET;
             for _ in 0..count {
                 ret.push(Trace::Correct);
             }
@@ -91,7 +150,9 @@ pub fn read<R: Read>(mut data: R) -> Result<Vec<Trace>, Error> {
 
 #[cfg(test)]
 mod tests {
+    use super::Size;
     use super::Trace;
+    use crate::Ref;
     use std::io;
 
     fn assert_round_trip(trace: &[Trace]) {
@@ -103,6 +164,27 @@ mod tests {
         );
     }
 
+    #[test]
+    fn size() {
+        let mut v = vec![Trace::Correct; 70_000];
+        v[5] = Trace::ActuallyLiteral;
+        v[6] = Trace::Actually(Ref::new(12, 3));
+        v[7] = Trace::Candidate {
+            index: 254,
+            run: None,
+        };
+        v[8] = Trace::Candidate {
+            index: 0,
+            run: Some(258),
+        };
+
+        let mut size = Size::default();
+        for (i, &trace) in v.iter().enumerate() {
+            size.push(trace);
+            assert_eq!(super::write(&v[..=i]).len(), size.bytes());
+        }
+    }
+
     #[test]
     fn long_trace() {
         let mut v = vec![Trace::Correct; 32_765];
@@ -111,6 +193,16 @@ mod tests {
         assert_round_trip(&v);
         v.push(Trace::ActuallyLiteral);
         assert_round_trip(&v);
+        v.push(Trace::Candidate {
+            index: 254,
+            run: Some(3),
+        });
+        v.push(Trace::Candidate {
+            index: 255,
+            run: None,
+        });
+        v.push(Trace::Actually(Ref::new(32_768, 258)));
+        assert_round_trip(&v);
         for _ in 0..10 {
             v.insert(5, Trace::Correct);
             assert_round_trip(&v);
@@ -0,0 +1,499 @@
+//! Where gzip and zlib end one block and start the next. Neither looks ahead at the data:
+//! a block is flushed when the buffer of codes is full or, in gzip's case, when a cheap
+//! estimate says the block is already compressing well, so it's worth starting fresh trees.
+//!
+//! miniz, similarly, ends a block when its buffer of codes is nearly full, or when the codes
+//! it has so far are barely smaller than their input.
+//!
+//! libdeflate compares the statistics of the latest codes with the rest of the block, but only
+//! between the steps of its parse, so needs the data, to find the steps; see `libdeflate`.
+//!
+//! Zopfli splits each master block where it costs least, after parsing it, so its blocks
+//! can only be found by running it; see `zopfli`.
+//!
+//! pigz compresses its input in independent chunks, each with zlib, primed with the end of the
+//! previous chunk, and flushed at the end. A block ending on a chunk boundary is where the
+//! compressor starts afresh, so the codes either side are traced separately; see `starts_chunk`.
+//!
+//! gzip's `--rsyncable` also ends a block after any code which covers one of the positions
+//! in `rsyncable::boundaries`, and pads it to a byte, so the output lines up with the input
+//! there; see `Predictor`.
+//!
+//! Anything else, like a `Z_SYNC_FLUSH` from the application, can't be predicted, so is
+//! recorded as an exception.
+
+use cast::usize;
+use failure::ensure;
+use failure::Error;
+
+use crate::huffman;
+use crate::libdeflate;
+use crate::libdeflate::Matchfinder;
+use crate::libdeflate::Parse;
+use crate::rsyncable;
+use crate::zopfli;
+use crate::Code;
+
+/// gzip's `LIT_BUFSIZE`, for builds without `SMALL_MEM`.
+const GZIP_LIT_BUFSIZE: usize = 0x8000;
+
+/// gzip only considers ending the block early every this many codes.
+const GZIP_GUESS_EVERY: usize = 0x1000;
+
+/// zlib's `lit_bufsize`, at pigz's `memLevel` of 8.
+const PIGZ_LIT_BUFSIZE: usize = 1 << 14;
+
+/// miniz's `LZ_CODE_BUF_SIZE`, in bytes: a byte per literal, three per match,
+/// and a byte of flags for every eight codes.
+const MINIZ_CODE_BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Splitter {
+    /// zlib's `_tr_tally`: the block ends when it holds `lit_bufsize - 1` codes, where
+    /// `lit_bufsize` is `1 << (memLevel + 6)`.
+    Zlib { lit_bufsize: u16 },
+
+    /// gzip's `ct_tally`: as zlib, with a fixed buffer, but, every 4096 codes, `guess`es whether
+    /// the block has compressed to less than half its input, and ends it there if so.
+    ///
+    /// `lazy` (levels 4-9) tallies each code one byte later than `deflate_fast` does, which
+    /// changes the input length the guess sees.
+    ///
+    /// `rsyncable` (`--rsyncable`) also ends the block after the code covering each boundary.
+    Gzip {
+        guess: bool,
+        lazy: bool,
+        rsyncable: bool,
+    },
+
+    /// Zopfli, with this many iterations of its parse, which decide where its blocks end.
+    Zopfli { iterations: u32 },
+
+    /// miniz's `compress_normal`: the block ends when the code buffer is within eight bytes
+    /// of full or, once the block covers more than 31KiB, the buffer is nearly as big as that.
+    /// `compress_fast` only checks whether the buffer is full.
+    Miniz { fast: bool },
+
+    /// libdeflate, at this level, which decides how it parses, and hence where it checks.
+    Libdeflate { level: u8 },
+
+    /// pigz: zlib, with its default buffer, restarted every `chunk` bytes of input
+    /// (`--blocksize`, 128KiB by default), where the last block of the chunk is flushed.
+    Pigz { chunk: u32 },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlockEnd {
+    /// The block ends where the compressor would have ended it, or at the end of the codes.
+    Predicted,
+
+    /// The block actually holds this many codes.
+    After(usize),
+}
+
+impl Splitter {
+    /// How many of `codes` the compressor would put in a block starting at `codes[0]`,
+    /// or `None` if that depends on more than the codes, as libdeflate's and Zopfli's
+    /// blocks do; see `hint`.
+    pub fn predict(&self, codes: &[Code]) -> Option<usize> {
+        let full = match *self {
+            Splitter::Zlib { lit_bufsize } => usize(lit_bufsize) - 1,
+            Splitter::Gzip { .. } => GZIP_LIT_BUFSIZE - 1,
+            Splitter::Pigz { .. } => PIGZ_LIT_BUFSIZE - 1,
+            Splitter::Miniz { .. } => codes.len(),
+            Splitter::Libdeflate { .. } | Splitter::Zopfli { .. } => return None,
+        };
+
+        let codes = &codes[..full.min(codes.len())];
+
+        Some(
+            match *self {
+                Splitter::Gzip {
+                    guess: true, lazy, ..
+                } => gzip_guess(codes, lazy),
+                Splitter::Miniz { fast } => miniz_flush(codes, fast),
+                Splitter::Gzip { guess: false, .. }
+                | Splitter::Zlib { .. }
+                | Splitter::Libdeflate { .. }
+                | Splitter::Pigz { .. }
+                | Splitter::Zopfli { .. } => None,
+            }
+            .unwrap_or(codes.len()),
+        )
+    }
+
+    /// Whether a block, with some codes, starting `pos` bytes into the stream starts a chunk,
+    /// for compressors which restart every so often.
+    ///
+    /// Runs of blocks are traced in pieces, split before each such block, with the window before
+    /// it as the preroll, as pigz primes each chunk's compressor with the previous 32KiB. Where
+    /// no block starts on the boundary, the stream can't have been chunked there, so the run is
+    /// traced as one.
+    pub fn starts_chunk(&self, pos: usize) -> bool {
+        match *self {
+            Splitter::Pigz { chunk } => 0 != pos && 0 == pos % usize(chunk),
+            _ => false,
+        }
+    }
+
+    /// Whether the compressor flushes at `rsyncable::boundaries`, padding the block to a byte
+    /// with an empty stored block, which doesn't otherwise interrupt the stream.
+    pub fn rsyncable(&self) -> bool {
+        match *self {
+            Splitter::Gzip { rsyncable, .. } => rsyncable,
+            _ => false,
+        }
+    }
+}
+
+/// A `Splitter`, with, if it needs them, libdeflate's hash tables for the data, where Zopfli's
+/// blocks end, or the positions of the boundaries gzip's `--rsyncable` flushes at, all counted
+/// from the preroll.
+///
+/// The rolling sum only covers the last `RSYNC_WIN` bytes, so it's the same in a preroll of
+/// at least that much as from the start of the stream. Every preroll is either that long,
+/// or is the start of the stream.
+struct Predictor<'s> {
+    splitter: &'s Splitter,
+    libdeflate: Option<(Matchfinder, Parse, u16, u16)>,
+    zopfli: Option<Vec<usize>>,
+    rsync_boundaries: Option<Vec<usize>>,
+}
+
+impl<'s> Predictor<'s> {
+    fn new(splitter: &'s Splitter, preroll: &[u8], data: &[u8]) -> Self {
+        Predictor {
+            splitter,
+            libdeflate: match *splitter {
+                Splitter::Libdeflate { level } => {
+                    let (parse, depth, nice) = libdeflate::params(level);
+                    let mf = Matchfinder::new(preroll, data, parse);
+                    Some((mf, parse, depth, nice))
+                }
+                _ => None,
+            },
+            zopfli: match *splitter {
+                Splitter::Zopfli { iterations } => Some(
+                    zopfli::compress(preroll, data, iterations)
+                        .iter()
+                        .map(|block| block.end)
+                        .collect(),
+                ),
+                _ => None,
+            },
+            rsync_boundaries: if splitter.rsyncable() {
+                let mut all = preroll.to_vec();
+                all.extend_from_slice(data);
+                Some(rsyncable::boundaries(&all))
+            } else {
+                None
+            },
+        }
+    }
+
+    /// As `Splitter::predict`, for `codes` starting `pos` into the preroll and data.
+    fn predict(&self, codes: &[Code], pos: usize) -> usize {
+        let len = match (self.libdeflate.as_ref(), self.zopfli.as_ref()) {
+            (Some(&(ref mf, parse, depth, nice)), _) => {
+                libdeflate::block_len(mf, parse, depth, nice, pos, codes)
+            }
+            (None, Some(ends)) => zopfli::block_len(ends, pos, codes),
+            (None, None) => self
+                .splitter
+                .predict(codes)
+                .expect("only libdeflate and Zopfli need the data"),
+        };
+
+        match self.rsync_boundaries {
+            Some(ref boundaries) => len.min(rsync_flush(boundaries, &codes[..len], pos)),
+            None => len,
+        }
+    }
+}
+
+/// How many of the `codes`, starting `pos` in, gzip puts in the block before flushing at the
+/// next boundary. It only checks after each code, so a match covering several flushes once.
+fn rsync_flush(boundaries: &[usize], codes: &[Code], pos: usize) -> usize {
+    let next = match boundaries.binary_search(&pos) {
+        Ok(id) | Err(id) => boundaries.get(id),
+    };
+
+    let next = match next {
+        Some(&next) => next,
+        None => return codes.len(),
+    };
+
+    let mut end = pos;
+    for (id, code) in codes.iter().enumerate() {
+        end += usize(code.emitted_bytes());
+        if end > next {
+            return id + 1;
+        }
+    }
+
+    codes.len()
+}
+
+fn emitted_bytes(codes: &[Code]) -> usize {
+    codes.iter().map(|code| usize(code.emitted_bytes())).sum()
+}
+
+/// The tail of `ct_tally`, which compares an upper bound for the compressed size
+/// (ignoring the length codes, for some reason) with the input size.
+fn gzip_guess(codes: &[Code], lazy: bool) -> Option<usize> {
+    let mut in_length = if lazy { 1 } else { 0 };
+    let mut distances = 0usize;
+    let mut distance_bits = 0usize;
+
+    for (id, code) in codes.iter().enumerate() {
+        if let Code::Reference(r) = *code {
+            let (_, extra_bits, _) = huffman::encode_distance(r.wide_dist()).expect("never none");
+            distances += 1;
+            distance_bits += 5 + usize(extra_bits);
+        }
+
+        let tallied = id + 1;
+        if 0 == tallied % GZIP_GUESS_EVERY {
+            let out_length = (tallied * 8 + distance_bits) / 8;
+            if distances < tallied / 2 && out_length < in_length / 2 {
+                return Some(tallied);
+            }
+        }
+
+        in_length += usize(code.emitted_bytes());
+    }
+
+    None
+}
+
+/// The checks after each code in `compress_normal` and `compress_fast`.
+fn miniz_flush(codes: &[Code], fast: bool) -> Option<usize> {
+    // the first byte of flags is reserved up front
+    let mut code_position = 1;
+    let mut total_bytes = 0;
+
+    for (id, code) in codes.iter().enumerate() {
+        code_position += match *code {
+            Code::Literal(_) => 1,
+            Code::Reference(_) => 3,
+        };
+
+        let tallied = id + 1;
+        if 0 == tallied % 8 {
+            code_position += 1;
+        }
+
+        total_bytes += usize(code.emitted_bytes());
+
+        let tight = code_position > MINIZ_CODE_BUF_SIZE - 8;
+        let fat = !fast && total_bytes > 31 * 1024 && (code_position * 115) >> 7 >= total_bytes;
+        if tight || fat {
+            return Some(tallied);
+        }
+    }
+
+    None
+}
+
+/// Work out how to recreate the `actual` block sizes, in codes, for this run of `codes`,
+/// which decompress to `data`, after the `preroll`.
+pub fn hint(
+    splitter: &Splitter,
+    preroll: &[u8],
+    data: &[u8],
+    codes: &[Code],
+    actual: &[usize],
+) -> Vec<BlockEnd> {
+    let predictor = Predictor::new(splitter, preroll, data);
+    let mut start = 0;
+    let mut pos = preroll.len();
+    actual
+        .iter()
+        .map(|&len| {
+            let predicted = predictor.predict(&codes[start..], pos);
+            pos += emitted_bytes(&codes[start..start + len]);
+            start += len;
+            if predicted == len {
+                BlockEnd::Predicted
+            } else {
+                BlockEnd::After(len)
+            }
+        })
+        .collect()
+}
+
+/// The inverse of `hint`: the number of codes in each block.
+pub fn restore(
+    splitter: &Splitter,
+    preroll: &[u8],
+    data: &[u8],
+    codes: &[Code],
+    hints: &[BlockEnd],
+) -> Result<Vec<usize>, Error> {
+    let predictor = Predictor::new(splitter, preroll, data);
+    let mut start = 0;
+    let mut pos = preroll.len();
+    let mut ret = Vec::with_capacity(hints.len());
+
+    for hint in hints {
+        let len = match *hint {
+            BlockEnd::Predicted => predictor.predict(&codes[start..], pos),
+            BlockEnd::After(len) => len,
+        };
+
+        ensure!(
+            len <= codes.len() - start,
+            "block of {} codes, but only {} remain",
+            len,
+            codes.len() - start
+        );
+
+        pos += emitted_bytes(&codes[start..start + len]);
+        start += len;
+        ret.push(len);
+    }
+
+    ensure!(
+        start == codes.len(),
+        "blocks cover {} codes, but there are {}",
+        start,
+        codes.len()
+    );
+
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Ref;
+
+    #[test]
+    fn zlib_buffer_full() {
+        let splitter = Splitter::Zlib { lit_bufsize: 128 };
+        let data = vec![b'a'; 300];
+        let codes: Vec<Code> = data.iter().map(|&b| Code::Literal(b)).collect();
+
+        assert_eq!(Some(127), splitter.predict(&codes));
+        assert_eq!(Some(46), splitter.predict(&codes[254..]));
+        assert_eq!(Some(0), splitter.predict(&[]));
+
+        let hints = hint(&splitter, &[], &data, &codes, &[127, 127, 46]);
+        assert_eq!(vec![BlockEnd::Predicted; 3], hints);
+        assert_eq!(
+            vec![127, 127, 46],
+            restore(&splitter, &[], &data, &codes, &hints).unwrap()
+        );
+    }
+
+    #[test]
+    fn flushed_early() {
+        let splitter = Splitter::Zlib { lit_bufsize: 128 };
+        let data = vec![b'a'; 300];
+        let codes: Vec<Code> = data.iter().map(|&b| Code::Literal(b)).collect();
+
+        let hints = hint(&splitter, &[], &data, &codes, &[20, 127, 127, 26]);
+        assert_eq!(
+            vec![
+                BlockEnd::After(20),
+                BlockEnd::Predicted,
+                BlockEnd::Predicted,
+                BlockEnd::Predicted
+            ],
+            hints
+        );
+        assert_eq!(
+            vec![20, 127, 127, 26],
+            restore(&splitter, &[], &data, &codes, &hints).unwrap()
+        );
+
+        assert!(restore(&splitter, &[], &data, &codes, &[BlockEnd::After(301)]).is_err());
+        assert!(restore(&splitter, &[], &data, &codes, &[BlockEnd::Predicted]).is_err());
+    }
+
+    #[test]
+    fn gzip_guess_needs_good_compression() {
+        let literals = vec![Code::Literal(b'a'); 40_000];
+        let guess = Splitter::Gzip {
+            guess: true,
+            lazy: true,
+            rsyncable: false,
+        };
+        assert_eq!(Some(32_767), guess.predict(&literals));
+
+        // ~20 bytes per code, with a third of them references
+        let mut compressing = Vec::new();
+        for _ in 0..2000 {
+            compressing.push(Code::Literal(b'a'));
+            compressing.push(Code::Literal(b'b'));
+            compressing.push(Code::Reference(Ref::new(2, 58)));
+        }
+
+        assert_eq!(Some(4096), guess.predict(&compressing));
+        assert_eq!(
+            Some(compressing.len()),
+            Splitter::Gzip {
+                guess: false,
+                lazy: true,
+                rsyncable: false,
+            }
+            .predict(&compressing)
+        );
+    }
+
+    #[test]
+    fn rsyncable_flushes_after_boundaries() {
+        let splitter = Splitter::Gzip {
+            guess: true,
+            lazy: true,
+            rsyncable: true,
+        };
+
+        // every position from `RSYNC_WIN` on is a boundary, so the seventeenth code, which
+        // covers the first, ends the block, and every block after that holds a single code
+        let data = vec![b'a'; 4200];
+        let mut codes = vec![Code::Literal(b'a')];
+        codes.extend(vec![Code::Reference(Ref::new(1, 258)); 16]);
+        codes.push(Code::Reference(Ref::new(1, 71)));
+
+        let hints = hint(&splitter, &[], &data, &codes, &[17, 1]);
+        assert_eq!(vec![BlockEnd::Predicted; 2], hints);
+        assert_eq!(
+            vec![17, 1],
+            restore(&splitter, &[], &data, &codes, &hints).unwrap()
+        );
+
+        // the same, starting eight codes in, with the rest as the preroll
+        let hints = hint(
+            &splitter,
+            &data[..1807],
+            &data[1807..],
+            &codes[8..],
+            &[9, 1],
+        );
+        assert_eq!(vec![BlockEnd::Predicted; 2], hints);
+    }
+
+    #[test]
+    fn miniz_fat_or_tight() {
+        let normal = Splitter::Miniz { fast: false };
+        let fast = Splitter::Miniz { fast: true };
+
+        // literals take more buffer than input, so the block ends as soon as it's over 31KiB
+        let literals = vec![Code::Literal(b'a'); 70_000];
+        assert_eq!(Some(31 * 1024 + 1), normal.predict(&literals));
+        assert_eq!(Some(58_248), fast.predict(&literals));
+
+        // 60 bytes in just over five bytes of buffer is never fat
+        let mut compressing = Vec::new();
+        for _ in 0..20_000 {
+            compressing.push(Code::Literal(b'a'));
+            compressing.push(Code::Literal(b'b'));
+            compressing.push(Code::Reference(Ref::new(2, 58)));
+        }
+
+        assert_eq!(Some(36_575), normal.predict(&compressing));
+        assert_eq!(Some(36_575), fast.predict(&compressing));
+        assert_eq!(Some(100), normal.predict(&compressing[..100]));
+    }
+}
@@ -1,12 +1,20 @@
 use std::u16;
 
+use cast::u16;
 use cast::usize;
 
 use crate::all_refs::AllRefs;
+use crate::libdeflate;
+use crate::libdeflate::Matchfinder;
+use crate::libdeflate::Parse;
 use crate::lookahead::Lookahead;
+use crate::miniz;
+use crate::miniz::FastTable;
 use crate::picker::Picker;
+use crate::split::Splitter;
 use crate::wams;
 use crate::wams::WamsOptimisations;
+use crate::zopfli;
 use crate::Code;
 use crate::DataLen;
 use crate::Guesser;
@@ -14,12 +22,66 @@ use crate::Looker;
 use crate::Obscure;
 use crate::Ref;
 
+/// `MIN_LOOKAHEAD` in gzip and zlib: this much of the window is kept free, so matches
+/// can be at most the window size, minus this, away.
+const MIN_LOOKAHEAD: u16 = 258 + 3 + 1;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub struct Config {
+    /// gzip (including 1.6 and probably onwards) will mis-encode
+    /// "aaaaaa" as "aa{ref one back, run=..}", as the encoder can't
+    /// be bothered to cope with pointers to the 0th character.
+    ///
+    /// Test-case:
+    ///
+    /// ```text
+    /// % yes aaaaaaaaaa | tr -d '\n' | head -c 8453631 | gzip > a.gz
+    /// % cargo run --example dump a.gz | uniq -c
+    ///     1 block 0:
+    ///     1  - dynamic huffman: BitVec: 110: 101111...
+    ///     2     - lit: 0x61: 'a'
+    /// 32765     - backref: 1 byte(s) back, 258 bytes long
+    ///     1 block 1:
+    ///     1  - fixed huffman:
+    ///     1     - backref: 1 byte(s) back, 258 bytes long
+    ///     1     - lit: 0x61: 'a'
+    /// ```
+    ///
+    /// Note the double 'a' at the start. See `AllRefs::apply_first_byte_bug_rule`.
     pub first_byte_bug: bool,
+
     pub lookahead: Lookahead,
     pub picker: Picker,
     pub wams: WamsOptimisations,
+    pub strategy: Strategy,
+
+    /// How many bits of the three-byte key go into the hash, as in zlib's `memLevel + 7`.
+    pub hash_bits: u8,
+
+    /// The furthest back a reference can point, `MAX_DIST` in gzip and zlib, which is
+    /// a little short of the window; see `with_window_bits`.
+    pub max_distance: u16,
+
+    /// Where the compressor ends each block.
+    pub splitter: Splitter,
+}
+
+/// zlib's `strategy` argument to `deflateInit2`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    Default,
+
+    /// Throw away matches of length five or less, at the lazy levels.
+    Filtered,
+
+    /// Only ever emit literals.
+    HuffmanOnly,
+
+    /// Only look for runs of the previous byte, i.e. distance one.
+    Rle,
+
+    /// Matches like `Default`; only the block type is different.
+    Fixed,
 }
 
 #[derive(Debug)]
@@ -29,20 +91,238 @@ pub struct Technique<'a, 'p: 'a, 'd: 'a> {
 }
 
 impl Config {
+    /// gzip's `deflate.c` finds matches in the same way as zlib's at the default settings,
+    /// but has a bigger buffer of codes, and guesses when to end blocks.
     pub fn gzip(level: u8) -> Self {
+        Self::gzip_flushing(level, false)
+    }
+
+    /// `gzip --rsyncable`, as gzip 1.12 does it; upstream took the flag from Debian's patch.
+    /// Matches are found exactly as without the flag, but blocks are also flushed at every
+    /// one of `rsyncable::boundaries`; see `Splitter::Gzip`.
+    pub fn gzip_rsyncable(level: u8) -> Self {
+        Self::gzip_flushing(level, true)
+    }
+
+    fn gzip_flushing(level: u8, rsyncable: bool) -> Self {
         assert!(
             level >= 1 && level <= 9,
             "gzip levels are between 1 and 9, inclusive"
         );
+        Config {
+            splitter: Splitter::Gzip {
+                guess: level >= 3,
+                lazy: level >= 4,
+                rsyncable,
+            },
+            ..Self::zlib(level, 8, 15, Strategy::Default)
+        }
+    }
+
+    /// Info-ZIP's `zip -1` to `-9`. Its `deflate.c` and `trees.c` are gzip 1.2.4's, buffer
+    /// sizes and all, so its members are traced exactly as gzip's are.
+    pub fn info_zip(level: u8) -> Self {
+        Self::gzip(level)
+    }
+
+    /// pigz, at levels 1 to 9, with a `--blocksize` of `chunk` bytes. Each chunk is compressed
+    /// by zlib, at its default settings, with the previous 32KiB as the dictionary.
+    pub fn pigz(level: u8, chunk: u32) -> Self {
+        assert!(chunk >= 32 * 1024, "pigz's chunks are at least 32KiB");
+        Config {
+            splitter: Splitter::Pigz { chunk },
+            ..Self::zlib(level, 8, 15, Strategy::Default)
+        }
+    }
+
+    /// The same technique, with a window of `1 << window_bits` bytes, as declared by the `CINFO`
+    /// of a zlib header. References are limited as zlib limits them, `MIN_LOOKAHEAD` short of
+    /// the window. Every technique already assumes a 32KiB window, so that changes nothing.
+    pub fn with_window_bits(self, window_bits: u8) -> Self {
+        if window_bits >= 15 {
+            return self;
+        }
+
+        // zlib silently upgrades 8 to 9
+        let window_bits = window_bits.max(9);
+
+        Config {
+            max_distance: self.max_distance.min((1 << window_bits) - MIN_LOOKAHEAD),
+            ..self
+        }
+    }
+
+    /// The arguments to zlib's `deflateInit2`, minus the method, which is always `Z_DEFLATED`.
+    ///
+    /// Levels 1-3 use `deflate_fast`, which is greedy, and stops inserting strings into the
+    /// hash table inside long matches; 4-9 use `deflate_slow`, which does lazy matching.
+    pub fn zlib(level: u8, mem_level: u8, window_bits: u8, strategy: Strategy) -> Self {
+        assert!(
+            level >= 1 && level <= 9,
+            "zlib levels with compression are between 1 and 9, inclusive"
+        );
+        assert!(
+            mem_level >= 1 && mem_level <= 9,
+            "zlib memLevels are between 1 and 9, inclusive"
+        );
+        assert!(
+            window_bits >= 8 && window_bits <= 15,
+            "zlib windowBits are between 8 and 15, inclusive"
+        );
+
+        // zlib silently upgrades 8 to 9
+        let window_bits = window_bits.max(9);
+
+        let lazy = level >= 4;
+
         Config {
             first_byte_bug: true,
-            lookahead: Lookahead::Greedy,
-            picker: if level >= 4 {
+            lookahead: match strategy {
+                Strategy::Default | Strategy::Filtered | Strategy::Fixed if lazy => Lookahead::Gzip,
+                _ => Lookahead::Greedy,
+            },
+            picker: if lazy {
                 Picker::DropFarThrees
             } else {
                 Picker::Longest
             },
             wams: wams::CONFIGURATIONS[usize(level - 1)],
+            strategy: match strategy {
+                // deflate_fast doesn't look at the strategy
+                Strategy::Filtered if !lazy => Strategy::Default,
+                other => other,
+            },
+            hash_bits: mem_level + 7,
+            max_distance: (1 << window_bits) - MIN_LOOKAHEAD,
+            splitter: Splitter::Zlib {
+                lit_bufsize: 1 << (mem_level + 6),
+            },
+        }
+    }
+
+    /// Zopfli, as the `zopfli` command line or crate run it, with this many iterations
+    /// of its optimal parse; both default to 15.
+    pub fn zopfli(iterations: u32) -> Self {
+        assert!(iterations >= 1, "Zopfli needs at least one iteration");
+
+        Config {
+            first_byte_bug: false,
+            lookahead: Lookahead::Greedy,
+            picker: Picker::Zopfli,
+            wams: WamsOptimisations {
+                quit_search_above_length: 258,
+                limit_count_of_distances: zopfli::MAX_CHAIN_HITS,
+                insert_only_below_length: None,
+                lookahead: None,
+            },
+            strategy: Strategy::Default,
+            hash_bits: 15,
+            max_distance: 32_767,
+            splitter: Splitter::Zopfli { iterations },
+        }
+    }
+
+    /// How many iterations Zopfli would run with this config, if it's one of Zopfli's.
+    fn zopfli_iterations(&self) -> Option<u32> {
+        match (self.picker, self.splitter) {
+            (Picker::Zopfli, Splitter::Zopfli { iterations }) => Some(iterations),
+            _ => None,
+        }
+    }
+
+    /// miniz, and `miniz_oxide`, at levels 1 (`compress_fast`) to 10. Levels 1-3 are greedy.
+    ///
+    /// `Rle` is ignored at level one, and `Filtered` and `HuffmanOnly` push it into
+    /// `compress_normal`, where `HuffmanOnly` means a search of no probes.
+    pub fn miniz(level: u8, strategy: Strategy) -> Self {
+        assert!(
+            level >= 1 && level <= 10,
+            "miniz levels with compression are between 1 and 10, inclusive"
+        );
+
+        let greedy = level <= 3;
+        let fast = 1 == level
+            && match strategy {
+                Strategy::Default | Strategy::Rle | Strategy::Fixed => true,
+                Strategy::Filtered | Strategy::HuffmanOnly => false,
+            };
+
+        Config {
+            // position zero is the end of the chain, but the fast table can point at it
+            first_byte_bug: !fast,
+            lookahead: match strategy {
+                Strategy::Default | Strategy::Filtered | Strategy::Fixed if !greedy => {
+                    Lookahead::Miniz
+                }
+                _ => Lookahead::Greedy,
+            },
+            picker: if fast {
+                Picker::MinizFast
+            } else {
+                Picker::Miniz
+            },
+            wams: WamsOptimisations {
+                quit_search_above_length: 258,
+                limit_count_of_distances: usize(miniz::NUM_PROBES[usize(level)]),
+                insert_only_below_length: None,
+                lookahead: None,
+            },
+            strategy: match strategy {
+                Strategy::Rle if fast => Strategy::Default,
+                other => other,
+            },
+            hash_bits: 15,
+            // `compress_normal` keeps a full match of lookahead in the window
+            max_distance: if fast { 32_768 } else { 32_768 - 258 },
+            splitter: Splitter::Miniz { fast },
+        }
+    }
+
+    /// libdeflate, at levels 1 to 12. Only 1-9 are emulated: 10-12 are near-optimal, which
+    /// isn't, so they're traced as if they were lazy2, with the same search limits, and most
+    /// of their codes need corrections.
+    pub fn libdeflate(level: u8) -> Self {
+        assert!(
+            level >= 1 && level <= 12,
+            "libdeflate levels with compression are between 1 and 12, inclusive"
+        );
+
+        let (parse, depth, nice) = libdeflate::params(level);
+
+        Config {
+            // the first position is hashed as zero, so is almost never found
+            first_byte_bug: true,
+            lookahead: match parse {
+                Parse::Lazy => Lookahead::Libdeflate,
+                Parse::Lazy2 => Lookahead::Libdeflate2,
+                Parse::Fastest | Parse::Greedy => Lookahead::Greedy,
+            },
+            picker: if Parse::Fastest == parse {
+                Picker::LibdeflateFast
+            } else {
+                Picker::Libdeflate
+            },
+            wams: WamsOptimisations {
+                quit_search_above_length: nice,
+                limit_count_of_distances: usize(depth),
+                insert_only_below_length: None,
+                lookahead: None,
+            },
+            strategy: Strategy::Default,
+            hash_bits: 15,
+            max_distance: 32_767,
+            splitter: Splitter::Libdeflate { level },
+        }
+    }
+
+    /// How libdeflate would parse with this config, if it's one of libdeflate's.
+    fn libdeflate_parse(&self) -> Option<Parse> {
+        match (self.picker, self.lookahead) {
+            (Picker::LibdeflateFast, _) => Some(Parse::Fastest),
+            (Picker::Libdeflate, Lookahead::Libdeflate) => Some(Parse::Lazy),
+            (Picker::Libdeflate, Lookahead::Libdeflate2) => Some(Parse::Lazy2),
+            (Picker::Libdeflate, _) => Some(Parse::Greedy),
+            _ => None,
         }
     }
 
@@ -56,6 +336,12 @@ impl Config {
             lookahead: Lookahead::ThreeZip,
             picker: Picker::DropFarThrees,
             wams: wams::CONFIGURATIONS[8],
+            strategy: Strategy::Default,
+            hash_bits: 15,
+            max_distance: 32_768,
+            splitter: Splitter::Zlib {
+                lit_bufsize: 1 << 14,
+            },
         }
     }
 }
@@ -66,15 +352,39 @@ impl<'a, 'p, 'd> Technique<'a, 'p, 'd> {
     }
 
     pub fn byte_at(&self, pos: usize) -> u8 {
-        self.all_refs.data[pos]
+        self.all_refs.get(pos)
+    }
+
+    pub fn previous_byte_run(&self, pos: usize) -> Option<Ref> {
+        self.all_refs.previous_byte_run(pos)
     }
 }
 
 impl<'a, 'p, 'd> Technique<'a, 'p, 'd> {
-    pub fn scanner(&self) -> Scanner {
+    pub fn scanner(&self) -> Scanner<'_, 'a, 'p, 'd> {
         Scanner {
             technique: self,
             obscured: Vec::new(),
+            fast: match self.config.picker {
+                Picker::MinizFast => Some(FastTable::new(self.all_refs)),
+                _ => None,
+            },
+            libdeflate: self.config.libdeflate_parse().map(|parse| {
+                let all_refs = self.all_refs;
+                let wams = &self.config.wams;
+                let mf = Matchfinder::new(all_refs.preroll, all_refs.data, parse);
+                let state = libdeflate::State::new(
+                    &mf,
+                    parse,
+                    u16(wams.limit_count_of_distances).expect("libdeflate's depths are small"),
+                    wams.quit_search_above_length,
+                    all_refs.preroll.len(),
+                );
+                (mf, state)
+            }),
+            zopfli: self.config.zopfli_iterations().map(|iterations| {
+                zopfli::Parsed::new(self.all_refs.preroll, self.all_refs.data, iterations)
+            }),
             pos: self.all_refs.preroll.len(),
         }
     }
@@ -84,6 +394,12 @@ impl<'a, 'p, 'd> Technique<'a, 'p, 'd> {
 pub struct Scanner<'t, 'a: 't, 'p: 'a + 't, 'd: 'a + 't> {
     technique: &'t Technique<'a, 'p, 'd>,
     obscured: Vec<Obscure>,
+    /// `compress_fast`'s idea of where each hash was last seen, if that's what we're emulating.
+    fast: Option<FastTable>,
+    /// libdeflate's hash tables, and where it is in its blocks, if that's what we're emulating.
+    libdeflate: Option<(Matchfinder, libdeflate::State)>,
+    /// Zopfli's parse of all the data, if that's what we're emulating.
+    zopfli: Option<zopfli::Parsed>,
     /// The distance through all known data which we have processed, including the preroll.
     pub pos: usize,
 }
@@ -93,10 +409,31 @@ impl<'t, 'a, 'p, 'd> Scanner<'t, 'a, 'p, 'd> {
         self.pos < self.data_len()
     }
 
+    /// The first `max_chain` references the compressor could see at the current position,
+    /// nearest first, ignoring the limits the `config` puts on its search.
+    pub fn candidates(&self, max_chain: usize) -> Vec<Ref> {
+        match self
+            .technique
+            .all_refs
+            .at(self.pos, &self.obscured, max_chain)
+        {
+            Some(refs) => refs.collect(),
+            None => Vec::new(),
+        }
+    }
+
     pub fn feedback(&mut self, code: Code) {
         let old_pos = self.pos;
         self.pos += usize(code.emitted_bytes());
 
+        if let Some(ref mut fast) = self.fast {
+            fast.insert(self.technique.all_refs, old_pos);
+        }
+
+        if let Some((ref mf, ref mut state)) = self.libdeflate {
+            state.feedback(mf, old_pos, code);
+        }
+
         let limit = match self.technique.config.wams.insert_only_below_length {
             Some(limit) => limit,
             None => return,
@@ -123,36 +460,60 @@ impl<'t, 'a, 'p, 'd, 'o> DataLen for Scanner<'t, 'a, 'p, 'd> {
 
 impl<'t, 'a, 'p, 'd, 'o> Looker for Scanner<'t, 'a, 'p, 'd> {
     fn best_candidate_better_than(&self, pos: usize, other: Option<u16>) -> (u8, Option<Ref>) {
-        let current_literal = self.technique.all_refs.get(pos);
-        let mut limit = self.technique.config.wams.limit_count_of_distances;
+        let config = &self.technique.config;
+        let all_refs = self.technique.all_refs;
+        let current_literal = all_refs.get(pos);
+        let mut limit = config.wams.limit_count_of_distances;
 
         if let Some(run) = other {
-            if let Some(lookahead) = self.technique.config.wams.lookahead {
-                if lookahead.abort_above_length > run {
+            if let Some(lookahead) = config.wams.lookahead {
+                if run >= lookahead.abort_above_length {
                     return (current_literal, None);
                 }
 
-                if run > lookahead.apathetic_above_length {
+                if run >= lookahead.apathetic_above_length {
                     limit /= 4;
                 }
             }
         }
 
-        let candidates = self.technique.all_refs.at(pos, &self.obscured);
+        let best = match config.strategy {
+            Strategy::HuffmanOnly => None,
+            Strategy::Rle => all_refs.previous_byte_run(pos),
+            Strategy::Default | Strategy::Filtered | Strategy::Fixed => match config.picker {
+                Picker::Miniz => miniz::find_match(all_refs, pos, limit, other),
+                Picker::MinizFast => self
+                    .fast
+                    .as_ref()
+                    .and_then(|fast| fast.find_match(all_refs, pos)),
+                picker => all_refs
+                    .at(pos, &self.obscured, limit)
+                    .and_then(|it| picker.picker(it, config.wams.quit_search_above_length)),
+            },
+        };
+
+        let best = match config.picker {
+            Picker::Miniz => best.filter(|&r| !miniz::dropped(pos, r)),
+            _ => best,
+        };
+
         (
             current_literal,
-            candidates.and_then(|it| {
-                self.technique.config.picker.picker(
-                    it.take(limit),
-                    self.technique.config.wams.quit_search_above_length,
-                )
-            }),
+            best.filter(|r| Strategy::Filtered != config.strategy || r.run() > 5),
         )
     }
 }
 
 impl<'t, 'a, 'p, 'd> Guesser for Scanner<'t, 'a, 'p, 'd> {
     fn codes(&self) -> Vec<Code> {
+        if let Some((ref mf, ref state)) = self.libdeflate {
+            return state.codes(mf, self.pos);
+        }
+
+        if let Some(ref zopfli) = self.zopfli {
+            return vec![zopfli.code_at(self.pos, self.technique.byte_at(self.pos))];
+        }
+
         self.technique.config.lookahead.lookahead(self, self.pos)
     }
 }
@@ -1,11 +1,30 @@
 use std::iter;
 
+use cast::u8;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+
+use crate::technique::Scanner;
 use crate::technique::Technique;
 use crate::Code;
 use crate::Guesser;
+use crate::Ref;
 use crate::Trace;
 
+/// How far down the chain a `Trace::Candidate` can point.
+pub const CANDIDATES: usize = 255;
+
 pub fn trace(codes: &[Code], technique: &Technique) -> Vec<Trace> {
+    trace_while(codes, technique, |_| true).expect("never gives up")
+}
+
+/// As `trace`, but gives up, returning `None`, as soon as `keep_going` rejects an entry.
+pub fn trace_while<F: FnMut(Trace) -> bool>(
+    codes: &[Code],
+    technique: &Technique,
+    mut keep_going: F,
+) -> Option<Vec<Trace>> {
     let mut ret = Vec::with_capacity(codes.len());
 
     let mut codes = codes.into_iter().peekable();
@@ -18,6 +37,9 @@ pub fn trace(codes: &[Code], technique: &Technique) -> Vec<Trace> {
         let matches = shared_prefix(&guesses, &mut codes);
         for matched in matches {
             ret.push(Trace::Correct);
+            if !keep_going(Trace::Correct) {
+                return None;
+            }
             scanner.feedback(*matched);
         }
 
@@ -27,23 +49,48 @@ pub fn trace(codes: &[Code], technique: &Technique) -> Vec<Trace> {
 
         match codes.next() {
             Some(&code) => {
-                ret.push(match code {
+                let miss = match code {
                     Code::Literal(_) => Trace::ActuallyLiteral,
-                    Code::Reference(r) => Trace::Actually(r),
-                });
+                    Code::Reference(r) => candidate(&scanner.candidates(CANDIDATES), r),
+                };
+                ret.push(miss);
+                if !keep_going(miss) {
+                    return None;
+                }
                 scanner.feedback(code);
             }
             None => panic!("the guesser guessed more than there actually are?"),
         }
     }
 
-    ret
+    Some(ret)
+}
+
+pub fn restore(trace: &[Trace], technique: &Technique) -> Result<Vec<Code>, Error> {
+    let mut trace = trace.iter();
+    let codes = restore_with(technique, |_, _| {
+        trace
+            .next()
+            .cloned()
+            .ok_or_else(|| format_err!("trace ended before the data"))
+    })?;
+
+    ensure!(
+        trace.next().is_none(),
+        "trace continues past the end of the data"
+    );
+
+    Ok(codes)
 }
 
-pub fn restore(trace: &[Trace], technique: &Technique) -> Vec<Code> {
-    let mut ret = Vec::with_capacity(trace.len());
+/// As `restore`, but asks `next` for each entry of the trace as it's needed, with the scanner,
+/// and the guess the entry corrects, so the trace can be read with them as context.
+pub fn restore_with<F>(technique: &Technique, mut next: F) -> Result<Vec<Code>, Error>
+where
+    F: FnMut(&Scanner, Code) -> Result<Trace, Error>,
+{
+    let mut ret = Vec::new();
 
-    let mut trace = trace.into_iter().peekable();
     let mut scanner = technique.scanner();
 
     while scanner.more_data() {
@@ -51,18 +98,21 @@ pub fn restore(trace: &[Trace], technique: &Technique) -> Vec<Code> {
         assert!(!guesses.is_empty());
 
         for guess in guesses {
-            let hint = *trace.next().expect("not out of data");
+            let hint = next(&scanner, guess)?;
             let orig = match hint {
                 Trace::Correct => guess,
                 Trace::Actually(r) => Code::Reference(r),
                 Trace::ActuallyLiteral => Code::Literal(technique.byte_at(scanner.pos)),
+                Trace::Candidate { index, run } => {
+                    Code::Reference(from_candidate(&scanner.candidates(CANDIDATES), index, run)?)
+                }
             };
 
             scanner.feedback(orig);
             ret.push(orig);
 
             match hint {
-                Trace::ActuallyLiteral | Trace::Actually(_) => {
+                Trace::ActuallyLiteral | Trace::Actually(_) | Trace::Candidate { .. } => {
                     // the guesser was wrong, and we moved in a way it doesn't understand; ignore it
                     break;
                 }
@@ -71,12 +121,50 @@ pub fn restore(trace: &[Trace], technique: &Technique) -> Vec<Code> {
         }
     }
 
-    ret
+    Ok(ret)
+}
+
+/// Name the `actual` reference by its place in the `candidates`, if it's there.
+fn candidate(candidates: &[Ref], actual: Ref) -> Trace {
+    match candidates.iter().position(|c| c.dist() == actual.dist()) {
+        Some(index) => Trace::Candidate {
+            index: u8(index).expect("at most CANDIDATES"),
+            run: if candidates[index].run() == actual.run() {
+                None
+            } else {
+                Some(actual.run())
+            },
+        },
+        None => Trace::Actually(actual),
+    }
+}
+
+/// The inverse of `candidate`.
+fn from_candidate(candidates: &[Ref], index: u8, run: Option<u16>) -> Result<Ref, Error> {
+    let candidate = candidates.get(usize::from(index)).ok_or_else(|| {
+        format_err!(
+            "candidate {} of only {} references",
+            index,
+            candidates.len()
+        )
+    })?;
+
+    Ok(match run {
+        None => *candidate,
+        Some(run) if run >= 3 && run < candidate.run() => Ref::new(candidate.dist(), run),
+        Some(run) => {
+            return Err(format_err!(
+                "can't cut {:?} short to a run of {}",
+                candidate,
+                run
+            ))
+        }
+    })
 }
 
 pub fn validate(codes: &[Code], technique: &Technique) -> Vec<Trace> {
     let trace = trace(codes, technique);
-    let restored = restore(&trace, technique);
+    let restored = restore(&trace, technique).expect("restoring what we just traced");
 
     assert_eq!(codes, restored.as_slice());
 
@@ -103,6 +191,72 @@ fn shared_prefix<'l, 't, T: 't + Eq, I: Iterator<Item = &'t T>>(
 mod tests {
     use std::iter;
 
+    use super::candidate;
+    use super::from_candidate;
+    use crate::technique::Config;
+    use crate::tracer;
+    use crate::Code;
+    use crate::Ref;
+    use crate::Trace;
+
+    #[test]
+    fn candidates() {
+        let candidates = [Ref::new(4, 10), Ref::new(9, 3), Ref::new(300, 258)];
+
+        for &(actual, expected) in &[
+            (
+                Ref::new(4, 10),
+                Trace::Candidate {
+                    index: 0,
+                    run: None,
+                },
+            ),
+            (
+                Ref::new(300, 5),
+                Trace::Candidate {
+                    index: 2,
+                    run: Some(5),
+                },
+            ),
+            (Ref::new(5, 3), Trace::Actually(Ref::new(5, 3))),
+        ] {
+            let trace = candidate(&candidates, actual);
+            assert_eq!(expected, trace);
+            if let Trace::Candidate { index, run } = trace {
+                assert_eq!(actual, from_candidate(&candidates, index, run).unwrap());
+            }
+        }
+
+        assert!(from_candidate(&candidates, 3, None).is_err());
+        assert!(from_candidate(&candidates, 1, Some(4)).is_err());
+        assert!(from_candidate(&candidates, 1, Some(3)).is_err());
+    }
+
+    #[test]
+    fn restore_checks_length() {
+        let config = Config::gzip(6);
+        let data = b"abcabcabc";
+        let codes = [
+            Code::Literal(b'a'),
+            Code::Literal(b'b'),
+            Code::Literal(b'c'),
+            Code::Reference(Ref::new(3, 6)),
+        ];
+
+        let mut traces = tracer::try_config(config, 0, &[], data, &codes);
+        assert_eq!(
+            &codes[..],
+            tracer::restore(config, 0, &[], data, &traces)
+                .unwrap()
+                .as_slice()
+        );
+
+        assert!(tracer::restore(config, 0, &[], data, &traces[..traces.len() - 1]).is_err());
+
+        traces.push(Trace::Correct);
+        assert!(tracer::restore(config, 0, &[], data, &traces).is_err());
+    }
+
     #[test]
     fn prefix() {
         use super::shared_prefix;
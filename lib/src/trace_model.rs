@@ -0,0 +1,178 @@
+//! The compact form of a trace, which range codes each entry with the guess it corrects as
+//! context. Misses are much more likely after long guessed references, where the compressor
+//! had the most choice, than after literals, where it had none.
+//!
+//! A reference which was missed is normally another of the references the compressor could
+//! see, a `Trace::Candidate`, so is coded by its position in the chain, and whether it ran as
+//! far as possible.
+
+use cast::u16;
+use cast::u8;
+use cast::usize;
+use failure::ensure;
+use failure::Error;
+
+use crate::range_coder;
+use crate::range_coder::Decoder;
+use crate::range_coder::Encoder;
+use crate::range_coder::Prob;
+use crate::technique::Technique;
+use crate::trace;
+use crate::Code;
+use crate::Ref;
+use crate::Trace;
+
+/// The index of a candidate, or this, for an absolute reference.
+const RANK_BITS: u8 = 8;
+const ESCAPE: usize = trace::CANDIDATES;
+
+const GUESS_KINDS: usize = 5;
+
+struct Model {
+    /// Whether the guess was correct, by the kind of guess, and whether the previous was.
+    correct: [[Prob; 2]; GUESS_KINDS],
+
+    /// Whether a wrong guess should have been a literal, by the kind of guess.
+    literal: [Prob; GUESS_KINDS],
+
+    /// Which candidate the actual reference was.
+    rank: Vec<Prob>,
+
+    /// Whether the reference was as long as it could be, by whether it was the nearest.
+    longest: [Prob; 2],
+
+    run: Vec<Prob>,
+}
+
+impl Model {
+    fn new() -> Model {
+        Model {
+            correct: Default::default(),
+            literal: Default::default(),
+            rank: range_coder::tree(RANK_BITS),
+            longest: Default::default(),
+            run: range_coder::tree(8),
+        }
+    }
+}
+
+/// Literals, then references by run length.
+fn kind(guess: Code) -> usize {
+    match guess {
+        Code::Literal(_) => 0,
+        Code::Reference(r) => match r.run() {
+            3 => 1,
+            4..=5 => 2,
+            6..=15 => 3,
+            _ => 4,
+        },
+    }
+}
+
+pub fn encode(technique: &Technique, traces: &[Trace]) -> Vec<u8> {
+    let mut model = Model::new();
+    let mut encoder = Encoder::new();
+    let mut previous_correct = true;
+    let mut traces = traces.iter();
+
+    trace::restore_with(technique, |_, guess| {
+        let hint = *traces.next().expect("not out of data");
+        let kind = kind(guess);
+        let correct = Trace::Correct == hint;
+
+        encoder.bit(&mut model.correct[kind][previous_correct as usize], correct);
+        previous_correct = correct;
+
+        if correct {
+            return Ok(hint);
+        }
+
+        if 0 != kind {
+            encoder.bit(&mut model.literal[kind], Trace::ActuallyLiteral == hint);
+        } else {
+            assert_ne!(
+                Trace::ActuallyLiteral,
+                hint,
+                "a literal can't be wrongly guessed as a literal"
+            );
+        }
+
+        match hint {
+            Trace::Candidate { index, run } => {
+                let index = usize::from(index);
+                assert_lt!(index, ESCAPE);
+                encoder.tree(&mut model.rank, index);
+                encoder.bit(&mut model.longest[(0 == index) as usize], run.is_none());
+                if let Some(run) = run {
+                    encoder.tree(&mut model.run, usize(run - 3));
+                }
+            }
+            Trace::Actually(actual) => {
+                encoder.tree(&mut model.rank, ESCAPE);
+                encoder.direct(u32::from(actual.dist() - 1), 15);
+                encoder.tree(&mut model.run, usize(actual.run() - 3));
+            }
+            Trace::ActuallyLiteral => {}
+            Trace::Correct => unreachable!(),
+        }
+
+        Ok(hint)
+    })
+    .expect("encoding can't fail");
+
+    encoder.finish()
+}
+
+pub fn decode(technique: &Technique, bytes: &[u8]) -> Result<Vec<Trace>, Error> {
+    let mut model = Model::new();
+    let mut decoder = Decoder::new(bytes);
+    let mut previous_correct = true;
+    let mut ret = Vec::new();
+
+    trace::restore_with(technique, |_, guess| {
+        let hint = decode_one(&mut model, &mut decoder, guess, previous_correct)?;
+        previous_correct = Trace::Correct == hint;
+        ret.push(hint);
+        Ok(hint)
+    })?;
+
+    ensure!(!decoder.overran(), "trace ended early");
+
+    Ok(ret)
+}
+
+fn decode_one(
+    model: &mut Model,
+    decoder: &mut Decoder,
+    guess: Code,
+    previous_correct: bool,
+) -> Result<Trace, Error> {
+    let kind = kind(guess);
+
+    if decoder.bit(&mut model.correct[kind][previous_correct as usize]) {
+        return Ok(Trace::Correct);
+    }
+
+    if 0 != kind && decoder.bit(&mut model.literal[kind]) {
+        return Ok(Trace::ActuallyLiteral);
+    }
+
+    let index = decoder.tree(&mut model.rank);
+    if ESCAPE == index {
+        let dist = u16(decoder.direct(15) + 1)?;
+        let run = u16(decoder.tree(&mut model.run) + 3)?;
+        ensure!(run <= 258, "invalid run length: {}", run);
+        return Ok(Trace::Actually(Ref::new(dist, run)));
+    }
+
+    let run = if decoder.bit(&mut model.longest[(0 == index) as usize]) {
+        None
+    } else {
+        Some(u16(decoder.tree(&mut model.run) + 3)?)
+    };
+
+    Ok(Trace::Candidate {
+        index: u8(index)?,
+        run,
+    })
+}
@@ -1,26 +1,117 @@
 use std::u16;
 
+use failure::Error;
+
 use crate::all_refs::AllRefs;
 use crate::serialise_trace;
 use crate::technique::Config;
 use crate::technique::Technique;
 use crate::trace;
+use crate::trace_model;
 use crate::Code;
 use crate::Trace;
 
-pub fn try_gzip(level: u8, preroll: &[u8], data: &[u8], codes: &[Code]) -> Vec<Trace> {
-    r#try(Config::gzip(level), preroll, data, codes)
+pub fn try_gzip(
+    level: u8,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    codes: &[Code],
+) -> Vec<Trace> {
+    try_config(Config::gzip(level), preroll_start, preroll, data, codes)
 }
 
-fn r#try(config: Config, preroll: &[u8], data: &[u8], codes: &[Code]) -> Vec<Trace> {
-    let limit = config.wams.insert_only_below_length.unwrap_or(u16::MAX);
-    let all_refs = AllRefs::with_sixteen(preroll, data, limit);
-
-    if config.first_byte_bug {
-        // TODO: ???
-    }
+/// Trace the `codes`, which decompress to `data`, after the `preroll`, which starts
+/// `preroll_start` bytes into the stream.
+pub fn try_config(
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    codes: &[Code],
+) -> Vec<Trace> {
+    let all_refs = all_refs(&config, preroll_start, preroll, data);
 
     let traces = trace::validate(codes, &Technique::new(config, &all_refs));
     serialise_trace::verify(&traces);
     traces
 }
+
+/// As `try_config`, but without checking the trace can be restored, and giving up, returning
+/// `None`, as soon as it's clear the serialised trace will be longer than `limit` bytes.
+pub fn try_config_within(
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    codes: &[Code],
+    limit: usize,
+) -> Option<Vec<Trace>> {
+    let all_refs = all_refs(&config, preroll_start, preroll, data);
+
+    let mut size = serialise_trace::Size::default();
+    trace::trace_while(codes, &Technique::new(config, &all_refs), |t| {
+        size.push(t);
+        size.bytes() <= limit
+    })
+}
+
+/// The inverse of `try_config`: recover the `codes` from the `trace`, given the same data.
+pub fn restore(
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    traces: &[Trace],
+) -> Result<Vec<Code>, Error> {
+    let all_refs = all_refs(&config, preroll_start, preroll, data);
+
+    trace::restore(traces, &Technique::new(config, &all_refs))
+}
+
+/// The compact form of the `traces`, which can only be read back with the same data.
+pub fn encode(
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    traces: &[Trace],
+) -> Vec<u8> {
+    let all_refs = all_refs(&config, preroll_start, preroll, data);
+
+    trace_model::encode(&Technique::new(config, &all_refs), traces)
+}
+
+/// The inverse of `encode`.
+pub fn decode(
+    config: Config,
+    preroll_start: usize,
+    preroll: &[u8],
+    data: &[u8],
+    bytes: &[u8],
+) -> Result<Vec<Trace>, Error> {
+    let all_refs = all_refs(&config, preroll_start, preroll, data);
+
+    trace_model::decode(&Technique::new(config, &all_refs), bytes)
+}
+
+fn all_refs<'p, 'd>(
+    config: &Config,
+    preroll_start: usize,
+    preroll: &'p [u8],
+    data: &'d [u8],
+) -> AllRefs<'p, 'd> {
+    let mut all_refs = AllRefs::with_hash(
+        preroll,
+        data,
+        u16::MAX,
+        config.hash_bits,
+        config.max_distance,
+    );
+
+    if config.first_byte_bug {
+        all_refs.apply_first_byte_bug_rule(preroll_start);
+    }
+
+    all_refs
+}
@@ -0,0 +1,536 @@
+//! Rebuild the header of a dynamic huffman block from the codes in it, in the same way as
+//! gzip 1.6 / zlib's `trees.c`, so the header only needs storing if the prediction was wrong.
+//!
+//! Zopfli builds and writes its trees its own way; see `zopfli::trees`.
+
+use cast::u16;
+use cast::usize;
+
+use crate::bit::BitVec;
+use crate::code_tree::CodeTree;
+use crate::huffman;
+use crate::serialise::Lengths;
+use crate::split::Splitter;
+use crate::technique::Config;
+use crate::zopfli;
+use crate::Code;
+
+const LITERAL_CODES: usize = 286;
+const DISTANCE_CODES: usize = 30;
+const BIT_LENGTH_CODES: usize = 19;
+
+const END_OF_BLOCK: usize = 256;
+
+const MAX_BITS: u8 = 15;
+const MAX_BIT_LENGTH_BITS: u8 = 7;
+
+/// zlib shares one heap between all the trees, so its size leaks into the result
+const HEAP_SIZE: usize = 2 * LITERAL_CODES + 1;
+
+/// repeat the previous length 3-6 times (2 extra bits)
+const REP_3_6: u8 = 16;
+
+/// repeat a zero length 3-10 times (3 extra bits)
+const REPZ_3_10: u8 = 17;
+
+/// repeat a zero length 11-138 times (7 extra bits)
+const REPZ_11_138: u8 = 18;
+
+/// The order the bit length code lengths are stored in, to make trimming the end likely.
+const BIT_LENGTH_ORDER: [usize; BIT_LENGTH_CODES] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Trees {
+    /// The header is exactly what gzip/zlib would have written for these codes.
+    Predicted,
+
+    /// The header matched the prediction for `shared` bits, then went its own way.
+    Diverged { shared: usize, rest: BitVec },
+}
+
+/// Work out how to recreate `actual` from `codes`.
+pub fn hint(codes: &[Code], config: &Config, actual: &BitVec) -> Trees {
+    let predicted = predict_for(codes, config);
+    if predicted == *actual {
+        return Trees::Predicted;
+    }
+
+    let shared = predicted
+        .iter()
+        .zip(actual.iter())
+        .take_while(|&(predicted, actual)| predicted == actual)
+        .count();
+
+    let mut rest = BitVec::new();
+    for bit in actual.iter().skip(shared) {
+        rest.push(bit);
+    }
+
+    Trees::Diverged { shared, rest }
+}
+
+/// The inverse of `hint`.
+pub fn restore(codes: &[Code], config: &Config, hint: &Trees) -> BitVec {
+    let predicted = predict_for(codes, config);
+    match *hint {
+        Trees::Predicted => predicted,
+        Trees::Diverged { shared, ref rest } => {
+            let mut ret = BitVec::new();
+            for bit in predicted.iter().take(shared).chain(rest.iter()) {
+                ret.push(bit);
+            }
+            ret
+        }
+    }
+}
+
+/// The header the compressor the `config` describes would write.
+fn predict_for(codes: &[Code], config: &Config) -> BitVec {
+    match config.splitter {
+        Splitter::Zopfli { .. } => zopfli::trees(codes),
+        _ => predict(codes),
+    }
+}
+
+/// The bits gzip/zlib's `send_all_trees` would emit for a block containing these codes.
+pub fn predict(codes: &[Code]) -> BitVec {
+    let (literal_freqs, distance_freqs) = frequencies(codes);
+
+    let (literal_lens, literal_max_code) = build_tree(&literal_freqs, MAX_BITS);
+    let (distance_lens, distance_max_code) = build_tree(&distance_freqs, MAX_BITS);
+
+    let literal_rle = run_length_encode(&literal_lens[..=literal_max_code]);
+    let distance_rle = run_length_encode(&distance_lens[..=distance_max_code]);
+
+    let mut bit_length_freqs = [0u32; BIT_LENGTH_CODES];
+    for &(sym, _) in literal_rle.iter().chain(distance_rle.iter()) {
+        bit_length_freqs[usize(sym)] += 1;
+    }
+
+    let (bit_length_lens, _) = build_tree(&bit_length_freqs, MAX_BIT_LENGTH_BITS);
+
+    // trim the trailing unused bit length codes, but we must send at least four
+    let bit_length_codes = 1
+        + (3..BIT_LENGTH_CODES)
+            .rev()
+            .find(|&rank| 0 != bit_length_lens[BIT_LENGTH_ORDER[rank]])
+            .unwrap_or(3);
+
+    let bit_length_tree = CodeTree::new(&bit_length_lens)
+        .expect("generated trees are complete")
+        .invert();
+
+    let mut ret = BitVec::new();
+    push_bits(&mut ret, 5, u16(literal_max_code + 1 - 257).unwrap());
+    push_bits(&mut ret, 5, u16(distance_max_code + 1 - 1).unwrap());
+    push_bits(&mut ret, 4, u16(bit_length_codes - 4).unwrap());
+
+    for &sym in &BIT_LENGTH_ORDER[..bit_length_codes] {
+        push_bits(&mut ret, 3, u16::from(bit_length_lens[sym]));
+    }
+
+    for &(sym, extra) in literal_rle.iter().chain(distance_rle.iter()) {
+        let code = bit_length_tree[usize(sym)]
+            .as_ref()
+            .expect("only used symbols are emitted");
+        for bit in code.iter() {
+            ret.push(bit);
+        }
+
+        if let Some((bits, val)) = extra {
+            push_bits(&mut ret, bits, val);
+        }
+    }
+
+    ret
+}
+
+/// The code lengths of the dynamic trees gzip/zlib would use for a block containing these codes.
+pub fn lengths(codes: &[Code]) -> Lengths {
+    let (literal_freqs, distance_freqs) = frequencies(codes);
+
+    let (literal_lens, _) = build_tree(&literal_freqs, MAX_BITS);
+    let (distance_lens, _) = build_tree(&distance_freqs, MAX_BITS);
+
+    Lengths::new(
+        &CodeTree::new(&literal_lens).expect("generated trees are complete"),
+        &CodeTree::new(&distance_lens).expect("generated trees are complete"),
+    )
+}
+
+fn push_bits(into: &mut BitVec, bits: u8, val: u16) {
+    for i in 0..bits {
+        into.push(0 != (val & (1 << i)));
+    }
+}
+
+fn frequencies(codes: &[Code]) -> (Vec<u32>, Vec<u32>) {
+    let mut literals = vec![0u32; LITERAL_CODES];
+    let mut distances = vec![0u32; DISTANCE_CODES];
+
+    for code in codes {
+        match *code {
+            Code::Literal(byte) => literals[usize::from(byte)] += 1,
+            Code::Reference(r) => {
+                literals[usize(huffman::encode_run_length(r.run()))] += 1;
+                let (sym, _, _) = huffman::encode_distance(r.wide_dist()).unwrap();
+                distances[usize::from(sym)] += 1;
+            }
+        }
+    }
+
+    literals[END_OF_BLOCK] = 1;
+
+    (literals, distances)
+}
+
+/// `scan_tree` and `send_tree`: run length encode the code lengths using
+/// the bit length alphabet, giving `(symbol, Some((extra bit count, extra bits)))`.
+fn run_length_encode(lens: &[u8]) -> Vec<(u8, Option<(u8, u16)>)> {
+    let mut ret = Vec::new();
+
+    let mut prev_len = None;
+    let mut count = 0u16;
+
+    let (mut max_count, mut min_count) = if 0 == lens[0] { (138, 3) } else { (7, 4) };
+
+    for n in 0..lens.len() {
+        let cur_len = lens[n];
+        // zlib writes an invalid length after the end as a guard
+        let next_len = lens.get(n + 1).cloned();
+
+        count += 1;
+        if count < max_count && Some(cur_len) == next_len {
+            continue;
+        } else if count < min_count {
+            for _ in 0..count {
+                ret.push((cur_len, None));
+            }
+        } else if 0 != cur_len {
+            if Some(cur_len) != prev_len {
+                ret.push((cur_len, None));
+                count -= 1;
+            }
+            ret.push((REP_3_6, Some((2, count - 3))));
+        } else if count <= 10 {
+            ret.push((REPZ_3_10, Some((3, count - 3))));
+        } else {
+            ret.push((REPZ_11_138, Some((7, count - 11))));
+        }
+
+        count = 0;
+        prev_len = Some(cur_len);
+
+        if Some(0) == next_len {
+            max_count = 138;
+            min_count = 3;
+        } else if Some(cur_len) == next_len {
+            max_count = 6;
+            min_count = 3;
+        } else {
+            max_count = 7;
+            min_count = 4;
+        }
+    }
+
+    ret
+}
+
+/// zlib's `build_tree` and `gen_bitlen`: the lengths of a length-limited huffman code
+/// for these frequencies, and the highest symbol with a non-zero length.
+fn build_tree(freqs: &[u32], max_length: u8) -> (Vec<u8>, usize) {
+    let elems = freqs.len();
+    let mut tree = Tree {
+        freq: freqs.to_vec(),
+        depth: vec![0; elems],
+        dad: vec![0; elems],
+        len: vec![0; elems],
+        heap: vec![0; HEAP_SIZE],
+        heap_len: 0,
+    };
+
+    let mut max_code: isize = -1;
+
+    for n in 0..elems {
+        if 0 != tree.freq[n] {
+            tree.heap_len += 1;
+            tree.heap[tree.heap_len] = n;
+            max_code = n as isize;
+        }
+    }
+
+    // The pkzip format requires that at least one distance code exists,
+    // and that at least one bit should be sent even if there is only one
+    // possible code. So to avoid special checks later on we force at least
+    // two codes of non zero frequency.
+    while tree.heap_len < 2 {
+        let node = if max_code < 2 {
+            max_code += 1;
+            max_code as usize
+        } else {
+            0
+        };
+
+        tree.heap_len += 1;
+        tree.heap[tree.heap_len] = node;
+        tree.freq[node] = 1;
+    }
+
+    let max_code = max_code as usize;
+
+    for n in (1..=tree.heap_len / 2).rev() {
+        tree.down_heap(n);
+    }
+
+    // Construct the Huffman tree by repeatedly combining the least two frequent nodes.
+    let mut heap_max = HEAP_SIZE;
+    let mut node = elems;
+    loop {
+        let n = tree.heap[1];
+        tree.heap[1] = tree.heap[tree.heap_len];
+        tree.heap_len -= 1;
+        tree.down_heap(1);
+
+        let m = tree.heap[1];
+
+        heap_max -= 1;
+        tree.heap[heap_max] = n;
+        heap_max -= 1;
+        tree.heap[heap_max] = m;
+
+        let freq = tree.freq[n] + tree.freq[m];
+        let depth = tree.depth[n].max(tree.depth[m]) + 1;
+        tree.freq.push(freq);
+        tree.depth.push(depth);
+        tree.dad.push(0);
+        tree.len.push(0);
+        tree.dad[n] = node;
+        tree.dad[m] = node;
+
+        tree.heap[1] = node;
+        node += 1;
+        tree.down_heap(1);
+
+        if tree.heap_len < 2 {
+            break;
+        }
+    }
+
+    heap_max -= 1;
+    tree.heap[heap_max] = tree.heap[1];
+
+    tree.gen_bitlen(heap_max, max_code, max_length);
+
+    tree.len.truncate(elems);
+    (tree.len, max_code)
+}
+
+struct Tree {
+    freq: Vec<u32>,
+    depth: Vec<u8>,
+    dad: Vec<usize>,
+    len: Vec<u8>,
+
+    /// one-indexed, as in zlib
+    heap: Vec<usize>,
+    heap_len: usize,
+}
+
+impl Tree {
+    fn smaller(&self, n: usize, m: usize) -> bool {
+        self.freq[n] < self.freq[m]
+            || (self.freq[n] == self.freq[m] && self.depth[n] <= self.depth[m])
+    }
+
+    /// Restore the heap property by moving down the tree starting at node k,
+    /// exchanging a node with the smallest of its two sons if necessary.
+    fn down_heap(&mut self, mut k: usize) {
+        let v = self.heap[k];
+        let mut j = k << 1;
+        while j <= self.heap_len {
+            if j < self.heap_len && self.smaller(self.heap[j + 1], self.heap[j]) {
+                j += 1;
+            }
+
+            if self.smaller(v, self.heap[j]) {
+                break;
+            }
+
+            self.heap[k] = self.heap[j];
+            k = j;
+            j <<= 1;
+        }
+        self.heap[k] = v;
+    }
+
+    /// Compute the optimal bit lengths for a tree, then fix them up to fit in `max_length`,
+    /// in exactly the same (non-optimal) way as zlib.
+    fn gen_bitlen(&mut self, heap_max: usize, max_code: usize, max_length: u8) {
+        let mut bl_count = [0u32; MAX_BITS as usize + 1];
+        let mut overflow = 0i32;
+
+        self.len[self.heap[heap_max]] = 0;
+
+        for h in heap_max + 1..HEAP_SIZE {
+            let n = self.heap[h];
+            let mut bits = self.len[self.dad[n]] + 1;
+            if bits > max_length {
+                bits = max_length;
+                overflow += 1;
+            }
+
+            self.len[n] = bits;
+
+            if n > max_code {
+                // not a leaf node
+                continue;
+            }
+
+            bl_count[usize::from(bits)] += 1;
+        }
+
+        if 0 == overflow {
+            return;
+        }
+
+        // Find the first bit length which could increase:
+        loop {
+            let mut bits = usize::from(max_length) - 1;
+            while 0 == bl_count[bits] {
+                bits -= 1;
+            }
+
+            // move one leaf down the tree, and one overflow item as its brother
+            bl_count[bits] -= 1;
+            bl_count[bits + 1] += 2;
+            bl_count[usize::from(max_length)] -= 1;
+
+            // The brother of the overflow item also moves one step up,
+            // but this does not affect bl_count[max_length]
+            overflow -= 2;
+            if overflow <= 0 {
+                break;
+            }
+        }
+
+        // Now recompute all bit lengths, scanning in increasing frequency.
+        let mut h = HEAP_SIZE;
+        for bits in (1..=max_length).rev() {
+            let mut n = bl_count[usize::from(bits)];
+            while 0 != n {
+                h -= 1;
+                let m = self.heap[h];
+                if m > max_code {
+                    continue;
+                }
+
+                self.len[m] = bits;
+                n -= 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::gzip;
+    use crate::parse;
+    use crate::Block;
+
+    fn dynamic_blocks(orig: &[u8]) -> Vec<(BitVec, Vec<Code>)> {
+        let mut raw = Cursor::new(orig);
+        gzip::GzipHeader::read(&mut raw).unwrap();
+        parse::parse_deflate(&mut raw)
+            .filter_map(|block| match block.unwrap() {
+                Block::DynamicHuffman { trees, codes } => Some((trees, codes)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn assert_predicted(orig: &[u8]) {
+        let blocks = dynamic_blocks(orig);
+        assert!(!blocks.is_empty());
+
+        for (trees, codes) in blocks {
+            assert_eq!(trees, predict(&codes));
+            assert_eq!(Trees::Predicted, hint(&codes, &Config::gzip(6), &trees));
+        }
+    }
+
+    #[test]
+    fn seq_20() {
+        assert_predicted(include_bytes!("../tests/data/seq-20.gz"));
+    }
+
+    #[test]
+    fn libcgi() {
+        assert_predicted(include_bytes!(
+            "../tests/data/libcgi-untaint-email-perl_0.03.orig.tar.gz"
+        ));
+    }
+
+    #[test]
+    fn librole() {
+        assert_predicted(include_bytes!(
+            "../tests/data/librole-basic-perl_0.13-1.debian.tar.gz"
+        ));
+    }
+
+    #[test]
+    fn blockandabit() {
+        assert_predicted(include_bytes!("../tests/data/blockandabit-sixteen-1.gz"));
+    }
+
+    #[test]
+    fn diverged() {
+        let (trees, codes) = dynamic_blocks(include_bytes!("../tests/data/seq-20.gz")).remove(0);
+
+        let mut altered = BitVec::new();
+        for (pos, bit) in trees.iter().enumerate() {
+            altered.push(if 20 == pos { !bit } else { bit });
+        }
+
+        let hint = hint(&codes, &Config::gzip(6), &altered);
+        match hint {
+            Trees::Diverged { shared, ref rest } => {
+                assert_eq!(20, shared);
+                assert_eq!(trees.len() - 20, rest.len());
+            }
+            Trees::Predicted => panic!("can't have predicted a change"),
+        }
+
+        assert_eq!(altered, restore(&codes, &Config::gzip(6), &hint));
+    }
+
+    #[test]
+    fn overflowing_lengths() {
+        // fibonacci frequencies give the deepest possible tree, which must be limited
+        let mut freqs = vec![0u32; LITERAL_CODES];
+        let (mut a, mut b) = (1u32, 1u32);
+        for freq in freqs.iter_mut().take(25) {
+            *freq = a;
+            let next = a + b;
+            a = b;
+            b = next;
+        }
+
+        let (lens, max_code) = build_tree(&freqs, MAX_BITS);
+        assert_eq!(24, max_code);
+        assert!(lens.iter().all(|&len| len <= MAX_BITS));
+
+        let kraft: u32 = lens
+            .iter()
+            .filter(|&&len| 0 != len)
+            .map(|&len| 1 << (MAX_BITS - len))
+            .sum();
+        assert_eq!(1 << MAX_BITS, kraft);
+
+        CodeTree::new(&lens).unwrap();
+    }
+}
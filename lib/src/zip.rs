@@ -0,0 +1,691 @@
+//! `.zip` files (and `.jar`, `.docx`, ...) contain a `DEFLATE` stream per member,
+//! surrounded by headers. This splits an archive into those parts, keeping everything
+//! which isn't compressed data, so it can be written back out exactly.
+//!
+//! Large members written by Windows' own zip support may instead be Deflate64, which we
+//! can take apart and put back together, but not trace.
+//!
+//! Zip64 and multi-disk archives are not supported.
+
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::LittleEndian as LE;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use cast::u16;
+use cast::u64;
+use cast::usize;
+use failure::bail;
+use failure::ensure;
+use failure::format_err;
+use failure::Error;
+
+use crate::bit::BitWriter;
+use crate::huffman::Format;
+use crate::parse;
+use crate::serialise::compressed_block;
+use crate::serialise::compressed_block64;
+use crate::serialise::decompressed_block;
+use crate::Block;
+use crate::CircularBuffer;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+
+const END_OF_CENTRAL_DIRECTORY_LEN: usize = 22;
+
+pub const METHOD_STORED: u16 = 0;
+pub const METHOD_DEFLATE: u16 = 8;
+pub const METHOD_DEFLATE64: u16 = 9;
+
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+const FLAG_DATA_DESCRIPTOR: u16 = 1 << 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Archive {
+    /// Anything before the first member, e.g. a self-extractor stub.
+    pub prefix: Vec<u8>,
+
+    /// In the order they appear in the file, which needn't be the central directory's order.
+    pub entries: Vec<Entry>,
+
+    pub central_directory: Vec<CentralHeader>,
+
+    /// Anything between the central directory and the end record.
+    pub between: Vec<u8>,
+
+    pub end: EndOfCentralDirectory,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub header: LocalHeader,
+    pub contents: Contents,
+    pub descriptor: Option<DataDescriptor>,
+
+    /// Anything between the end of this entry and the start of the next thing.
+    pub padding: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Contents {
+    Deflate(Vec<Block>),
+
+    /// From `parse_deflate64`, with references up to 64KiB back.
+    Deflate64(Vec<Block>),
+
+    /// Stored, encrypted, or in some compression method we don't understand.
+    Raw(Vec<u8>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LocalHeader {
+    pub version_needed: u16,
+    pub flags: u16,
+    pub method: u16,
+    pub mtime: u16,
+    pub mdate: u16,
+    pub crc32: u32,
+    pub compressed_size: u32,
+    pub uncompressed_size: u32,
+    pub name: Vec<u8>,
+    pub extra: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DataDescriptor {
+    /// The signature is optional, but almost always present.
+    pub signature: bool,
+    pub crc32: u32,
+    pub compressed_size: u32,
+    pub uncompressed_size: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CentralHeader {
+    pub version_made_by: u16,
+    pub version_needed: u16,
+    pub flags: u16,
+    pub method: u16,
+    pub mtime: u16,
+    pub mdate: u16,
+    pub crc32: u32,
+    pub compressed_size: u32,
+    pub uncompressed_size: u32,
+    pub disk_start: u16,
+    pub internal_attributes: u16,
+    pub external_attributes: u32,
+    pub local_header_offset: u32,
+    pub name: Vec<u8>,
+    pub extra: Vec<u8>,
+    pub comment: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EndOfCentralDirectory {
+    pub disk: u16,
+    pub central_directory_disk: u16,
+    pub entries_on_disk: u16,
+    pub entries: u16,
+    pub central_directory_size: u32,
+    pub central_directory_offset: u32,
+    pub comment: Vec<u8>,
+}
+
+impl Entry {
+    /// The uncompressed contents of this entry, if we understand its compression method.
+    pub fn decompressed(&self) -> Result<Vec<u8>, Error> {
+        match self.contents {
+            Contents::Deflate(ref blocks) | Contents::Deflate64(ref blocks) => {
+                let mut data = Vec::new();
+                let mut dictionary = CircularBuffer::with_capacity(64 * 1024);
+                for block in blocks {
+                    decompressed_block(&mut data, &mut dictionary, block)?;
+                }
+                Ok(data)
+            }
+            Contents::Raw(ref data) if METHOD_STORED == self.header.method => Ok(data.clone()),
+            Contents::Raw(_) => bail!("unsupported compression method: {}", self.header.method),
+        }
+    }
+}
+
+pub fn read(file: &[u8]) -> Result<Archive, Error> {
+    let end_pos = find_end_of_central_directory(file)?;
+    let end = EndOfCentralDirectory::read(&mut Cursor::new(&file[end_pos..]))?;
+
+    ensure!(
+        0 == end.disk && 0 == end.central_directory_disk && end.entries == end.entries_on_disk,
+        "multi-disk archives are not supported"
+    );
+    ensure!(
+        0xffff != end.entries && 0xffff_ffff != end.central_directory_offset,
+        "zip64 archives are not supported"
+    );
+
+    let central_directory_start = usize(end.central_directory_offset);
+    ensure!(
+        central_directory_start <= end_pos,
+        "central directory starts after it ends"
+    );
+
+    let mut reader = Cursor::new(&file[central_directory_start..end_pos]);
+    let mut central_directory = Vec::with_capacity(usize(end.entries));
+    for _ in 0..end.entries {
+        central_directory.push(CentralHeader::read(&mut reader)?);
+    }
+    let central_directory_end = central_directory_start + usize(reader.position());
+    let between = file[central_directory_end..end_pos].to_vec();
+
+    // Local headers are found through the central directory, as, with a data descriptor,
+    // the local header might not know how long the entry is.
+    let mut by_offset: Vec<&CentralHeader> = central_directory.iter().collect();
+    by_offset.sort_by_key(|header| header.local_header_offset);
+    by_offset.dedup_by_key(|header| header.local_header_offset);
+
+    let prefix_end = by_offset
+        .first()
+        .map(|header| usize(header.local_header_offset))
+        .unwrap_or(central_directory_start);
+    ensure!(
+        prefix_end <= central_directory_start,
+        "entry starts inside the central directory"
+    );
+    let prefix = file[..prefix_end].to_vec();
+
+    let mut entries = Vec::with_capacity(by_offset.len());
+    for (id, central) in by_offset.iter().enumerate() {
+        let start = usize(central.local_header_offset);
+        let next = by_offset
+            .get(id + 1)
+            .map(|next| usize(next.local_header_offset))
+            .unwrap_or(central_directory_start);
+
+        ensure!(start <= next, "entries overlap");
+
+        entries.push(read_entry(&file[start..next], central)?);
+    }
+
+    Ok(Archive {
+        prefix,
+        entries,
+        central_directory,
+        between,
+        end,
+    })
+}
+
+pub fn write<W: Write>(archive: &Archive, mut into: W) -> Result<(), Error> {
+    into.write_all(&archive.prefix)?;
+
+    for entry in &archive.entries {
+        entry.header.write(&mut into)?;
+
+        match entry.contents {
+            Contents::Deflate(ref blocks) => {
+                let mut writer = BitWriter::new(&mut into);
+                for (id, block) in blocks.iter().enumerate() {
+                    writer.write_bit(id + 1 == blocks.len())?;
+                    compressed_block(&mut writer, block)?;
+                }
+                writer.align()?;
+            }
+            Contents::Deflate64(ref blocks) => {
+                let mut writer = BitWriter::new(&mut into);
+                for (id, block) in blocks.iter().enumerate() {
+                    writer.write_bit(id + 1 == blocks.len())?;
+                    compressed_block64(&mut writer, block)?;
+                }
+                writer.align()?;
+            }
+            Contents::Raw(ref data) => into.write_all(data)?,
+        }
+
+        if let Some(ref descriptor) = entry.descriptor {
+            descriptor.write(&mut into)?;
+        }
+
+        into.write_all(&entry.padding)?;
+    }
+
+    for header in &archive.central_directory {
+        header.write(&mut into)?;
+    }
+
+    into.write_all(&archive.between)?;
+
+    archive.end.write(&mut into)
+}
+
+/// Read a local header, the data, and the data descriptor, from a slice ending at the next entry.
+fn read_entry(from: &[u8], central: &CentralHeader) -> Result<Entry, Error> {
+    let mut reader = Cursor::new(from);
+    let header = LocalHeader::read(&mut reader)?;
+
+    let data_start = usize(reader.position());
+    let data_end = data_start + usize(central.compressed_size);
+    ensure!(data_end <= from.len(), "entry data overruns the next entry");
+    let raw = &from[data_start..data_end];
+
+    let encrypted = 0 != (header.flags & FLAG_ENCRYPTED);
+    let contents = match header.method {
+        METHOD_DEFLATE if !encrypted => read_deflate(raw, Format::Deflate)
+            .map(Contents::Deflate)
+            .unwrap_or_else(|| Contents::Raw(raw.to_vec())),
+        METHOD_DEFLATE64 if !encrypted => read_deflate(raw, Format::Deflate64)
+            .map(Contents::Deflate64)
+            .unwrap_or_else(|| Contents::Raw(raw.to_vec())),
+        _ => Contents::Raw(raw.to_vec()),
+    };
+
+    let mut reader = Cursor::new(&from[data_end..]);
+    let descriptor = if 0 != (header.flags & FLAG_DATA_DESCRIPTOR) {
+        Some(DataDescriptor::read(&mut reader)?)
+    } else {
+        None
+    };
+
+    let padding = from[data_end + usize(reader.position())..].to_vec();
+
+    Ok(Entry {
+        header,
+        contents,
+        descriptor,
+        padding,
+    })
+}
+
+/// The blocks, if the data is exactly one valid stream.
+fn read_deflate(raw: &[u8], format: Format) -> Option<Vec<Block>> {
+    let mut reader = Cursor::new(raw);
+    let blocks = parse::parse(&mut reader, format)
+        .collect::<Result<Vec<Block>, Error>>()
+        .ok()?;
+
+    if u64(raw.len()) != reader.position() {
+        return None;
+    }
+
+    Some(blocks)
+}
+
+fn find_end_of_central_directory(file: &[u8]) -> Result<usize, Error> {
+    ensure!(
+        file.len() >= END_OF_CENTRAL_DIRECTORY_LEN,
+        "too short to be a zip file"
+    );
+
+    // The record is at the end of the file, followed only by a comment of up to 64kB.
+    let earliest = file
+        .len()
+        .saturating_sub(END_OF_CENTRAL_DIRECTORY_LEN + usize::from(u16::MAX));
+
+    for pos in (earliest..=file.len() - END_OF_CENTRAL_DIRECTORY_LEN).rev() {
+        let mut reader = Cursor::new(&file[pos..]);
+        if END_OF_CENTRAL_DIRECTORY_SIGNATURE != reader.read_u32::<LE>()? {
+            continue;
+        }
+
+        reader.set_position(20);
+        let comment_len = usize::from(reader.read_u16::<LE>()?);
+        if pos + END_OF_CENTRAL_DIRECTORY_LEN + comment_len == file.len() {
+            return Ok(pos);
+        }
+    }
+
+    Err(format_err!("no end of central directory record"))
+}
+
+fn read_signature<R: Read>(mut from: R, expected: u32) -> Result<(), Error> {
+    let signature = from.read_u32::<LE>()?;
+    ensure!(
+        expected == signature,
+        "invalid signature: expected {:08x}, found {:08x}",
+        expected,
+        signature
+    );
+    Ok(())
+}
+
+fn read_vec<R: Read>(mut from: R, len: u16) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; usize::from(len)];
+    from.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn len_u16(data: &[u8]) -> Result<u16, Error> {
+    Ok(u16(data.len())?)
+}
+
+impl LocalHeader {
+    fn read<R: Read>(mut from: R) -> Result<Self, Error> {
+        read_signature(&mut from, LOCAL_HEADER_SIGNATURE)?;
+        let version_needed = from.read_u16::<LE>()?;
+        let flags = from.read_u16::<LE>()?;
+        let method = from.read_u16::<LE>()?;
+        let mtime = from.read_u16::<LE>()?;
+        let mdate = from.read_u16::<LE>()?;
+        let crc32 = from.read_u32::<LE>()?;
+        let compressed_size = from.read_u32::<LE>()?;
+        let uncompressed_size = from.read_u32::<LE>()?;
+        let name_len = from.read_u16::<LE>()?;
+        let extra_len = from.read_u16::<LE>()?;
+        let name = read_vec(&mut from, name_len)?;
+        let extra = read_vec(&mut from, extra_len)?;
+
+        Ok(LocalHeader {
+            version_needed,
+            flags,
+            method,
+            mtime,
+            mdate,
+            crc32,
+            compressed_size,
+            uncompressed_size,
+            name,
+            extra,
+        })
+    }
+
+    fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_u32::<LE>(LOCAL_HEADER_SIGNATURE)?;
+        into.write_u16::<LE>(self.version_needed)?;
+        into.write_u16::<LE>(self.flags)?;
+        into.write_u16::<LE>(self.method)?;
+        into.write_u16::<LE>(self.mtime)?;
+        into.write_u16::<LE>(self.mdate)?;
+        into.write_u32::<LE>(self.crc32)?;
+        into.write_u32::<LE>(self.compressed_size)?;
+        into.write_u32::<LE>(self.uncompressed_size)?;
+        into.write_u16::<LE>(len_u16(&self.name)?)?;
+        into.write_u16::<LE>(len_u16(&self.extra)?)?;
+        into.write_all(&self.name)?;
+        into.write_all(&self.extra)?;
+        Ok(())
+    }
+}
+
+impl DataDescriptor {
+    fn read<R: Read>(mut from: R) -> Result<Self, Error> {
+        let first = from.read_u32::<LE>()?;
+        let (signature, crc32) = if DATA_DESCRIPTOR_SIGNATURE == first {
+            (true, from.read_u32::<LE>()?)
+        } else {
+            (false, first)
+        };
+
+        Ok(DataDescriptor {
+            signature,
+            crc32,
+            compressed_size: from.read_u32::<LE>()?,
+            uncompressed_size: from.read_u32::<LE>()?,
+        })
+    }
+
+    fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        if self.signature {
+            into.write_u32::<LE>(DATA_DESCRIPTOR_SIGNATURE)?;
+        }
+        into.write_u32::<LE>(self.crc32)?;
+        into.write_u32::<LE>(self.compressed_size)?;
+        into.write_u32::<LE>(self.uncompressed_size)?;
+        Ok(())
+    }
+}
+
+impl CentralHeader {
+    fn read<R: Read>(mut from: R) -> Result<Self, Error> {
+        read_signature(&mut from, CENTRAL_HEADER_SIGNATURE)?;
+        let version_made_by = from.read_u16::<LE>()?;
+        let version_needed = from.read_u16::<LE>()?;
+        let flags = from.read_u16::<LE>()?;
+        let method = from.read_u16::<LE>()?;
+        let mtime = from.read_u16::<LE>()?;
+        let mdate = from.read_u16::<LE>()?;
+        let crc32 = from.read_u32::<LE>()?;
+        let compressed_size = from.read_u32::<LE>()?;
+        let uncompressed_size = from.read_u32::<LE>()?;
+        let name_len = from.read_u16::<LE>()?;
+        let extra_len = from.read_u16::<LE>()?;
+        let comment_len = from.read_u16::<LE>()?;
+        let disk_start = from.read_u16::<LE>()?;
+        let internal_attributes = from.read_u16::<LE>()?;
+        let external_attributes = from.read_u32::<LE>()?;
+        let local_header_offset = from.read_u32::<LE>()?;
+        let name = read_vec(&mut from, name_len)?;
+        let extra = read_vec(&mut from, extra_len)?;
+        let comment = read_vec(&mut from, comment_len)?;
+
+        Ok(CentralHeader {
+            version_made_by,
+            version_needed,
+            flags,
+            method,
+            mtime,
+            mdate,
+            crc32,
+            compressed_size,
+            uncompressed_size,
+            disk_start,
+            internal_attributes,
+            external_attributes,
+            local_header_offset,
+            name,
+            extra,
+            comment,
+        })
+    }
+
+    fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_u32::<LE>(CENTRAL_HEADER_SIGNATURE)?;
+        into.write_u16::<LE>(self.version_made_by)?;
+        into.write_u16::<LE>(self.version_needed)?;
+        into.write_u16::<LE>(self.flags)?;
+        into.write_u16::<LE>(self.method)?;
+        into.write_u16::<LE>(self.mtime)?;
+        into.write_u16::<LE>(self.mdate)?;
+        into.write_u32::<LE>(self.crc32)?;
+        into.write_u32::<LE>(self.compressed_size)?;
+        into.write_u32::<LE>(self.uncompressed_size)?;
+        into.write_u16::<LE>(len_u16(&self.name)?)?;
+        into.write_u16::<LE>(len_u16(&self.extra)?)?;
+        into.write_u16::<LE>(len_u16(&self.comment)?)?;
+        into.write_u16::<LE>(self.disk_start)?;
+        into.write_u16::<LE>(self.internal_attributes)?;
+        into.write_u32::<LE>(self.external_attributes)?;
+        into.write_u32::<LE>(self.local_header_offset)?;
+        into.write_all(&self.name)?;
+        into.write_all(&self.extra)?;
+        into.write_all(&self.comment)?;
+        Ok(())
+    }
+}
+
+impl EndOfCentralDirectory {
+    fn read<R: Read>(mut from: R) -> Result<Self, Error> {
+        read_signature(&mut from, END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+        let disk = from.read_u16::<LE>()?;
+        let central_directory_disk = from.read_u16::<LE>()?;
+        let entries_on_disk = from.read_u16::<LE>()?;
+        let entries = from.read_u16::<LE>()?;
+        let central_directory_size = from.read_u32::<LE>()?;
+        let central_directory_offset = from.read_u32::<LE>()?;
+        let comment_len = from.read_u16::<LE>()?;
+        let comment = read_vec(&mut from, comment_len)?;
+
+        Ok(EndOfCentralDirectory {
+            disk,
+            central_directory_disk,
+            entries_on_disk,
+            entries,
+            central_directory_size,
+            central_directory_offset,
+            comment,
+        })
+    }
+
+    fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_u32::<LE>(END_OF_CENTRAL_DIRECTORY_SIGNATURE)?;
+        into.write_u16::<LE>(self.disk)?;
+        into.write_u16::<LE>(self.central_directory_disk)?;
+        into.write_u16::<LE>(self.entries_on_disk)?;
+        into.write_u16::<LE>(self.entries)?;
+        into.write_u32::<LE>(self.central_directory_size)?;
+        into.write_u32::<LE>(self.central_directory_offset)?;
+        into.write_u16::<LE>(len_u16(&self.comment)?)?;
+        into.write_all(&self.comment)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crc::crc32;
+
+    use super::*;
+    use crate::Code;
+    use crate::Ref;
+
+    fn round_trip(orig: &[u8]) -> Archive {
+        let archive = read(orig).unwrap();
+        let mut rebuilt = Vec::with_capacity(orig.len());
+        write(&archive, &mut rebuilt).unwrap();
+        assert_eq!(orig, rebuilt.as_slice());
+        archive
+    }
+
+    #[test]
+    fn info_zip() {
+        let archive = round_trip(include_bytes!("../tests/data/info-zip.zip"));
+
+        assert_eq!(b"rezip test archive", archive.end.comment.as_slice());
+        assert_eq!(5, archive.entries.len());
+        assert_eq!(5, archive.central_directory.len());
+        assert!(archive.prefix.is_empty());
+
+        let names: Vec<&[u8]> = archive
+            .entries
+            .iter()
+            .map(|entry| entry.header.name.as_slice())
+            .collect();
+        assert_eq!(
+            vec![
+                &b"docs/"[..],
+                b"docs/tiny.txt",
+                b"docs/LICENSE",
+                b"docs/empty/",
+                b"docs/gzip.md"
+            ],
+            names
+        );
+
+        let tiny = &archive.entries[1];
+        assert_eq!(METHOD_STORED, tiny.header.method);
+        assert_eq!(b"tiny", tiny.decompressed().unwrap().as_slice());
+
+        let licence = &archive.entries[2];
+        assert_eq!(METHOD_DEFLATE, licence.header.method);
+        match licence.contents {
+            Contents::Deflate(_) => {}
+            _ => panic!("should have understood the compression"),
+        }
+
+        assert_eq!(
+            &include_bytes!("../../LICENSE")[..],
+            licence.decompressed().unwrap().as_slice()
+        );
+    }
+
+    #[test]
+    fn data_descriptor() {
+        let archive = round_trip(include_bytes!("../tests/data/info-zip-streamed.zip"));
+        assert_eq!(1, archive.entries.len());
+
+        let entry = &archive.entries[0];
+        assert_eq!(0, entry.header.compressed_size);
+        assert_eq!(
+            Some(DataDescriptor {
+                signature: true,
+                crc32: 0x9339_8ec6,
+                compressed_size: 2419,
+                uncompressed_size: 5514,
+            }),
+            entry.descriptor
+        );
+        assert_eq!(
+            &include_bytes!("../../gzip.md")[..],
+            entry.decompressed().unwrap().as_slice()
+        );
+    }
+
+    #[test]
+    fn deflate64() {
+        // hand-written fixed huffman blocks, reaching 50,000 bytes back with the distance
+        // codes `DEFLATE` reserves, and running 1,000 and 65,538 bytes with the last length
+        // code; checked against Info-ZIP's `unzip -t`
+        let orig = include_bytes!("../tests/data/deflate64.zip");
+        let archive = round_trip(orig);
+        assert_eq!(1, archive.entries.len());
+
+        let entry = &archive.entries[0];
+        assert_eq!(METHOD_DEFLATE64, entry.header.method);
+        let refs: Vec<Ref> = match entry.contents {
+            Contents::Deflate64(ref blocks) => blocks
+                .iter()
+                .flat_map(|block| match *block {
+                    Block::FixedHuffman(ref codes) => codes.clone(),
+                    _ => panic!("only fixed huffman blocks"),
+                })
+                .filter_map(|code| match code {
+                    Code::Reference(r) => Some(r),
+                    Code::Literal(_) => None,
+                })
+                .collect(),
+            _ => panic!("should have understood the compression"),
+        };
+        assert_eq!(
+            vec![(50_000, 1_000), (40_000, 258), (33_000, 65_538)],
+            refs[refs.len() - 3..]
+                .iter()
+                .map(|r| (r.wide_dist(), r.wide_run()))
+                .collect::<Vec<_>>()
+        );
+
+        let data = entry.decompressed().unwrap();
+        assert_eq!(118_452, data.len());
+        assert_eq!(entry.header.crc32, crc32::checksum_ieee(&data));
+        assert!(data.ends_with(b"end\n"));
+
+        // not valid classic `DEFLATE`
+        let start = usize(orig.len()) - usize(archive.end.central_directory_size) - 22;
+        assert!(read_deflate(&orig[..start], Format::Deflate).is_none());
+    }
+
+    #[test]
+    fn prefix_and_padding() {
+        let orig = include_bytes!("../tests/data/info-zip-streamed.zip");
+
+        // move everything along by a stub, and pad the only entry,
+        // adjusting the offsets to match, like a self-extractor would
+        let mut archive = read(orig).unwrap();
+        archive.prefix = b"#!/bin/sh\nexit 1\n".to_vec();
+        archive.entries[0].padding = vec![0; 7];
+        let moved = u32::from(u16(archive.prefix.len()).unwrap());
+        archive.central_directory[0].local_header_offset += moved;
+        archive.end.central_directory_offset += moved + 7;
+
+        let mut modified = Vec::new();
+        write(&archive, &mut modified).unwrap();
+
+        assert_eq!(archive, round_trip(&modified));
+    }
+
+    #[test]
+    fn not_a_zip() {
+        assert!(read(include_bytes!("../tests/data/lol.gz")).is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! The zlib wrapper (RFC 1950), as found in PNGs, PDFs, git objects, ...
+//!
+//! Two bytes of header, an optional preset dictionary id, the `DEFLATE` stream,
+//! then the Adler-32 of the uncompressed data. All integers are big endian.
+
+use std::io::Read;
+use std::io::Write;
+
+use byteorder::BigEndian as BE;
+use byteorder::ReadBytesExt;
+use byteorder::WriteBytesExt;
+use failure::ensure;
+use failure::Error;
+
+const METHOD_DEFLATE: u8 = 8;
+const FLAG_DICTIONARY: u8 = 1 << 5;
+
+/// The largest prime smaller than 65536.
+const ADLER_MOD: u32 = 65521;
+
+/// The most bytes we can sum before `b` could overflow a `u32`.
+const ADLER_CHUNK: usize = 5552;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+    /// `CM` and `CINFO`.
+    pub cmf: u8,
+
+    /// `FCHECK`, `FDICT` and `FLEVEL`. Kept whole, as `FCHECK` has two valid values
+    /// when the rest of the header is already a multiple of 31.
+    pub flg: u8,
+
+    /// The Adler-32 of the preset dictionary, present iff `FDICT` is set.
+    pub dictionary_id: Option<u32>,
+}
+
+impl Header {
+    pub fn read<R: Read>(mut from: R) -> Result<Header, Error> {
+        let cmf = from.read_u8()?;
+        let flg = from.read_u8()?;
+
+        ensure!(
+            METHOD_DEFLATE == cmf & 0x0f,
+            "unsupported compression method"
+        );
+        ensure!(cmf >> 4 <= 7, "invalid window size");
+        ensure!(
+            0 == ((u16::from(cmf) << 8) | u16::from(flg)) % 31,
+            "invalid header check bits"
+        );
+
+        let dictionary_id = if 0 != (flg & FLAG_DICTIONARY) {
+            Some(from.read_u32::<BE>()?)
+        } else {
+            None
+        };
+
+        Ok(Header {
+            cmf,
+            flg,
+            dictionary_id,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut into: W) -> Result<(), Error> {
+        into.write_u8(self.cmf)?;
+        into.write_u8(self.flg)?;
+        if let Some(id) = self.dictionary_id {
+            into.write_u32::<BE>(id)?;
+        }
+        Ok(())
+    }
+
+    /// The base-two logarithm of the window size the compressor declared, e.g. `15` for 32kB.
+    pub fn window_bits(&self) -> u8 {
+        (self.cmf >> 4) + 8
+    }
+
+    /// `FLEVEL`: `0` (fastest) to `3` (slowest). Informational only; zlib maps
+    /// levels 0-1, 2-5, 6 and 7-9 on to these.
+    pub fn level(&self) -> u8 {
+        self.flg >> 6
+    }
+
+    /// Check that this is the dictionary the stream was compressed with. It should then be
+    /// used as the `preroll`, and to pre-fill the `CircularBuffer` before decompression.
+    pub fn check_dictionary(&self, dictionary: &[u8]) -> Result<(), Error> {
+        match self.dictionary_id {
+            Some(id) => ensure!(id == adler32(dictionary), "wrong preset dictionary"),
+            None => ensure!(
+                dictionary.is_empty(),
+                "stream doesn't use a preset dictionary"
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Whether a file starting `cmf`, `flg` is a zlib stream; gzip's magic never looks like one.
+pub fn is_header(cmf: u8, flg: u8) -> bool {
+    METHOD_DEFLATE == cmf & 0x0f
+        && cmf >> 4 <= 7
+        && 0 == ((u16::from(cmf) << 8) | u16::from(flg)) % 31
+}
+
+pub fn read_trailer<R: Read>(mut from: R) -> Result<u32, Error> {
+    Ok(from.read_u32::<BE>()?)
+}
+
+pub fn write_trailer<W: Write>(mut into: W, data: &[u8]) -> Result<(), Error> {
+    into.write_u32::<BE>(adler32(data))?;
+    Ok(())
+}
+
+pub fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    for chunk in data.chunks(ADLER_CHUNK) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= ADLER_MOD;
+        b %= ADLER_MOD;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use flate2::Compress;
+    use flate2::Compression;
+    use flate2::FlushCompress;
+
+    use super::*;
+    use crate::bit::BitWriter;
+    use crate::parse::parse_deflate;
+    use crate::serialise::compressed_block;
+    use crate::serialise::decompressed_block;
+    use crate::tracer;
+    use crate::Block;
+    use crate::CircularBuffer;
+    use crate::Code;
+    use crate::Config;
+    use crate::Strategy;
+    use crate::Trace;
+
+    fn compress(dictionary: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut compress = Compress::new(Compression::default(), true);
+        if !dictionary.is_empty() {
+            compress.set_dictionary(dictionary).unwrap();
+        }
+
+        let mut out = Vec::with_capacity(data.len() + 128);
+        compress
+            .compress_vec(data, &mut out, FlushCompress::Finish)
+            .unwrap();
+        assert_eq!(data.len() as u64, compress.total_in());
+        out
+    }
+
+    /// Take the stream apart, trace it as zlib's default level, with the dictionary as the
+    /// preroll, check the trace restores the codes, and put it back together again.
+    /// Returns the data, its codes, and their trace.
+    fn round_trip(dictionary: &[u8], orig: &[u8]) -> (Vec<u8>, Vec<Code>, Vec<Trace>) {
+        let mut reader = Cursor::new(orig);
+        let header = Header::read(&mut reader).unwrap();
+        header.check_dictionary(dictionary).unwrap();
+        let config =
+            Config::zlib(6, 8, 15, Strategy::Default).with_window_bits(header.window_bits());
+
+        let mut data = Vec::new();
+        let mut all_codes = Vec::new();
+        let mut all_traces = Vec::new();
+        let mut rebuilt = Vec::new();
+        header.write(&mut rebuilt).unwrap();
+
+        {
+            let mut buffer = CircularBuffer::new();
+            buffer.extend(dictionary);
+
+            let mut writer = BitWriter::new(&mut rebuilt);
+            let mut it = parse_deflate(&mut reader).peekable();
+            while let Some(block) = it.next() {
+                let block = block.unwrap();
+                let start = data.len();
+                decompressed_block(&mut data, &mut buffer, &block).unwrap();
+
+                if let Block::FixedHuffman(ref codes) | Block::DynamicHuffman { ref codes, .. } =
+                    block
+                {
+                    // the dictionary is the start of the window, as far as the compressor knows
+                    let preroll: Vec<u8> =
+                        dictionary.iter().chain(&data[..start]).cloned().collect();
+                    let preroll_start = preroll.len().saturating_sub(32 * 1024);
+                    let preroll = &preroll[preroll_start..];
+                    let trace =
+                        tracer::try_config(config, preroll_start, preroll, &data[start..], codes);
+                    assert_eq!(
+                        codes,
+                        &tracer::restore(config, preroll_start, preroll, &data[start..], &trace)
+                            .unwrap()
+                    );
+                    all_codes.extend_from_slice(codes);
+                    all_traces.extend(trace);
+                }
+
+                writer.write_bit(it.peek().is_none()).unwrap();
+                compressed_block(&mut writer, &block).unwrap();
+            }
+            writer.align().unwrap();
+        }
+
+        let adler = read_trailer(&mut reader).unwrap();
+        assert_eq!(adler32(&data), adler);
+        write_trailer(&mut rebuilt, &data).unwrap();
+
+        assert_eq!(orig, rebuilt.as_slice());
+        (data, all_codes, all_traces)
+    }
+
+    #[test]
+    fn adler() {
+        assert_eq!(1, adler32(b""));
+        assert_eq!(0x11e6_0398, adler32(b"Wikipedia"));
+        assert_eq!(0xa497_59ea, adler32(&[0xff; 6000]));
+    }
+
+    #[test]
+    fn header() {
+        let header = Header::read(&[0x78, 0x9c][..]).unwrap();
+        assert_eq!(15, header.window_bits());
+        assert_eq!(2, header.level());
+        assert_eq!(None, header.dictionary_id);
+
+        assert!(Header::read(&[0x78, 0x9d][..]).is_err());
+        assert!(Header::read(&[0x1f, 0x8b][..]).is_err());
+    }
+
+    #[test]
+    fn plain() {
+        let data = include_bytes!("../../LICENSE");
+        assert_eq!(
+            &data[..],
+            round_trip(b"", &compress(b"", data)).0.as_slice()
+        );
+    }
+
+    #[test]
+    fn preset_dictionary() {
+        let dictionary = b"THE SOFTWARE IS PROVIDED \"AS IS\", WITHOUT WARRANTY OF ANY KIND";
+        let data = include_bytes!("../../LICENSE");
+        let orig = compress(dictionary, data);
+
+        let header = Header::read(&orig[..]).unwrap();
+        assert_eq!(Some(adler32(dictionary)), header.dictionary_id);
+        assert!(header.check_dictionary(b"").is_err());
+
+        let (decompressed, codes, trace) = round_trip(dictionary, &orig);
+        assert_eq!(&data[..], decompressed.as_slice());
+
+        // the dictionary is only useful if something refers back into it
+        let mut pos = 0;
+        let mut reaches_back = false;
+        for code in &codes {
+            if let Code::Reference(r) = *code {
+                reaches_back |= usize::from(r.dist()) > pos;
+            }
+            pos += usize::from(code.emitted_bytes());
+        }
+        assert!(reaches_back);
+
+        // and zlib is predicted exactly with it as the preroll
+        assert!(trace.iter().all(|&t| Trace::Correct == t));
+    }
+}
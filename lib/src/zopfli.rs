@@ -0,0 +1,1880 @@
+//! Zopfli compresses its input in master blocks of a million bytes, each with the end of the
+//! previous one as its window. A master block is first split where a quick, lazy parse says
+//! the statistics change. Each part then gets an optimal parse against a cost model built from
+//! the previous parse's statistics, fifteen times over by default, keeping the cheapest. Once
+//! the cost settles, the statistics are stirred with a fixed sequence of random numbers, so the
+//! result is deterministic, if chaotic. The parts' codes are then split again, and each block
+//! is written stored, with the fixed trees (after parsing again, for their costs), or with its
+//! own dynamic trees, whichever is smallest.
+//!
+//! None of that can be predicted one code at a time, so this is a port of the whole compressor,
+//! as the `zopfli` crate (0.8.4) has it, which is run over the data up front; the trace then
+//! just checks its codes, and the splitter its block ends. The crate is checked against Google's
+//! C Zopfli for identical output, but takes its entropies with `log2`, where C multiplies
+//! natural logs by `1 / ln 2`, so the two could round differently, and disagree on the odd code.
+//!
+//! The dynamic trees are built with Katajainen's boundary package-merge, smoothed for
+//! run length encoding when that's smaller, and written with whichever of the repeat codes
+//! make the header shortest; see `trees`.
+
+use std::cmp;
+use std::f64;
+use std::iter;
+
+use cast::u16;
+use cast::usize;
+
+use crate::bit::BitVec;
+use crate::block_type::BlockType;
+use crate::huffman;
+use crate::Code;
+use crate::Ref;
+
+/// `ZOPFLI_MASTER_BLOCK_SIZE`: each this many bytes of input are compressed on their own.
+pub const MASTER_BLOCK: usize = 1_000_000;
+
+/// `ZOPFLI_MAX_CHAIN_HITS`: how far down the hash chains a search goes.
+pub const MAX_CHAIN_HITS: usize = 8192;
+
+/// The number of iterations the `zopfli` command line, and crate, run by default.
+pub const DEFAULT_ITERATIONS: u32 = 15;
+
+const WINDOW: usize = 32 * 1024;
+const WINDOW_MASK: usize = WINDOW - 1;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// How many runs of equal distances the longest match cache remembers for each position.
+const CACHE_LENGTH: usize = 8;
+
+const NUM_LL: usize = 288;
+const NUM_D: usize = 32;
+
+const END_OF_BLOCK: usize = 256;
+
+/// `maximum_block_splits`: the most blocks a master block is split into, before the second split.
+const MAXIMUM_BLOCK_SPLITS: usize = 15;
+
+/// The order the code length code lengths are stored in.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// A block Zopfli writes: its codes, and where its data ends, counted from the start of
+/// the preroll. Stored blocks keep the codes they'd have had.
+#[derive(Clone, Debug)]
+pub struct Block {
+    pub end: usize,
+    pub codes: Vec<Code>,
+}
+
+/// Compress the `data`, after the `preroll`, which should be at most the window, as Zopfli
+/// would with this many `iterations`.
+pub fn compress(preroll: &[u8], data: &[u8], iterations: u32) -> Vec<Block> {
+    let mut all = Vec::with_capacity(preroll.len() + data.len());
+    all.extend_from_slice(preroll);
+    all.extend_from_slice(data);
+
+    let mut ret = Vec::new();
+    let mut start = preroll.len();
+    loop {
+        let end = all.len().min(start + MASTER_BLOCK);
+        master_block(&all, start, end, iterations, &mut ret);
+        start = end;
+        if start == all.len() {
+            break;
+        }
+    }
+
+    ret
+}
+
+/// Zopfli's codes for some data, by where they start.
+#[derive(Debug)]
+pub struct Parsed {
+    codes: Vec<Option<Code>>,
+}
+
+impl Parsed {
+    pub fn new(preroll: &[u8], data: &[u8], iterations: u32) -> Parsed {
+        let mut codes = vec![None; preroll.len() + data.len()];
+        let mut pos = preroll.len();
+        for block in compress(preroll, data, iterations) {
+            for code in block.codes {
+                codes[pos] = Some(code);
+                pos += usize(code.emitted_bytes());
+            }
+        }
+
+        Parsed { codes }
+    }
+
+    /// The code Zopfli has at `pos`, whose byte is `literal`. Off its path, after a correction,
+    /// that's what's left of the reference covering `pos`, if it's still long enough.
+    pub fn code_at(&self, pos: usize, literal: u8) -> Code {
+        if let Some(code) = self.codes[pos] {
+            return code;
+        }
+
+        for start in (pos.saturating_sub(MAX_MATCH - 1)..pos).rev() {
+            if let Some(Code::Reference(r)) = self.codes[start] {
+                let end = start + usize(r.run());
+                if end >= pos + MIN_MATCH {
+                    return Code::Reference(Ref::new(r.dist(), u16(end - pos).unwrap()));
+                }
+            }
+        }
+
+        Code::Literal(literal)
+    }
+}
+
+/// How many of the `codes`, starting `pos` into the preroll and data, Zopfli puts in the
+/// block, given where its blocks `end`.
+pub fn block_len(ends: &[usize], pos: usize, codes: &[Code]) -> usize {
+    let end = match ends.iter().find(|&&end| end > pos) {
+        Some(&end) => end,
+        None => return codes.len(),
+    };
+
+    let mut at = pos;
+    for (id, code) in codes.iter().enumerate() {
+        if at >= end {
+            return id;
+        }
+        at += usize(code.emitted_bytes());
+    }
+
+    codes.len()
+}
+
+/// The type Zopfli would pick for a block of these codes: the fixed trees, if they're no worse
+/// than the block's own. Zopfli compares its own parse for the fixed trees, which we can't see,
+/// but a block which went fixed is parsed for them, so they still win.
+pub fn block_type(codes: &[Code]) -> BlockType {
+    let store = Store::from_codes(codes);
+    let fixed = block_size(&store, 0, store.len(), BlockType::FixedHuffman);
+    let dynamic = block_size(&store, 0, store.len(), BlockType::DynamicHuffman);
+    if fixed <= dynamic {
+        BlockType::FixedHuffman
+    } else {
+        BlockType::DynamicHuffman
+    }
+}
+
+/// The header `add_dynamic_tree` writes for a block of these codes.
+pub fn trees(codes: &[Code]) -> BitVec {
+    let store = Store::from_codes(codes);
+    let (_, ll_lengths, d_lengths) = dynamic_lengths(&store, 0, store.len());
+
+    let mut best = 0;
+    let mut best_size = 0;
+    for repeats in 0..8 {
+        let size = encode_tree(&ll_lengths, &d_lengths, repeats, None);
+        if 0 == best_size || size < best_size {
+            best_size = size;
+            best = repeats;
+        }
+    }
+
+    let mut ret = BitVec::new();
+    encode_tree(&ll_lengths, &d_lengths, best, Some(&mut ret));
+    ret
+}
+
+/// `blocksplit_attempt`, for the master block `arr[instart..inend]`.
+fn master_block(arr: &[u8], instart: usize, inend: usize, iterations: u32, into: &mut Vec<Block>) {
+    let points = blocksplit(arr, instart, inend);
+
+    let mut lz77 = Store::default();
+    let mut splits = Vec::with_capacity(points.len());
+    let mut total = 0.0;
+    let mut last = instart;
+    for &point in points.iter().chain(iter::once(&inend)) {
+        let mut cache = Cache::new(point - last);
+        let store = lz77_optimal(&mut cache, arr, last, point, iterations);
+        total += block_size_auto_type(&store, 0, store.len());
+        for (&code, &pos) in store.codes.iter().zip(&store.pos) {
+            lz77.push(code, pos);
+        }
+        splits.push(lz77.len());
+        last = point;
+    }
+
+    // the end of the last part isn't a split
+    splits.pop();
+
+    if points.len() > 1 {
+        let again = blocksplit_lz77(&lz77);
+        let mut total_again = 0.0;
+        let mut last = 0;
+        for &split in again.iter().chain(iter::once(&lz77.len())) {
+            total_again += block_size_auto_type(&lz77, last, split);
+            last = split;
+        }
+
+        if total_again < total {
+            splits = again;
+        }
+    }
+
+    let mut last = 0;
+    let mut pos = instart;
+    for &split in splits.iter().chain(iter::once(&lz77.len())) {
+        pos += lz77.byte_range(last, split);
+        into.push(auto_type_block(arr, &lz77, last, split, pos));
+        last = split;
+    }
+}
+
+/// `add_lz77_block_auto_type`: the block, with the codes for whichever of the three block
+/// types is smallest. An empty block is written with the fixed trees.
+fn auto_type_block(arr: &[u8], lz77: &Store, lstart: usize, lend: usize, end: usize) -> Block {
+    if lstart == lend {
+        return Block {
+            end,
+            codes: Vec::new(),
+        };
+    }
+
+    let uncompressed = block_size(lz77, lstart, lend, BlockType::Uncompressed);
+    let mut fixed = block_size(lz77, lstart, lend, BlockType::FixedHuffman);
+    let dynamic = block_size(lz77, lstart, lend, BlockType::DynamicHuffman);
+
+    // only parse again for the fixed trees if they might win
+    let expensive_fixed = lz77.len() < 1000 || fixed <= dynamic * 1.1;
+    let mut fixed_store = None;
+    if expensive_fixed {
+        let instart = lz77.pos[lstart];
+        let inend = instart + lz77.byte_range(lstart, lend);
+        let mut cache = Cache::new(inend - instart);
+        let store = lz77_optimal_fixed(&mut cache, arr, instart, inend);
+        fixed = block_size(&store, 0, store.len(), BlockType::FixedHuffman);
+        fixed_store = Some(store);
+    }
+
+    let stored = uncompressed <= fixed && uncompressed <= dynamic;
+
+    // only the fixed trees get codes of their own
+    let codes = match fixed_store {
+        Some(store) if !stored && fixed <= dynamic => store.codes,
+        _ => lz77.codes[lstart..lend].to_vec(),
+    };
+
+    Block { end, codes }
+}
+
+/// `Lz77Store`: codes, where they start, and cumulative histograms of their symbols, taken
+/// every `NUM_LL` (or `NUM_D`) codes, so the histogram of any range is cheap.
+#[derive(Clone, Debug, Default)]
+struct Store {
+    codes: Vec<Code>,
+    pos: Vec<usize>,
+    ll_symbol: Vec<u16>,
+    d_symbol: Vec<u16>,
+    ll_counts: Vec<usize>,
+    d_counts: Vec<usize>,
+}
+
+impl Store {
+    fn from_codes(codes: &[Code]) -> Store {
+        let mut store = Store::default();
+        let mut pos = 0;
+        for &code in codes {
+            store.push(code, pos);
+            pos += usize(code.emitted_bytes());
+        }
+        store
+    }
+
+    fn len(&self) -> usize {
+        self.codes.len()
+    }
+
+    fn push(&mut self, code: Code, pos: usize) {
+        let origsize = self.codes.len();
+        let llstart = NUM_LL * (origsize / NUM_LL);
+        let dstart = NUM_D * (origsize / NUM_D);
+
+        if 0 == origsize % NUM_LL {
+            if 0 == origsize {
+                self.ll_counts.resize(NUM_LL, 0);
+            } else {
+                let previous = self.ll_counts[origsize - NUM_LL..origsize].to_vec();
+                self.ll_counts.extend(previous);
+            }
+        }
+
+        if 0 == origsize % NUM_D {
+            if 0 == origsize {
+                self.d_counts.resize(NUM_D, 0);
+            } else {
+                let previous = self.d_counts[origsize - NUM_D..origsize].to_vec();
+                self.d_counts.extend(previous);
+            }
+        }
+
+        self.pos.push(pos);
+        self.codes.push(code);
+
+        match code {
+            Code::Literal(byte) => {
+                self.ll_symbol.push(u16::from(byte));
+                self.d_symbol.push(0);
+                self.ll_counts[llstart + usize::from(byte)] += 1;
+            }
+            Code::Reference(r) => {
+                let ll_symbol = huffman::encode_run_length(r.run());
+                let d_symbol = u16::from(dist_symbol(r.dist()));
+                self.ll_symbol.push(ll_symbol);
+                self.d_symbol.push(d_symbol);
+                self.ll_counts[llstart + usize(ll_symbol)] += 1;
+                self.d_counts[dstart + usize(d_symbol)] += 1;
+            }
+        }
+    }
+
+    /// The histogram of the codes up to and including `lpos`.
+    fn histogram_at(&self, lpos: usize) -> (Vec<usize>, Vec<usize>) {
+        let llpos = NUM_LL * (lpos / NUM_LL);
+        let dpos = NUM_D * (lpos / NUM_D);
+
+        let mut ll = self.ll_counts[llpos..llpos + NUM_LL].to_vec();
+        for i in lpos + 1..cmp::min(llpos + NUM_LL, self.len()) {
+            ll[usize(self.ll_symbol[i])] -= 1;
+        }
+
+        let mut d = self.d_counts[dpos..dpos + NUM_D].to_vec();
+        for i in lpos + 1..cmp::min(dpos + NUM_D, self.len()) {
+            if let Code::Reference(_) = self.codes[i] {
+                d[usize(self.d_symbol[i])] -= 1;
+            }
+        }
+
+        (ll, d)
+    }
+
+    fn histogram(&self, lstart: usize, lend: usize) -> (Vec<usize>, Vec<usize>) {
+        if lstart + NUM_LL * 3 > lend {
+            let mut ll = vec![0; NUM_LL];
+            let mut d = vec![0; NUM_D];
+            for i in lstart..lend {
+                ll[usize(self.ll_symbol[i])] += 1;
+                if let Code::Reference(_) = self.codes[i] {
+                    d[usize(self.d_symbol[i])] += 1;
+                }
+            }
+            return (ll, d);
+        }
+
+        let (mut ll, mut d) = self.histogram_at(lend - 1);
+        if lstart > 0 {
+            let (ll_before, d_before) = self.histogram_at(lstart - 1);
+            for (count, before) in ll.iter_mut().zip(ll_before) {
+                *count -= before;
+            }
+            for (count, before) in d.iter_mut().zip(d_before) {
+                *count -= before;
+            }
+        }
+
+        (ll, d)
+    }
+
+    fn byte_range(&self, lstart: usize, lend: usize) -> usize {
+        if lstart == lend {
+            return 0;
+        }
+
+        let last = lend - 1;
+        self.pos[last] + usize(self.codes[last].emitted_bytes()) - self.pos[lstart]
+    }
+
+    fn push_len_dist(&mut self, arr: &[u8], length: u16, dist: u16, pos: usize) {
+        if 0 == dist {
+            self.push(Code::Literal(arr[pos]), pos);
+        } else {
+            self.push(Code::Reference(Ref::new(dist, length)), pos);
+        }
+    }
+}
+
+/// One of Zopfli's hash chains: `prev` links each position to the previous one in the window
+/// with the same hash, or to itself.
+#[derive(Clone)]
+struct Chain {
+    head: Vec<i32>,
+    prev: Vec<u16>,
+    hashval: Vec<i32>,
+    val: u16,
+}
+
+impl Chain {
+    fn new() -> Chain {
+        Chain {
+            head: vec![-1; 65536],
+            prev: (0..WINDOW).map(|i| u16(i).unwrap()).collect(),
+            hashval: vec![-1; WINDOW],
+            val: 0,
+        }
+    }
+
+    fn update(&mut self, hpos: usize) {
+        let val = i32::from(self.val);
+        let head = self.head[usize(self.val)];
+        self.prev[hpos] = if head >= 0 && self.hashval[head as usize] == val {
+            head as u16
+        } else {
+            hpos as u16
+        };
+        self.hashval[hpos] = val;
+        self.head[usize(self.val)] = hpos as i32;
+    }
+}
+
+/// `ZopfliHash`: a chain of the three byte hashes, another of those combined with the length
+/// of the run of the byte at each position, and those run lengths, `same`.
+struct Hash {
+    hash1: Chain,
+    hash2: Chain,
+    same: Vec<u16>,
+}
+
+impl Hash {
+    /// A hash of the window before `instart`, in an `arr` which ends at the end of the block.
+    fn warmed_up(arr: &[u8], instart: usize) -> Hash {
+        let mut h = Hash {
+            hash1: Chain::new(),
+            hash2: Chain::new(),
+            same: vec![0; WINDOW],
+        };
+
+        let windowstart = instart.saturating_sub(WINDOW);
+        h.update_val(arr[windowstart]);
+        if windowstart + 1 < arr.len() {
+            h.update_val(arr[windowstart + 1]);
+        }
+
+        for i in windowstart..instart {
+            h.update(arr, i);
+        }
+
+        h
+    }
+
+    fn update_val(&mut self, c: u8) {
+        self.hash1.val = ((self.hash1.val << 5) ^ u16::from(c)) & 32767;
+    }
+
+    fn update(&mut self, arr: &[u8], pos: usize) {
+        self.update_val(arr.get(pos + MIN_MATCH - 1).cloned().unwrap_or(0));
+
+        let hpos = pos & WINDOW_MASK;
+        self.hash1.update(hpos);
+
+        let mut amount = 0u16;
+        let previous = self.same[pos.wrapping_sub(1) & WINDOW_MASK];
+        if previous > 1 {
+            amount = previous - 1;
+        }
+
+        while pos + usize(amount) + 1 < arr.len()
+            && arr[pos] == arr[pos + usize(amount) + 1]
+            && amount < u16::MAX
+        {
+            amount += 1;
+        }
+        self.same[hpos] = amount;
+
+        self.hash2.val = (amount.wrapping_sub(MIN_MATCH as u16) & 255) ^ self.hash1.val;
+        self.hash2.update(hpos);
+    }
+}
+
+/// `ZopfliLongestMatchCache`: the longest match found at each position of a block, and,
+/// in `sublen`, the nearest distance for each shorter length, as up to eight runs.
+struct Cache {
+    length: Vec<u16>,
+    dist: Vec<u16>,
+    sublen: Vec<u8>,
+}
+
+enum Cached {
+    Hit { dist: u16, length: u16 },
+    Miss { limit: usize },
+}
+
+impl Cache {
+    fn new(blocksize: usize) -> Cache {
+        Cache {
+            // a length with no distance means nothing's stored yet
+            length: vec![1; blocksize],
+            dist: vec![0; blocksize],
+            sublen: vec![0; CACHE_LENGTH * blocksize * 3],
+        }
+    }
+
+    fn available(&self, lmcpos: usize) -> bool {
+        0 == self.length[lmcpos] || 0 != self.dist[lmcpos]
+    }
+
+    fn max_sublen(&self, lmcpos: usize) -> usize {
+        let start = CACHE_LENGTH * lmcpos * 3;
+        if 0 == self.sublen[start + 1] && 0 == self.sublen[start + 2] {
+            return 0;
+        }
+        usize::from(self.sublen[start + (CACHE_LENGTH - 1) * 3]) + 3
+    }
+
+    fn try_get(&self, lmcpos: usize, limit: usize, sublen: &mut Option<&mut [u16]>) -> Cached {
+        let length = self.length[lmcpos];
+        let max_sublen = self.max_sublen(lmcpos);
+        let limit_ok = MAX_MATCH == limit
+            || usize(length) <= limit
+            || (sublen.is_some() && max_sublen >= limit);
+
+        if !limit_ok || !self.available(lmcpos) {
+            return Cached::Miss { limit };
+        }
+
+        if sublen.is_some() && usize(length) > max_sublen {
+            // the distances for the shorter lengths are needed, but it's still the longest
+            return Cached::Miss {
+                limit: usize(length),
+            };
+        }
+
+        let length = cmp::min(usize(length), limit);
+        let dist = match *sublen {
+            Some(ref mut sublen) => {
+                self.fetch_sublen(lmcpos, length, sublen);
+                sublen[length]
+            }
+            None => self.dist[lmcpos],
+        };
+
+        Cached::Hit {
+            dist,
+            length: u16(length).unwrap(),
+        }
+    }
+
+    fn store(&mut self, lmcpos: usize, limit: usize, sublen: &[u16], dist: u16, length: u16) {
+        if MAX_MATCH != limit || self.available(lmcpos) {
+            return;
+        }
+
+        if usize(length) < MIN_MATCH {
+            self.dist[lmcpos] = 0;
+            self.length[lmcpos] = 0;
+        } else {
+            self.dist[lmcpos] = dist;
+            self.length[lmcpos] = length;
+        }
+
+        self.store_sublen(lmcpos, sublen, usize(length));
+    }
+
+    fn store_sublen(&mut self, lmcpos: usize, sublen: &[u16], length: usize) {
+        if length < MIN_MATCH {
+            return;
+        }
+
+        let start = CACHE_LENGTH * lmcpos * 3;
+        let mut j = 0;
+        let mut best_length = 0;
+        for i in MIN_MATCH..=length {
+            if i == length || sublen[i] != sublen[i + 1] {
+                self.sublen[start + j * 3] = (i - 3) as u8;
+                self.sublen[start + j * 3 + 1] = sublen[i] as u8;
+                self.sublen[start + j * 3 + 2] = (sublen[i] >> 8) as u8;
+                best_length = i;
+                j += 1;
+                if j >= CACHE_LENGTH {
+                    break;
+                }
+            }
+        }
+
+        if j < CACHE_LENGTH {
+            self.sublen[start + (CACHE_LENGTH - 1) * 3] = (best_length - 3) as u8;
+        }
+    }
+
+    fn fetch_sublen(&self, lmcpos: usize, length: usize, sublen: &mut [u16]) {
+        if length < MIN_MATCH {
+            return;
+        }
+
+        let start = CACHE_LENGTH * lmcpos * 3;
+        let max_length = self.max_sublen(lmcpos);
+        let mut prev_length = 0;
+        for j in 0..CACHE_LENGTH {
+            let length = usize::from(self.sublen[start + j * 3]) + 3;
+            let dist = u16::from(self.sublen[start + j * 3 + 1])
+                + 256 * u16::from(self.sublen[start + j * 3 + 2]);
+            for entry in &mut sublen[prev_length..=length] {
+                *entry = dist;
+            }
+            if length == max_length {
+                break;
+            }
+            prev_length = length + 1;
+        }
+    }
+}
+
+/// `find_longest_match`: the distance and length of the longest match at `pos`, searching at
+/// most `limit` bytes, and filling in the nearest distance for each shorter length in `sublen`.
+/// No match is a length of zero or one.
+fn find_longest_match(
+    cache: Option<&mut Cache>,
+    h: &Hash,
+    arr: &[u8],
+    pos: usize,
+    blockstart: usize,
+    limit: usize,
+    mut sublen: Option<&mut [u16]>,
+) -> (u16, u16) {
+    let size = arr.len();
+    let lmcpos = pos - blockstart;
+
+    let mut limit = limit;
+    if let Some(ref cache) = cache {
+        match cache.try_get(lmcpos, limit, &mut sublen) {
+            Cached::Hit { dist, length } => return (dist, length),
+            Cached::Miss { limit: cached } => limit = cached,
+        }
+    }
+
+    if size - pos < MIN_MATCH {
+        return (0, 0);
+    }
+
+    if pos + limit > size {
+        limit = size - pos;
+    }
+
+    let (dist, length) = longest_match_loop(h, arr, pos, limit, &mut sublen);
+
+    if let (Some(cache), Some(sublen)) = (cache, sublen) {
+        cache.store(lmcpos, limit, sublen, dist, length);
+    }
+
+    (dist, length)
+}
+
+/// Walk the chains at `pos`, switching to the one which includes the run lengths once the
+/// match is at least as long as this position's run.
+fn longest_match_loop(
+    h: &Hash,
+    arr: &[u8],
+    pos: usize,
+    limit: usize,
+    sublen: &mut Option<&mut [u16]>,
+) -> (u16, u16) {
+    let size = arr.len();
+    let hpos = pos & WINDOW_MASK;
+    let mut chain = &h.hash1;
+
+    let mut pp = hpos;
+    let mut p = usize(chain.prev[pp]);
+    let mut dist = if p < pp { pp - p } else { WINDOW - p + pp };
+
+    let mut best_length = 1;
+    let mut best_dist = 0;
+    let mut chain_counter = MAX_CHAIN_HITS;
+    let arrayend = pos + limit;
+
+    while dist < WINDOW && chain_counter > 0 {
+        let mut current_length = 0;
+
+        if dist > 0 {
+            let mut scan = pos;
+            let mut found = pos - dist;
+
+            // checking the byte which would make it longer first is quicker
+            if pos + best_length >= size || arr[scan + best_length] == arr[found + best_length] {
+                let same0 = h.same[pos & WINDOW_MASK];
+                if same0 > 2 && arr[scan] == arr[found] {
+                    let same1 = h.same[(pos - dist) & WINDOW_MASK];
+                    let same = usize(cmp::min(cmp::min(same0, same1), u16(limit).unwrap()));
+                    scan += same;
+                    found += same;
+                }
+
+                while scan < arrayend && arr[scan] == arr[found] {
+                    scan += 1;
+                    found += 1;
+                }
+                current_length = scan - pos;
+            }
+
+            if current_length > best_length {
+                if let Some(ref mut sublen) = *sublen {
+                    for entry in &mut sublen[best_length + 1..=current_length] {
+                        *entry = dist as u16;
+                    }
+                }
+                best_dist = dist;
+                best_length = current_length;
+                if current_length >= limit {
+                    break;
+                }
+            }
+        }
+
+        if std::ptr::eq(chain, &h.hash1)
+            && best_length >= usize(h.same[hpos])
+            && i32::from(h.hash2.val) == h.hash2.hashval[p]
+        {
+            chain = &h.hash2;
+        }
+
+        pp = p;
+        p = usize(chain.prev[p]);
+        if p == pp {
+            break;
+        }
+
+        dist += if p < pp { pp - p } else { WINDOW - p + pp };
+        chain_counter -= 1;
+    }
+
+    (best_dist as u16, best_length as u16)
+}
+
+/// Matches a kilobyte or more away are worth a byte less.
+fn length_score(length: u16, dist: u16) -> u16 {
+    if dist > 1024 {
+        length - 1
+    } else {
+        length
+    }
+}
+
+/// `ZopfliLZ77Greedy`, which, despite the name, looks a byte ahead, as gzip does.
+fn greedy(arr: &[u8], instart: usize, inend: usize) -> Store {
+    let mut store = Store::default();
+    if instart == inend {
+        return store;
+    }
+
+    let arr = &arr[..inend];
+    let mut h = Hash::warmed_up(arr, instart);
+
+    let mut i = instart;
+    let mut prev_length = 0;
+    let mut prev_match = 0;
+    let mut match_available = false;
+
+    while i < inend {
+        h.update(arr, i);
+
+        let (mut dist, mut length) = find_longest_match(None, &h, arr, i, instart, MAX_MATCH, None);
+        let score = length_score(length, dist);
+
+        let prev_score = length_score(prev_length, prev_match);
+        if match_available {
+            match_available = false;
+            if score > prev_score + 1 {
+                store.push(Code::Literal(arr[i - 1]), i - 1);
+                if usize(score) >= MIN_MATCH && usize(length) < MAX_MATCH {
+                    match_available = true;
+                    prev_length = length;
+                    prev_match = dist;
+                    i += 1;
+                    continue;
+                }
+            } else {
+                store.push_len_dist(arr, prev_length, prev_match, i - 1);
+                for _ in 2..prev_length {
+                    i += 1;
+                    h.update(arr, i);
+                }
+                i += 1;
+                continue;
+            }
+        } else if usize(score) >= MIN_MATCH && usize(length) < MAX_MATCH {
+            match_available = true;
+            prev_length = length;
+            prev_match = dist;
+            i += 1;
+            continue;
+        }
+
+        if usize(score) >= MIN_MATCH {
+            store.push_len_dist(arr, length, dist, i);
+        } else {
+            length = 1;
+            dist = 0;
+            store.push_len_dist(arr, length, dist, i);
+        }
+        for _ in 1..length {
+            i += 1;
+            h.update(arr, i);
+        }
+        i += 1;
+    }
+
+    store
+}
+
+fn dist_symbol(dist: u16) -> u8 {
+    huffman::encode_distance(u32::from(dist))
+        .expect("never none")
+        .0
+}
+
+fn dist_extra_bits(dist: u16) -> u8 {
+    huffman::encode_distance(u32::from(dist))
+        .expect("never none")
+        .1
+}
+
+fn length_extra_bits(length: u16) -> u8 {
+    huffman::extra_run_length(length).map_or(0, |(bits, _)| bits)
+}
+
+fn length_symbol_extra_bits(symbol: usize) -> usize {
+    match symbol {
+        265..=284 => (symbol - 261) / 4,
+        _ => 0,
+    }
+}
+
+fn dist_symbol_extra_bits(symbol: usize) -> usize {
+    if symbol < 4 {
+        0
+    } else {
+        symbol / 2 - 1
+    }
+}
+
+/// `get_cost_fixed`: bits for a literal (`dist` zero) or a match, with the fixed trees.
+fn fixed_cost(litlen: usize, dist: u16) -> f64 {
+    if 0 == dist {
+        return if litlen <= 143 { 8.0 } else { 9.0 };
+    }
+
+    let length = u16(litlen).unwrap();
+    let symbol = huffman::encode_run_length(length);
+    let bits = 7
+        + u32::from(symbol > 279)
+        + 5
+        + u32::from(dist_extra_bits(dist))
+        + u32::from(length_extra_bits(length));
+    f64::from(bits)
+}
+
+/// `RanState`: Marsaglia's multiply-with-carry, always from the same seed.
+struct RanState {
+    m_w: u32,
+    m_z: u32,
+}
+
+impl RanState {
+    fn new() -> RanState {
+        RanState { m_w: 1, m_z: 2 }
+    }
+
+    fn next(&mut self) -> u32 {
+        self.m_z = 36969 * (self.m_z & 65535) + (self.m_z >> 16);
+        self.m_w = 18000 * (self.m_w & 65535) + (self.m_w >> 16);
+        (self.m_z << 16).wrapping_add(self.m_w)
+    }
+}
+
+/// `SymbolStats`: how often each symbol was used, and so its cost in bits.
+#[derive(Copy, Clone)]
+struct Stats {
+    litlens: [usize; NUM_LL],
+    dists: [usize; NUM_D],
+    ll_symbols: [f64; NUM_LL],
+    d_symbols: [f64; NUM_D],
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats {
+            litlens: [0; NUM_LL],
+            dists: [0; NUM_D],
+            ll_symbols: [0.0; NUM_LL],
+            d_symbols: [0.0; NUM_D],
+        }
+    }
+}
+
+impl Stats {
+    fn of(store: &Store) -> Stats {
+        let mut stats = Stats::default();
+        for &code in &store.codes {
+            match code {
+                Code::Literal(byte) => stats.litlens[usize::from(byte)] += 1,
+                Code::Reference(r) => {
+                    stats.litlens[usize(huffman::encode_run_length(r.run()))] += 1;
+                    stats.dists[usize::from(dist_symbol(r.dist()))] += 1;
+                }
+            }
+        }
+        stats.litlens[END_OF_BLOCK] = 1;
+        stats.calculate_entropy();
+        stats
+    }
+
+    /// `add_weighed_stat_freqs`, which leaves the costs to be calculated.
+    fn weighed(&self, w1: f64, other: &Stats, w2: f64) -> Stats {
+        let mut ret = Stats::default();
+        for i in 0..NUM_LL {
+            ret.litlens[i] = (self.litlens[i] as f64 * w1 + other.litlens[i] as f64 * w2) as usize;
+        }
+        for i in 0..NUM_D {
+            ret.dists[i] = (self.dists[i] as f64 * w1 + other.dists[i] as f64 * w2) as usize;
+        }
+        ret.litlens[END_OF_BLOCK] = 1;
+        ret
+    }
+
+    fn randomize(&mut self, state: &mut RanState) {
+        randomize_freqs(&mut self.litlens, state);
+        randomize_freqs(&mut self.dists, state);
+        self.litlens[END_OF_BLOCK] = 1;
+    }
+
+    fn calculate_entropy(&mut self) {
+        entropy(&self.litlens, &mut self.ll_symbols);
+        entropy(&self.dists, &mut self.d_symbols);
+    }
+
+    /// `get_cost_stat`: bits for a literal (`dist` zero) or a match, with these statistics.
+    fn cost(&self, litlen: usize, dist: u16) -> f64 {
+        if 0 == dist {
+            return self.ll_symbols[litlen];
+        }
+
+        let length = u16(litlen).unwrap();
+        let ll_symbol = usize(huffman::encode_run_length(length));
+        let lbits = f64::from(length_extra_bits(length));
+        let dbits = f64::from(dist_extra_bits(dist));
+        let d_symbol = usize::from(dist_symbol(dist));
+        lbits + dbits + self.ll_symbols[ll_symbol] + self.d_symbols[d_symbol]
+    }
+}
+
+fn randomize_freqs(freqs: &mut [usize], state: &mut RanState) {
+    let n = freqs.len();
+    for i in 0..n {
+        if 0 == (state.next() >> 4) % 3 {
+            let index = state.next() as usize % n;
+            freqs[i] = freqs[index];
+        }
+    }
+}
+
+fn entropy(counts: &[usize], bit_lengths: &mut [f64]) {
+    let sum: usize = counts.iter().sum();
+    let log2sum = (if 0 == sum { counts.len() } else { sum } as f64).log2();
+    for (&count, bits) in counts.iter().zip(bit_lengths) {
+        *bits = if 0 == count {
+            log2sum
+        } else {
+            log2sum - (count as f64).log2()
+        };
+    }
+}
+
+/// `get_cost_model_min_cost`: the cost of the cheapest length with the cheapest distance.
+fn min_cost<F: Fn(usize, u16) -> f64>(cost_model: &F) -> f64 {
+    // the smallest distance for each distance symbol
+    const DSYMBOLS: [u16; 30] = [
+        1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+        2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+    ];
+
+    let mut best_length = 0;
+    let mut min = f64::INFINITY;
+    for length in 3..259 {
+        let cost = cost_model(length, 1);
+        if cost < min {
+            best_length = length;
+            min = cost;
+        }
+    }
+
+    let mut best_dist = 0;
+    min = f64::INFINITY;
+    for &dist in &DSYMBOLS {
+        let cost = cost_model(3, dist);
+        if cost < min {
+            best_dist = dist;
+            min = cost;
+        }
+    }
+
+    cost_model(best_length, best_dist)
+}
+
+/// `get_best_lengths`: the cheapest way to reach each position of the block, under the
+/// `cost_model`, as the length of the code which gets there.
+fn best_lengths<F: Fn(usize, u16) -> f64>(
+    cache: &mut Cache,
+    arr: &[u8],
+    instart: usize,
+    inend: usize,
+    cost_model: F,
+) -> Vec<u16> {
+    let blocksize = inend - instart;
+    let mut length_array = vec![0u16; blocksize + 1];
+    if instart == inend {
+        return length_array;
+    }
+
+    let arr = &arr[..inend];
+    let mut h = Hash::warmed_up(arr, instart);
+
+    let mut costs = vec![f32::INFINITY; blocksize + 1];
+    costs[0] = 0.0;
+
+    let mut sublen = vec![0u16; MAX_MATCH + 1];
+    let min = min_cost(&cost_model);
+
+    let mut i = instart;
+    while i < inend {
+        let mut j = i - instart;
+        h.update(arr, i);
+
+        // in a long run of a single byte, skip ahead by whole matches
+        if h.same[i & WINDOW_MASK] > u16(MAX_MATCH * 2).unwrap()
+            && i > instart + MAX_MATCH + 1
+            && i + MAX_MATCH * 2 + 1 < inend
+            && h.same[(i - MAX_MATCH) & WINDOW_MASK] > u16(MAX_MATCH).unwrap()
+        {
+            let symbol_cost = cost_model(MAX_MATCH, 1);
+            for _ in 0..MAX_MATCH {
+                costs[j + MAX_MATCH] = costs[j] + symbol_cost as f32;
+                length_array[j + MAX_MATCH] = u16(MAX_MATCH).unwrap();
+                i += 1;
+                j += 1;
+                h.update(arr, i);
+            }
+        }
+
+        let (_, length) = find_longest_match(
+            Some(cache),
+            &h,
+            arr,
+            i,
+            instart,
+            MAX_MATCH,
+            Some(&mut sublen),
+        );
+
+        if i < inend {
+            let cost = cost_model(usize::from(arr[i]), 0) + f64::from(costs[j]);
+            if cost < f64::from(costs[j + 1]) {
+                costs[j + 1] = cost as f32;
+                length_array[j + 1] = 1;
+            }
+        }
+
+        let kend = cmp::min(usize(length), inend - i);
+        let min_here = min + f64::from(costs[j]);
+        for k in 3..=kend {
+            if f64::from(costs[j + k]) <= min_here {
+                continue;
+            }
+
+            let cost = cost_model(k, sublen[k]) + f64::from(costs[j]);
+            if cost < f64::from(costs[j + k]) {
+                costs[j + k] = cost as f32;
+                length_array[j + k] = u16(k).unwrap();
+            }
+        }
+
+        i += 1;
+    }
+
+    length_array
+}
+
+/// `lz77_optimal_run`: the cheapest path through the block, under the `cost_model`, then the
+/// codes along it, searching again for each match's distance.
+fn optimal_run<F: Fn(usize, u16) -> f64>(
+    cache: &mut Cache,
+    arr: &[u8],
+    instart: usize,
+    inend: usize,
+    cost_model: F,
+) -> Store {
+    let length_array = best_lengths(cache, arr, instart, inend, cost_model);
+
+    let mut path = Vec::new();
+    let mut index = inend - instart;
+    while index > 0 {
+        let length = length_array[index];
+        path.push(length);
+        index -= usize(length);
+    }
+
+    let mut store = Store::default();
+    if instart == inend {
+        return store;
+    }
+
+    let arr = &arr[..inend];
+    let mut h = Hash::warmed_up(arr, instart);
+
+    let mut pos = instart;
+    for &length in path.iter().rev() {
+        h.update(arr, pos);
+
+        let mut length = length;
+        if usize(length) >= MIN_MATCH {
+            let (dist, _) =
+                find_longest_match(Some(cache), &h, arr, pos, instart, usize(length), None);
+            store.push_len_dist(arr, length, dist, pos);
+        } else {
+            length = 1;
+            store.push(Code::Literal(arr[pos]), pos);
+        }
+
+        for j in 1..usize(length) {
+            h.update(arr, pos + j);
+        }
+
+        pos += usize(length);
+    }
+
+    store
+}
+
+/// `lz77_optimal_fixed`: one run, costed with the fixed trees.
+fn lz77_optimal_fixed(cache: &mut Cache, arr: &[u8], instart: usize, inend: usize) -> Store {
+    optimal_run(cache, arr, instart, inend, fixed_cost)
+}
+
+/// `lz77_optimal`: start with the statistics of the greedy parse, then parse again with the
+/// statistics of the last parse, keeping the cheapest, and randomising once it stalls.
+fn lz77_optimal(
+    cache: &mut Cache,
+    arr: &[u8],
+    instart: usize,
+    inend: usize,
+    iterations: u32,
+) -> Store {
+    let mut stats = Stats::of(&greedy(arr, instart, inend));
+
+    let mut output = Store::default();
+    let mut best_stats = Stats::default();
+    let mut best_cost = f64::INFINITY;
+    let mut last_cost = 0.0;
+    let mut ran_state = RanState::new();
+    let mut last_random_step = None;
+
+    for iteration in 0..iterations {
+        let current = optimal_run(cache, arr, instart, inend, |litlen, dist| {
+            stats.cost(litlen, dist)
+        });
+        let cost = block_size(&current, 0, current.len(), BlockType::DynamicHuffman);
+
+        if cost < best_cost {
+            output = current.clone();
+            best_stats = stats;
+            best_cost = cost;
+        }
+
+        if iteration + 1 == iterations {
+            break;
+        }
+
+        let last_stats = stats;
+        stats = Stats::of(&current);
+
+        // converges slower, but better, so only once the randomness kicks in
+        if last_random_step.is_some() {
+            stats = stats.weighed(1.0, &last_stats, 0.5);
+            stats.calculate_entropy();
+        }
+
+        if iteration > 5 && (cost - last_cost).abs() < f64::EPSILON {
+            stats = best_stats;
+            stats.randomize(&mut ran_state);
+            stats.calculate_entropy();
+            last_random_step = Some(iteration);
+        }
+
+        last_cost = cost;
+    }
+
+    output
+}
+
+/// `blocksplit`: where to split the master block, by its greedy parse, as byte positions.
+fn blocksplit(arr: &[u8], instart: usize, inend: usize) -> Vec<usize> {
+    let store = greedy(arr, instart, inend);
+    let points = blocksplit_lz77(&store);
+
+    let mut ret = Vec::with_capacity(points.len());
+    let mut pos = instart;
+    for (id, code) in store.codes.iter().enumerate() {
+        if ret.len() == points.len() {
+            break;
+        }
+        if points[ret.len()] == id {
+            ret.push(pos);
+        }
+        pos += usize(code.emitted_bytes());
+    }
+
+    ret
+}
+
+/// `blocksplit_lz77`: repeatedly split the largest block at the point which minimises the
+/// cost of its halves, if that's cheaper, until there are `MAXIMUM_BLOCK_SPLITS` blocks.
+fn blocksplit_lz77(lz77: &Store) -> Vec<usize> {
+    let mut points = Vec::new();
+    if lz77.len() < 10 {
+        return points;
+    }
+
+    let mut blocks = 1;
+    let mut done = vec![false; lz77.len()];
+    let mut lstart = 0;
+    let mut lend = lz77.len();
+
+    while blocks < MAXIMUM_BLOCK_SPLITS {
+        let (llpos, split_cost) = find_minimum(
+            |i| block_size_auto_type(lz77, lstart, i) + block_size_auto_type(lz77, i, lend),
+            lstart + 1,
+            lend,
+        );
+
+        let orig_cost = block_size_auto_type(lz77, lstart, lend);
+        if split_cost > orig_cost || llpos == lstart + 1 || llpos == lend {
+            done[lstart] = true;
+        } else {
+            points.push(llpos);
+            points.sort();
+            blocks += 1;
+        }
+
+        match largest_splittable_block(lz77.len(), &done, &points) {
+            Some((start, end)) if end - start >= 10 => {
+                lstart = start;
+                lend = end;
+            }
+            _ => break,
+        }
+    }
+
+    points
+}
+
+/// `find_minimum`: every position, for short ranges, otherwise narrowing in on nine evenly
+/// spaced probes, for as long as they improve.
+fn find_minimum<F: Fn(usize) -> f64>(f: F, start: usize, end: usize) -> (usize, f64) {
+    if end - start < 1024 {
+        let mut best = f64::INFINITY;
+        let mut result = start;
+        for i in start..end {
+            let v = f(i);
+            if v < best {
+                best = v;
+                result = i;
+            }
+        }
+        return (result, best);
+    }
+
+    const NUM: usize = 9;
+    let mut start = start;
+    let mut end = end;
+    let mut p = [0; NUM];
+    let mut last_best = f64::INFINITY;
+    let mut pos = start;
+
+    while end - start > NUM {
+        let mut best_i = 0;
+        let mut best = f64::INFINITY;
+        let multiplier = (end - start) / (NUM + 1);
+        for (i, point) in p.iter_mut().enumerate() {
+            *point = start + (i + 1) * multiplier;
+            let v = f(*point);
+            if v < best {
+                best = v;
+                best_i = i;
+            }
+        }
+
+        if best > last_best {
+            break;
+        }
+
+        if best_i > 0 {
+            start = p[best_i - 1];
+        }
+        if best_i < NUM - 1 {
+            end = p[best_i + 1];
+        }
+
+        pos = p[best_i];
+        last_best = best;
+    }
+
+    (pos, last_best)
+}
+
+fn largest_splittable_block(
+    size: usize,
+    done: &[bool],
+    points: &[usize],
+) -> Option<(usize, usize)> {
+    let mut longest = 0;
+    let mut found = None;
+    let mut last = 0;
+    for &point in points {
+        if !done[last] && point - last > longest {
+            found = Some((last, point));
+            longest = point - last;
+        }
+        last = point;
+    }
+
+    let end = size - 1;
+    if !done[last] && end - last > longest {
+        found = Some((last, end));
+    }
+
+    found
+}
+
+/// `calculate_block_size_auto_type`: the smallest of the sizes, in bits. The fixed trees are
+/// only considered if the whole store is small.
+fn block_size_auto_type(lz77: &Store, lstart: usize, lend: usize) -> f64 {
+    let uncompressed = block_size(lz77, lstart, lend, BlockType::Uncompressed);
+    let fixed = if lz77.len() > 1000 {
+        uncompressed
+    } else {
+        block_size(lz77, lstart, lend, BlockType::FixedHuffman)
+    };
+    let dynamic = block_size(lz77, lstart, lend, BlockType::DynamicHuffman);
+    uncompressed.min(fixed).min(dynamic)
+}
+
+/// `calculate_block_size`, in bits, including the block header.
+fn block_size(lz77: &Store, lstart: usize, lend: usize, block_type: BlockType) -> f64 {
+    match block_type {
+        BlockType::Uncompressed => {
+            let length = lz77.byte_range(lstart, lend);
+            let blocks = (length + 65534) / 65535;
+            (blocks * 5 * 8 + length * 8) as f64
+        }
+        BlockType::FixedHuffman => {
+            let (ll_lengths, d_lengths) = fixed_lengths();
+            let (ll_counts, d_counts) = lz77.histogram(lstart, lend);
+            3.0 + symbol_size(&ll_counts, &d_counts, &ll_lengths, &d_lengths) as f64
+        }
+        BlockType::DynamicHuffman => dynamic_lengths(lz77, lstart, lend).0 + 3.0,
+    }
+}
+
+fn fixed_lengths() -> (Vec<u8>, Vec<u8>) {
+    let mut ll = vec![8; 144];
+    ll.resize(256, 9);
+    ll.resize(280, 7);
+    ll.resize(288, 8);
+    (ll, vec![5; NUM_D])
+}
+
+/// The bits for the codes with these counts, and the end of block, with these lengths.
+fn symbol_size(
+    ll_counts: &[usize],
+    d_counts: &[usize],
+    ll_lengths: &[u8],
+    d_lengths: &[u8],
+) -> usize {
+    let mut result = 0;
+    for i in 0..END_OF_BLOCK {
+        result += usize::from(ll_lengths[i]) * ll_counts[i];
+    }
+    for i in 257..286 {
+        result += usize::from(ll_lengths[i]) * ll_counts[i];
+        result += length_symbol_extra_bits(i) * ll_counts[i];
+    }
+    for i in 0..30 {
+        result += usize::from(d_lengths[i]) * d_counts[i];
+        result += dist_symbol_extra_bits(i) * d_counts[i];
+    }
+    result + usize::from(ll_lengths[END_OF_BLOCK])
+}
+
+/// `get_dynamic_lengths`: the size, in bits, and code lengths of the dynamic trees.
+fn dynamic_lengths(lz77: &Store, lstart: usize, lend: usize) -> (f64, Vec<u8>, Vec<u8>) {
+    let (mut ll_counts, d_counts) = lz77.histogram(lstart, lend);
+    ll_counts[END_OF_BLOCK] = 1;
+
+    let ll_lengths = length_limited_code_lengths(&ll_counts, 15);
+    let mut d_lengths = length_limited_code_lengths(&d_counts, 15);
+    patch_distance_codes(&mut d_lengths);
+
+    // `try_optimize_huffman_for_rle`
+    let header_size = tree_size(&ll_lengths, &d_lengths);
+    let data_size = symbol_size(&ll_counts, &d_counts, &ll_lengths, &d_lengths);
+
+    let mut ll_counts2 = ll_counts.clone();
+    let mut d_counts2 = d_counts.clone();
+    optimize_huffman_for_rle(&mut ll_counts2);
+    optimize_huffman_for_rle(&mut d_counts2);
+
+    let ll_lengths2 = length_limited_code_lengths(&ll_counts2, 15);
+    let mut d_lengths2 = length_limited_code_lengths(&d_counts2, 15);
+    patch_distance_codes(&mut d_lengths2);
+
+    let header_size2 = tree_size(&ll_lengths2, &d_lengths2);
+    let data_size2 = symbol_size(&ll_counts, &d_counts, &ll_lengths2, &d_lengths2);
+
+    if header_size2 + data_size2 < header_size + data_size {
+        ((header_size2 + data_size2) as f64, ll_lengths2, d_lengths2)
+    } else {
+        ((header_size + data_size) as f64, ll_lengths, d_lengths)
+    }
+}
+
+/// Some decoders reject fewer than two distance codes.
+fn patch_distance_codes(d_lengths: &mut [u8]) {
+    match d_lengths[..30].iter().filter(|&&len| 0 != len).count() {
+        0 => {
+            d_lengths[0] = 1;
+            d_lengths[1] = 1;
+        }
+        1 => {
+            let index = if 0 != d_lengths[0] { 1 } else { 0 };
+            d_lengths[index] = 1;
+        }
+        _ => (),
+    }
+}
+
+/// Smooth the `counts` towards runs, so the lengths are cheaper to encode.
+fn optimize_huffman_for_rle(counts: &mut [usize]) {
+    let length = match counts.iter().rposition(|&count| 0 != count) {
+        Some(last) => last + 1,
+        None => return,
+    };
+
+    // runs which are already long enough to be encoded as runs are left alone; the last
+    // run never is, as Zopfli doesn't check it
+    let mut good_for_rle = vec![false; length];
+    let mut symbol = counts[0];
+    let mut stride = 0;
+    for i in 0..length {
+        if counts[i] == symbol {
+            stride += 1;
+        } else {
+            if (0 == symbol && stride >= 5) || (0 != symbol && stride >= 7) {
+                for k in 0..stride {
+                    good_for_rle[i - k - 1] = true;
+                }
+            }
+            stride = 1;
+            symbol = counts[i];
+        }
+    }
+
+    let mut stride = 0;
+    let mut limit = counts[0];
+    let mut sum = 0;
+    for i in 0..=length {
+        if i == length || good_for_rle[i] || (counts[i] as i64 - limit as i64).abs() >= 4 {
+            if stride >= 4 || (stride >= 3 && 0 == sum) {
+                let count = if 0 == sum {
+                    0
+                } else {
+                    cmp::max((sum + stride / 2) / stride, 1)
+                };
+                for c in &mut counts[i - stride..i] {
+                    *c = count;
+                }
+            }
+            stride = 0;
+            sum = 0;
+            limit = if length > 2 && i < length - 3 {
+                (counts[i] + counts[i + 1] + counts[i + 2] + counts[i + 3] + 2) / 4
+            } else if i < length {
+                counts[i]
+            } else {
+                0
+            };
+        }
+        stride += 1;
+        if i != length {
+            sum += counts[i];
+        }
+    }
+}
+
+/// `calculate_tree_size`: the smallest header, over which of the repeat codes are used.
+fn tree_size(ll_lengths: &[u8], d_lengths: &[u8]) -> usize {
+    (0..8)
+        .map(|repeats| encode_tree(ll_lengths, d_lengths, repeats, None))
+        .min()
+        .expect("non-empty")
+}
+
+/// `encode_tree`: the size of the header, in bits, using code 16 if `repeats & 1`, 17 if
+/// `repeats & 2`, and 18 if `repeats & 4`, and the header itself, into `out`, if present.
+fn encode_tree(
+    ll_lengths: &[u8],
+    d_lengths: &[u8],
+    repeats: u8,
+    out: Option<&mut BitVec>,
+) -> usize {
+    let use_16 = 0 != repeats & 1;
+    let use_17 = 0 != repeats & 2;
+    let use_18 = 0 != repeats & 4;
+
+    let mut hlit = 29;
+    let mut hdist = 29;
+    while hlit > 0 && 0 == ll_lengths[257 + hlit - 1] {
+        hlit -= 1;
+    }
+    while hdist > 0 && 0 == d_lengths[hdist] {
+        hdist -= 1;
+    }
+
+    let hlit2 = hlit + 257;
+    let lld_total = hlit2 + hdist + 1;
+    let length_at = |i: usize| {
+        if i < hlit2 {
+            ll_lengths[i]
+        } else {
+            d_lengths[i - hlit2]
+        }
+    };
+
+    let mut rle: Vec<(u8, u16)> = Vec::new();
+    let mut cl_counts = [0usize; 19];
+
+    let mut i = 0;
+    while i < lld_total {
+        let symbol = length_at(i);
+        let mut count = 1;
+        if use_16 || (0 == symbol && (use_17 || use_18)) {
+            let mut j = i + 1;
+            while j < lld_total && symbol == length_at(j) {
+                count += 1;
+                j += 1;
+            }
+        }
+        i += count - 1;
+
+        if 0 == symbol && count >= 3 {
+            if use_18 {
+                while count >= 11 {
+                    let run = cmp::min(count, 138);
+                    rle.push((18, u16(run - 11).unwrap()));
+                    cl_counts[18] += 1;
+                    count -= run;
+                }
+            }
+            if use_17 {
+                while count >= 3 {
+                    let run = cmp::min(count, 10);
+                    rle.push((17, u16(run - 3).unwrap()));
+                    cl_counts[17] += 1;
+                    count -= run;
+                }
+            }
+        }
+
+        if use_16 && count >= 4 {
+            // the first is written as itself
+            count -= 1;
+            cl_counts[usize::from(symbol)] += 1;
+            rle.push((symbol, 0));
+            while count >= 3 {
+                let run = cmp::min(count, 6);
+                rle.push((16, u16(run - 3).unwrap()));
+                cl_counts[16] += 1;
+                count -= run;
+            }
+        }
+
+        cl_counts[usize::from(symbol)] += count;
+        for _ in 0..count {
+            rle.push((symbol, 0));
+        }
+
+        i += 1;
+    }
+
+    let clcl = length_limited_code_lengths(&cl_counts, 7);
+
+    let mut hclen = 15;
+    while hclen > 0 && 0 == cl_counts[CODE_LENGTH_ORDER[hclen + 4 - 1]] {
+        hclen -= 1;
+    }
+
+    if let Some(out) = out {
+        let symbols = canonical_codes(&clcl);
+        push_bits(out, 5, u16(hlit).unwrap());
+        push_bits(out, 5, u16(hdist).unwrap());
+        push_bits(out, 4, u16(hclen).unwrap());
+        for &sym in &CODE_LENGTH_ORDER[..hclen + 4] {
+            push_bits(out, 3, u16::from(clcl[sym]));
+        }
+
+        for &(sym, extra) in &rle {
+            let sym = usize::from(sym);
+            for bit in (0..clcl[sym]).rev() {
+                out.push(0 != (symbols[sym] >> bit) & 1);
+            }
+            match sym {
+                16 => push_bits(out, 2, extra),
+                17 => push_bits(out, 3, extra),
+                18 => push_bits(out, 7, extra),
+                _ => (),
+            }
+        }
+    }
+
+    let mut size = 14 + (hclen + 4) * 3;
+    for (&len, &count) in clcl.iter().zip(&cl_counts) {
+        size += usize::from(len) * count;
+    }
+    size + cl_counts[16] * 2 + cl_counts[17] * 3 + cl_counts[18] * 7
+}
+
+fn push_bits(into: &mut BitVec, bits: u8, val: u16) {
+    for i in 0..bits {
+        into.push(0 != (val & (1 << i)));
+    }
+}
+
+/// The canonical huffman code for each symbol, for these lengths.
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut bl_count = [0u16; 16];
+    for &len in lengths {
+        bl_count[usize::from(len)] += 1;
+    }
+    bl_count[0] = 0;
+
+    let mut next_code = [0u16; 16];
+    let mut code = 0;
+    for bits in 1..16 {
+        code = (code + bl_count[bits - 1]) << 1;
+        next_code[bits] = code;
+    }
+
+    lengths
+        .iter()
+        .map(|&len| {
+            if 0 == len {
+                return 0;
+            }
+            let code = next_code[usize::from(len)];
+            next_code[usize::from(len)] += 1;
+            code
+        })
+        .collect()
+}
+
+/// A chain in the boundary package-merge: its weight, how many leaves it has taken,
+/// and the chain in the previous list it extends.
+struct Node {
+    weight: usize,
+    count: usize,
+    tail: Option<usize>,
+}
+
+/// `length_limited_code_lengths`: Katajainen, Moffat and Turpin's boundary package-merge,
+/// for code lengths of at most `max_bits`. The leaves are sorted stably by weight, so ties
+/// go to the lower symbol.
+fn length_limited_code_lengths(frequencies: &[usize], max_bits: usize) -> Vec<u8> {
+    let mut leaves: Vec<(usize, usize)> = frequencies
+        .iter()
+        .enumerate()
+        .filter(|&(_, &freq)| 0 != freq)
+        .map(|(symbol, &freq)| (freq, symbol))
+        .collect();
+
+    let mut lengths = vec![0; frequencies.len()];
+    if leaves.len() <= 2 {
+        for &(_, symbol) in &leaves {
+            lengths[symbol] = 1;
+        }
+        return lengths;
+    }
+
+    leaves.sort_by_key(|&(weight, _)| weight);
+
+    let max_bits = cmp::min(leaves.len() - 1, max_bits);
+
+    let mut pm = PackageMerge {
+        nodes: vec![
+            Node {
+                weight: leaves[0].0,
+                count: 1,
+                tail: None,
+            },
+            Node {
+                weight: leaves[1].0,
+                count: 2,
+                tail: None,
+            },
+        ],
+        lists: [(0, 1); 15],
+        leaves: &leaves,
+    };
+
+    for _ in 0..2 * leaves.len() - 5 {
+        pm.boundary_pm(max_bits - 1);
+    }
+    pm.boundary_pm_final(max_bits - 1);
+
+    // the counts of leaves in each list's final chain give the lengths
+    let mut counts = [0; 16];
+    let mut end = 16;
+    let mut node = pm.lists[max_bits - 1].1;
+    loop {
+        end -= 1;
+        counts[end] = pm.nodes[node].count;
+        match pm.nodes[node].tail {
+            Some(tail) => node = tail,
+            None => break,
+        }
+    }
+
+    let mut val = counts[15];
+    let mut ptr = 15;
+    let mut value = 1;
+    while ptr >= end {
+        while val > counts[ptr - 1] {
+            lengths[leaves[val - 1].1] = value;
+            val -= 1;
+        }
+        ptr -= 1;
+        value += 1;
+    }
+
+    lengths
+}
+
+struct PackageMerge<'l> {
+    nodes: Vec<Node>,
+
+    /// The last two chains of each list: `lookahead0` and `lookahead1`.
+    lists: [(usize, usize); 15],
+    leaves: &'l [(usize, usize)],
+}
+
+impl<'l> PackageMerge<'l> {
+    fn node(&mut self, weight: usize, count: usize, tail: Option<usize>) -> usize {
+        self.nodes.push(Node {
+            weight,
+            count,
+            tail,
+        });
+        self.nodes.len() - 1
+    }
+
+    fn boundary_pm(&mut self, index: usize) {
+        let last_count = self.nodes[self.lists[index].1].count;
+        if 0 == index && last_count >= self.leaves.len() {
+            return;
+        }
+
+        self.lists[index].0 = self.lists[index].1;
+        let tail = self.nodes[self.lists[index].0].tail;
+
+        if 0 == index {
+            let weight = self.leaves[last_count].0;
+            self.lists[index].1 = self.node(weight, last_count + 1, tail);
+            return;
+        }
+
+        let (previous0, previous1) = self.lists[index - 1];
+        let weight_sum = self.nodes[previous0].weight + self.nodes[previous1].weight;
+        if last_count < self.leaves.len() && weight_sum > self.leaves[last_count].0 {
+            let weight = self.leaves[last_count].0;
+            self.lists[index].1 = self.node(weight, last_count + 1, tail);
+        } else {
+            self.lists[index].1 = self.node(weight_sum, last_count, Some(previous1));
+            self.boundary_pm(index - 1);
+            self.boundary_pm(index - 1);
+        }
+    }
+
+    fn boundary_pm_final(&mut self, index: usize) {
+        let last = self.lists[index].1;
+        let last_count = self.nodes[last].count;
+
+        let (previous0, previous1) = self.lists[index - 1];
+        let weight_sum = self.nodes[previous0].weight + self.nodes[previous1].weight;
+        if last_count < self.leaves.len() && weight_sum > self.leaves[last_count].0 {
+            let tail = self.nodes[last].tail;
+            self.lists[index].1 = self.node(0, last_count + 1, tail);
+        } else {
+            self.nodes[last].tail = Some(previous1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_merge() {
+        // the examples from the paper
+        let freqs = [1, 1, 5, 7, 10, 14];
+        assert_eq!(
+            vec![3, 3, 3, 3, 2, 2],
+            length_limited_code_lengths(&freqs, 3)
+        );
+        assert_eq!(
+            vec![4, 4, 3, 2, 2, 2],
+            length_limited_code_lengths(&freqs, 4)
+        );
+
+        let freqs = [252, 0, 1, 6, 9, 10, 6, 3, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(
+            vec![1, 0, 6, 4, 3, 3, 3, 5, 6, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            length_limited_code_lengths(&freqs, 7)
+        );
+
+        assert_eq!(
+            vec![0, 1, 0, 1],
+            length_limited_code_lengths(&[0, 3, 0, 9], 15)
+        );
+    }
+
+    #[test]
+    fn covering_reference() {
+        let data = b"abcabcabcabc";
+        let parsed = Parsed::new(b"", data, DEFAULT_ITERATIONS);
+        assert_eq!(Code::Literal(b'a'), parsed.code_at(0, b'a'));
+        assert_eq!(Code::Reference(Ref::new(3, 9)), parsed.code_at(3, b'a'));
+
+        // part way through the reference, what's left of it, until that's too short
+        assert_eq!(Code::Reference(Ref::new(3, 7)), parsed.code_at(5, b'c'));
+        assert_eq!(Code::Literal(b'b'), parsed.code_at(10, b'b'));
+    }
+
+    #[test]
+    fn block_ends() {
+        let codes = vec![Code::Literal(b'a'); 10];
+        assert_eq!(4, block_len(&[4, 10], 0, &codes));
+        assert_eq!(6, block_len(&[4, 10], 4, &codes[4..]));
+        assert_eq!(10, block_len(&[], 0, &codes));
+    }
+
+    #[test]
+    fn empty() {
+        let blocks = compress(b"", b"", DEFAULT_ITERATIONS);
+        assert_eq!(1, blocks.len());
+        assert_eq!(0, blocks[0].end);
+        assert!(blocks[0].codes.is_empty());
+    }
+}
@@ -0,0 +1,94 @@
+extern crate librezip;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use librezip::block_type::TypeHint;
+use librezip::metadata;
+use librezip::metadata::Stretch;
+use librezip::split::BlockEnd;
+use librezip::Config;
+use librezip::Trace;
+
+fn ends(level: u8, file: &[u8]) -> Vec<BlockEnd> {
+    let (meta, _) = metadata::reduce(io::Cursor::new(file), Config::gzip(level)).unwrap();
+
+    let mut ends = Vec::new();
+    for stretch in meta.members.iter().flat_map(|member| &member.stretches) {
+        match stretch {
+            Stretch::Uncompressed { .. } => panic!("unexpected stored block"),
+            Stretch::Huffman { trace, blocks, .. } => {
+                assert!(trace.iter().all(|&x| Trace::Correct == x));
+                ends.extend(blocks.iter().map(|block| block.end));
+            }
+        }
+    }
+    ends
+}
+
+#[test]
+fn buffer_full() {
+    // 32,767 codes, then the rest
+    assert_eq!(
+        vec![BlockEnd::Predicted; 2],
+        ends(1, include_bytes!("data/blockandabit-sixteen-1.gz"))
+    );
+}
+
+#[test]
+fn guessed_fast() {
+    // 4,096 codes, after which the data is compressing well enough to end the block
+    assert_eq!(
+        vec![BlockEnd::Predicted; 2],
+        ends(3, include_bytes!("data/fox-twelve-3.gz"))
+    );
+}
+
+#[test]
+fn guessed_lazy() {
+    assert_eq!(
+        vec![BlockEnd::Predicted; 2],
+        ends(4, include_bytes!("data/fox-twelve-4.gz"))
+    );
+}
+
+#[test]
+fn sync_flushed() {
+    // written by a streaming compressor, which flushes wherever it likes, with empty stored blocks
+    let file = include_bytes!("data/librole-basic-perl_0.13-1.debian.tar.gz");
+    let (meta, data) = metadata::reduce(io::Cursor::new(&file[..]), Config::gzip(6)).unwrap();
+
+    let empty_stored = meta
+        .members
+        .iter()
+        .flat_map(|member| &member.stretches)
+        .filter(|stretch| Stretch::Uncompressed { len: 0 } == **stretch)
+        .count();
+    assert_eq!(8, empty_stored);
+
+    let mut rebuilt = Vec::new();
+    metadata::expand(&meta, &data, &mut rebuilt).unwrap();
+    assert_eq!(&file[..], rebuilt.as_slice());
+}
+
+#[test]
+fn block_types_predicted() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let file = fs::read(entry.unwrap().path()).unwrap();
+        if !file.starts_with(&[0x1f, 0x8b]) {
+            continue;
+        }
+
+        let (meta, _) = metadata::reduce(io::Cursor::new(&file), Config::gzip(6)).unwrap();
+        for stretch in meta.members.iter().flat_map(|member| &member.stretches) {
+            if let Stretch::Huffman { blocks, .. } = stretch {
+                assert!(blocks
+                    .iter()
+                    .all(|block| TypeHint::Predicted == block.block_type));
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Helpers shared by the emulation tests; each test crate uses a different subset.
+
+#![allow(dead_code)]
+
+use std::io::Cursor;
+
+use itertools::Itertools;
+use rand::Rng;
+use rand::SeedableRng;
+
+use librezip::block_type;
+use librezip::block_type::BlockType;
+use librezip::split;
+use librezip::split::BlockEnd;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Code;
+use librezip::Config;
+use librezip::Trace;
+
+pub const GZIP_MD: &[u8] = include_bytes!("../../../gzip.md");
+pub const LICENSE: &[u8] = include_bytes!("../../../LICENSE");
+
+/// Each run of huffman blocks must trace with no corrections at all, and every block must
+/// end where, and be the type, the compressor would have picked.
+pub fn assert_all_correct(config: Config, compressed: &[u8], expected: &[u8]) {
+    assert_blocks_correct(config, compressed, expected, true)
+}
+
+/// As `assert_all_correct`, for compressors whose block types aren't emulated.
+pub fn assert_all_correct_but_types(config: Config, compressed: &[u8], expected: &[u8]) {
+    assert_blocks_correct(config, compressed, expected, false)
+}
+
+fn assert_blocks_correct(config: Config, compressed: &[u8], expected: &[u8], types: bool) {
+    let mut data = Vec::new();
+    let mut dictionary = CircularBuffer::new();
+    let mut pending: Vec<Vec<Code>> = Vec::new();
+    let mut pending_start = 0;
+
+    for block in librezip::parse_deflate(&mut Cursor::new(compressed)) {
+        let block = block.unwrap();
+        let start = data.len();
+        librezip::decompressed_block(&mut data, &mut dictionary, &block).unwrap();
+
+        let (codes, block_type) = match block {
+            Block::Uncompressed(_) => {
+                assert_stretch_correct(config, &data[..start], pending_start, &pending);
+                pending.clear();
+                pending_start = data.len();
+                continue;
+            }
+            Block::FixedHuffman(codes) => (codes, BlockType::FixedHuffman),
+            Block::DynamicHuffman { codes, .. } => (codes, BlockType::DynamicHuffman),
+        };
+
+        if types {
+            assert_eq!(block_type, block_type::predict(&codes, &config));
+        }
+        pending.push(codes);
+    }
+
+    assert_stretch_correct(config, &data, pending_start, &pending);
+    assert_eq!(expected, data.as_slice());
+}
+
+fn assert_stretch_correct(config: Config, data: &[u8], start: usize, blocks: &[Vec<Code>]) {
+    if blocks.is_empty() {
+        return;
+    }
+
+    let codes: Vec<Code> = blocks.iter().flatten().cloned().collect();
+    let lens: Vec<usize> = blocks.iter().map(|block| block.len()).collect();
+
+    let preroll_start = start.saturating_sub(32 * 1024);
+    let preroll = &data[preroll_start..start];
+    let trace =
+        librezip::tracer::try_config(config, preroll_start, preroll, &data[start..], &codes);
+    assert!(
+        trace.iter().all(|&x| Trace::Correct == x),
+        "must be fully correct: {}",
+        trace.iter().map(|x| format!("{:?}", x)).join("")
+    );
+
+    assert_eq!(
+        vec![BlockEnd::Predicted; lens.len()],
+        split::hint(&config.splitter, preroll, &data[start..], &codes, &lens)
+    );
+}
+
+/// Words from a small vocabulary: compressible, but with plenty of choice.
+pub fn random_text(len: usize) -> Vec<u8> {
+    let words: Vec<&[u8]> = vec![
+        b"the ", b"a ", b"fox ", b"jumps ", b"over\n", b"lazy ", b"dog ",
+    ];
+    let mut rng = rand::rngs::StdRng::seed_from_u64(17);
+    let mut text = Vec::with_capacity(len + 8);
+    while text.len() < len {
+        text.extend_from_slice(words[rng.gen_range(0, words.len())]);
+    }
+    text
+}
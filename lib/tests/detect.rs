@@ -0,0 +1,152 @@
+extern crate flate2;
+extern crate libdeflater;
+extern crate librezip;
+extern crate miniz_oxide;
+
+use std::io::Cursor;
+
+use flate2::Compress;
+use flate2::Compression;
+use flate2::FlushCompress;
+use libdeflater::CompressionLvl;
+use libdeflater::Compressor;
+
+use librezip::detect;
+use librezip::detect::Detected;
+use librezip::gzip::GzipHeader;
+use librezip::zlib;
+use librezip::Config;
+use librezip::Strategy;
+
+fn gzip(file: &[u8]) -> Option<Detected> {
+    let mut reader = Cursor::new(file);
+    GzipHeader::read(&mut reader).unwrap();
+    detect::detect(librezip::parse_deflate(&mut reader)).unwrap()
+}
+
+fn zlib_compress(level: u32, window_bits: u8, data: &[u8]) -> Vec<u8> {
+    let mut compress = Compress::new_with_window_bits(Compression::new(level), true, window_bits);
+    let mut out = Vec::with_capacity(data.len());
+    compress
+        .compress_vec(data, &mut out, FlushCompress::Finish)
+        .unwrap();
+    out
+}
+
+fn zlib(level: u32, window_bits: u8, data: &[u8]) -> Option<Detected> {
+    let mut reader = Cursor::new(zlib_compress(level, window_bits, data));
+    let header = zlib::Header::read(&mut reader).unwrap();
+    detect::detect_window(librezip::parse_deflate(&mut reader), header.window_bits()).unwrap()
+}
+
+#[test]
+fn gzip_levels() {
+    for &(level, file) in &[
+        (3, &include_bytes!("data/fox-twelve-3.gz")[..]),
+        (4, &include_bytes!("data/fox-twelve-4.gz")[..]),
+    ] {
+        let detected = gzip(file).unwrap();
+        assert_eq!(Config::gzip(level), detected.config);
+        assert_eq!(1.0, detected.confidence);
+    }
+}
+
+#[test]
+fn gzip_rsyncable() {
+    // the first block ends at the first boundary, where plain gzip would have carried on
+    for file in &[
+        &include_bytes!("data/libcgi-untaint-email-perl-rsyncable-3.gz")[..],
+        &include_bytes!("data/libcgi-untaint-email-perl-rsyncable-9.gz")[..],
+    ] {
+        let detected = gzip(file).unwrap();
+        assert!(detected.config.splitter.rsyncable());
+        assert_eq!(1.0, detected.confidence);
+    }
+}
+
+#[test]
+fn zlib_block_ends() {
+    // enough codes that zlib ends a block, which gzip wouldn't have
+    let data: Vec<u8> = (0..10_000u32)
+        .flat_map(|i| format!("{:08x}\n", i.wrapping_mul(2_654_435_761)).into_bytes())
+        .collect();
+
+    for &level in &[1, 6] {
+        let detected = zlib(u32::from(level), 15, &data).unwrap();
+        assert_eq!(
+            Config::zlib(level, 8, 15, Strategy::Default),
+            detected.config
+        );
+        assert_eq!(1.0, detected.confidence);
+    }
+}
+
+#[test]
+fn zlib_small_windows() {
+    let data: Vec<u8> = (0..10_000u32)
+        .flat_map(|i| format!("{:08x}\n", i.wrapping_mul(2_654_435_761)).into_bytes())
+        .collect();
+
+    for &window_bits in &[9, 12] {
+        // the levels can tie on so little data, but the window must be right
+        let detected = zlib(6, window_bits, &data).unwrap();
+        assert_eq!(
+            Config::zlib(6, 8, window_bits, Strategy::Default).max_distance,
+            detected.config.max_distance
+        );
+        assert_eq!(1.0, detected.confidence);
+
+        // ignoring the header, references are expected further back than zlib could look
+        let compressed = zlib_compress(6, window_bits, &data);
+        let detected = detect::detect(librezip::parse_deflate(Cursor::new(&compressed[2..])))
+            .unwrap()
+            .unwrap();
+        assert!(detected.confidence < 1.0);
+    }
+}
+
+#[test]
+fn miniz_levels() {
+    let data: Vec<u8> = (0..10_000u32)
+        .flat_map(|i| format!("{:08x}\n", i.wrapping_mul(2_654_435_761)).into_bytes())
+        .collect();
+
+    for &level in &[1, 6] {
+        let deflated = miniz_oxide::deflate::compress_to_vec(&data, level);
+        let detected = detect::detect(librezip::parse_deflate(Cursor::new(deflated)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Config::miniz(level, Strategy::Default), detected.config);
+        assert_eq!(1.0, detected.confidence);
+    }
+}
+
+#[test]
+fn libdeflate_levels() {
+    let data: Vec<u8> = (0..10_000u32)
+        .flat_map(|i| format!("{:08x}\n", i.wrapping_mul(2_654_435_761)).into_bytes())
+        .collect();
+
+    for &level in &[1, 6] {
+        let mut compressor = Compressor::new(CompressionLvl::new(i32::from(level)).unwrap());
+        let mut deflated = vec![0u8; compressor.deflate_compress_bound(data.len())];
+        let written = compressor.deflate_compress(&data, &mut deflated).unwrap();
+        deflated.truncate(written);
+
+        let detected = detect::detect(librezip::parse_deflate(Cursor::new(deflated)))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Config::libdeflate(level), detected.config);
+        assert_eq!(1.0, detected.confidence);
+    }
+}
+
+#[test]
+fn only_stored() {
+    // a single, empty, final stored block
+    let stored = [0x01, 0x00, 0x00, 0xff, 0xff];
+    assert_eq!(
+        None,
+        detect::detect(librezip::parse_deflate(Cursor::new(&stored[..]))).unwrap()
+    );
+}
@@ -0,0 +1,70 @@
+extern crate itertools;
+extern crate librezip;
+
+use itertools::Itertools;
+
+use librezip::block_type;
+use librezip::block_type::BlockType;
+use librezip::split;
+use librezip::split::BlockEnd;
+use librezip::zip;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Code;
+use librezip::Config;
+use librezip::Trace;
+
+/// Every deflated member must trace with no corrections, and its blocks must end where,
+/// and be the type, `zip` would have picked.
+fn assert_members_correct(level: u8, file: &[u8]) {
+    let config = Config::info_zip(level);
+    let archive = zip::read(file).unwrap();
+    let mut seen = 0;
+
+    for entry in &archive.entries {
+        let blocks = match entry.contents {
+            zip::Contents::Deflate(ref blocks) => blocks,
+            zip::Contents::Deflate64(_) | zip::Contents::Raw(_) => continue,
+        };
+
+        let mut data = Vec::new();
+        let mut dictionary = CircularBuffer::new();
+        let mut codes: Vec<Code> = Vec::new();
+        let mut lens = Vec::new();
+
+        for block in blocks {
+            librezip::decompressed_block(&mut data, &mut dictionary, block).unwrap();
+            let (block_codes, expected) = match *block {
+                Block::FixedHuffman(ref codes) => (codes, BlockType::FixedHuffman),
+                Block::DynamicHuffman { ref codes, .. } => (codes, BlockType::DynamicHuffman),
+                Block::Uncompressed(_) => panic!("text is never stored"),
+            };
+            assert_eq!(expected, block_type::predict(block_codes, &config));
+            codes.extend_from_slice(block_codes);
+            lens.push(block_codes.len());
+        }
+
+        let trace = librezip::tracer::try_config(config, 0, &[], &data, &codes);
+        assert!(
+            trace.iter().all(|&x| Trace::Correct == x),
+            "must be fully correct: {}",
+            trace.iter().map(|x| format!("{:?}", x)).join("")
+        );
+
+        assert_eq!(
+            vec![BlockEnd::Predicted; lens.len()],
+            split::hint(&config.splitter, &[], &data, &codes, &lens)
+        );
+
+        seen += 1;
+    }
+
+    assert_ne!(0, seen);
+}
+
+#[test]
+fn levels() {
+    assert_members_correct(1, include_bytes!("data/info-zip-1.zip"));
+    assert_members_correct(6, include_bytes!("data/info-zip.zip"));
+    assert_members_correct(9, include_bytes!("data/info-zip-9.zip"));
+}
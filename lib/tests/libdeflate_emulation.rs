@@ -0,0 +1,65 @@
+extern crate itertools;
+extern crate libdeflater;
+extern crate librezip;
+extern crate rand;
+
+mod common;
+
+use libdeflater::CompressionLvl;
+use libdeflater::Compressor;
+use rand::Rng;
+use rand::SeedableRng;
+
+use librezip::Config;
+
+use common::assert_all_correct_but_types;
+use common::random_text;
+use common::GZIP_MD;
+use common::LICENSE;
+
+fn libdeflate(level: u8, data: &[u8]) -> Vec<u8> {
+    let mut compressor = Compressor::new(CompressionLvl::new(i32::from(level)).unwrap());
+    let mut out = vec![0u8; compressor.deflate_compress_bound(data.len())];
+    let written = compressor.deflate_compress(data, &mut out).unwrap();
+    out.truncate(written);
+    out
+}
+
+/// Few distinct literals, so libdeflate insists on longer matches.
+fn random_dna(len: usize) -> Vec<u8> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(19);
+    (0..len).map(|_| b"ACGT"[rng.gen_range(0, 4)]).collect()
+}
+
+fn hex_lines(count: u32) -> Vec<u8> {
+    (0..count)
+        .flat_map(|i| format!("{:08x}\n", i).into_bytes())
+        .collect()
+}
+
+#[test]
+fn text_every_level() {
+    for level in 1..=9 {
+        for &data in &[LICENSE, GZIP_MD] {
+            assert_all_correct_but_types(Config::libdeflate(level), &libdeflate(level, data), data);
+        }
+    }
+}
+
+#[test]
+fn random_text_every_level() {
+    // long enough for several blocks, and to end some of them on the statistics
+    let data = random_text(300 * 1024);
+    for level in 1..=9 {
+        assert_all_correct_but_types(Config::libdeflate(level), &libdeflate(level, &data), &data);
+    }
+}
+
+#[test]
+fn few_literals() {
+    for data in &[random_dna(100 * 1024), hex_lines(20_000)] {
+        for level in 1..=9 {
+            assert_all_correct_but_types(Config::libdeflate(level), &libdeflate(level, data), data);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+extern crate flate2;
+extern crate libdeflater;
+extern crate librezip;
+extern crate miniz_oxide;
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use flate2::Compress;
+use flate2::Compression;
+use flate2::FlushCompress;
+use libdeflater::CompressionLvl;
+use libdeflater::Compressor;
+
+use librezip::gzip::GzipFooter;
+use librezip::metadata;
+use librezip::metadata::Footer;
+use librezip::metadata::Stretch;
+use librezip::split::BlockEnd;
+use librezip::Config;
+use librezip::Strategy;
+use librezip::Trace;
+
+fn round_trip(path: &Path) {
+    let orig = fs::read(path).unwrap();
+    assert_round_trips(&orig, Config::gzip_16_default());
+}
+
+fn assert_round_trips(orig: &[u8], config: Config) -> metadata::Metadata {
+    let (meta, data) = metadata::reduce(io::Cursor::new(orig), config).unwrap();
+
+    let mut stored = Vec::new();
+    metadata::write(&meta, &data, &mut stored).unwrap();
+    let meta_read = metadata::read(io::Cursor::new(&stored), &data).unwrap();
+    assert_eq!(meta, meta_read);
+
+    let mut rebuilt = Vec::with_capacity(orig.len());
+    metadata::expand(&meta_read, &data, &mut rebuilt).unwrap();
+
+    assert_eq!(orig, rebuilt.as_slice(), "must be rebuilt exactly");
+    meta
+}
+
+fn is_gzip(path: &Path) -> bool {
+    fs::read(path).unwrap().starts_with(&[0x1f, 0x8b])
+}
+
+#[test]
+fn everything_round_trips() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+    let mut seen = 0;
+
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if !is_gzip(&path) {
+            continue;
+        }
+
+        round_trip(&path);
+        seen += 1;
+    }
+
+    assert_ne!(0, seen);
+}
+
+#[test]
+fn members_round_trip() {
+    // `cat fox.gz lol.gz fox.gz`, then some padding; each member is compressed afresh,
+    // so the second fox traces as well as the first
+    let fox: &[u8] = include_bytes!("data/fox-twelve-4.gz");
+    let lol: &[u8] = include_bytes!("data/lol.gz");
+    let mut orig = Vec::new();
+    for file in &[fox, lol, fox, b"\0\0\0\0"] {
+        orig.extend_from_slice(file);
+    }
+
+    let meta = assert_round_trips(&orig, Config::gzip(4));
+    assert_eq!(3, meta.members.len());
+    assert!(meta.members.iter().all(|member| member.footer.is_some()));
+    assert_eq!(b"\0\0\0\0", meta.trailer.as_slice());
+
+    for member in &[&meta.members[0], &meta.members[2]] {
+        for stretch in &member.stretches {
+            if let Stretch::Huffman { trace, .. } = stretch {
+                assert!(trace.iter().all(|&t| Trace::Correct == t));
+            }
+        }
+    }
+
+    // each footer only covers its own member's data
+    let (_, mut data) = metadata::reduce(io::Cursor::new(&orig), Config::gzip(4)).unwrap();
+    let lol_start = meta.members[0].len();
+    data[lol_start] ^= 1;
+    let err = metadata::expand(&meta, &data, &mut Vec::new()).unwrap_err();
+    assert!(err.to_string().contains("member 1"), "{}", err);
+}
+
+#[test]
+fn level_changed_midway() {
+    // zlib's `deflateParams` ended the level 1 block, then carried on at level 9
+    let meta = assert_round_trips(
+        include_bytes!("data/license-1-gzip-md-9.gz"),
+        Config::zlib(1, 8, 15, Strategy::Default),
+    );
+
+    match meta.members[0].stretches.as_slice() {
+        [Stretch::Huffman { config: None, .. }, Stretch::Huffman {
+            config: Some(config),
+            ..
+        }] => {
+            // level 1 is greedy; the rest of the file can only be explained by a lazy level
+            assert!(config.wams.lookahead.is_some());
+        }
+        other => panic!("the second level must be recorded: {:?}", other),
+    }
+}
+
+#[test]
+fn rsyncable_round_trips() {
+    // `gzip --rsyncable` of a Debian `.orig.tar`, which flushed 26 times, and had to pad all
+    // but one of them
+    for &(level, orig) in &[
+        (
+            3,
+            &include_bytes!("data/libcgi-untaint-email-perl-rsyncable-3.gz")[..],
+        ),
+        (
+            9,
+            &include_bytes!("data/libcgi-untaint-email-perl-rsyncable-9.gz")[..],
+        ),
+    ] {
+        let meta = assert_round_trips(orig, Config::gzip_rsyncable(level));
+        match meta.members[0].stretches.as_slice() {
+            [Stretch::Huffman {
+                config: None,
+                trace,
+                blocks,
+                ..
+            }] => {
+                assert!(trace.iter().all(|&t| Trace::Correct == t));
+                assert!(blocks.iter().all(|block| BlockEnd::Predicted == block.end));
+                assert_eq!(27, blocks.len());
+            }
+            other => panic!("the padding mustn't split the stretch: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn miniz_round_trips() {
+    let data = include_bytes!("../../gzip.md");
+
+    for &level in &[1, 6] {
+        // the smallest possible gzip header, then miniz's raw `DEFLATE` stream
+        let mut orig = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+        orig.extend(miniz_oxide::deflate::compress_to_vec(data, level));
+        GzipFooter::of(data).write(&mut orig).unwrap();
+
+        let meta = assert_round_trips(&orig, Config::miniz(level, Strategy::Default));
+        match meta.members[0].stretches.as_slice() {
+            [Stretch::Huffman { config: None, .. }] => (),
+            other => panic!("miniz must explain the whole file: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn libdeflate_round_trips() {
+    let data = include_bytes!("../../gzip.md");
+
+    // the near-optimal levels are only approximated, so must be recorded as corrections
+    for &level in &[1, 6, 12] {
+        let mut compressor = Compressor::new(CompressionLvl::new(level).unwrap());
+        let mut orig = vec![0u8; compressor.gzip_compress_bound(data.len())];
+        let written = compressor.gzip_compress(data, &mut orig).unwrap();
+        orig.truncate(written);
+
+        assert_round_trips(&orig, Config::libdeflate(level as u8));
+    }
+}
+
+#[test]
+fn zlib_round_trips() {
+    let data = include_bytes!("../../gzip.md");
+
+    for &window_bits in &[9, 15] {
+        let mut compress = Compress::new_with_window_bits(Compression::new(6), true, window_bits);
+        let mut orig = Vec::with_capacity(data.len());
+        compress
+            .compress_vec(data, &mut orig, FlushCompress::Finish)
+            .unwrap();
+        orig.extend_from_slice(b"garbage");
+
+        // the window comes from the header, not the config
+        let meta = assert_round_trips(&orig, Config::zlib(6, 8, 15, Strategy::Default));
+        assert_eq!(
+            Config::zlib(6, 8, window_bits, Strategy::Default),
+            meta.config
+        );
+        assert_eq!(b"garbage", meta.trailer.as_slice());
+
+        match meta.members.as_slice() {
+            [member] => {
+                assert_eq!(
+                    Some(Footer::Zlib(librezip::zlib::adler32(data))),
+                    member.footer
+                );
+                for stretch in &member.stretches {
+                    if let Stretch::Huffman { trace, .. } = stretch {
+                        assert!(trace.iter().all(|&t| Trace::Correct == t));
+                    }
+                }
+            }
+            other => panic!("a zlib stream is one member: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,103 @@
+extern crate itertools;
+extern crate librezip;
+extern crate miniz_oxide;
+extern crate rand;
+
+mod common;
+
+use miniz_oxide::deflate::core::compress;
+use miniz_oxide::deflate::core::create_comp_flags_from_zip_params;
+use miniz_oxide::deflate::core::CompressionStrategy;
+use miniz_oxide::deflate::core::CompressorOxide;
+use miniz_oxide::deflate::core::TDEFLFlush;
+use miniz_oxide::deflate::core::TDEFLStatus;
+use rand::Rng;
+use rand::SeedableRng;
+
+use librezip::Config;
+use librezip::Strategy;
+
+use common::assert_all_correct;
+use common::random_text;
+use common::GZIP_MD;
+use common::LICENSE;
+
+/// `flate2`'s rust backend is `miniz_oxide`, but the `flate2` we depend on is built against
+/// zlib, so go straight to the source.
+fn miniz(level: u8, strategy: CompressionStrategy, data: &[u8]) -> Vec<u8> {
+    let mut compressor = CompressorOxide::new(create_comp_flags_from_zip_params(
+        i32::from(level),
+        -15,
+        strategy as i32,
+    ));
+    let mut out = vec![0u8; data.len() + 1024];
+    let (status, read, written) = compress(&mut compressor, data, &mut out, TDEFLFlush::Finish);
+    assert_eq!(TDEFLStatus::Done, status);
+    assert_eq!(data.len(), read);
+    out.truncate(written);
+    out
+}
+
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(18);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+#[test]
+fn text_every_level() {
+    for level in 1..=10 {
+        for &data in &[LICENSE, GZIP_MD] {
+            assert_all_correct(
+                Config::miniz(level, Strategy::Default),
+                &miniz(level, CompressionStrategy::Default, data),
+                data,
+            );
+        }
+    }
+}
+
+#[test]
+fn random_text_every_level() {
+    // long enough to fill the code buffer, and to wrap the `u16` positions in the chains
+    let data = random_text(300 * 1024);
+    for level in 1..=10 {
+        assert_all_correct(
+            Config::miniz(level, Strategy::Default),
+            &miniz(level, CompressionStrategy::Default, &data),
+            &data,
+        );
+    }
+}
+
+#[test]
+fn random_bytes_are_stored() {
+    // the stored block ends inside the first window, as positions are counted from the preroll
+    let mut data = random_bytes(32 * 1024);
+    data.extend(random_text(100 * 1024));
+    data.extend(random_bytes(10 * 1024));
+    for &level in &[1, 2, 6, 9] {
+        assert_all_correct(
+            Config::miniz(level, Strategy::Default),
+            &miniz(level, CompressionStrategy::Default, &data),
+            &data,
+        );
+    }
+}
+
+#[test]
+fn strategies() {
+    for &level in &[1, 3, 6] {
+        for &(strategy, miniz_strategy) in &[
+            (Strategy::Filtered, CompressionStrategy::Filtered),
+            (Strategy::HuffmanOnly, CompressionStrategy::HuffmanOnly),
+            (Strategy::Rle, CompressionStrategy::RLE),
+            (Strategy::Fixed, CompressionStrategy::Fixed),
+        ] {
+            assert_all_correct(
+                Config::miniz(level, strategy),
+                &miniz(level, miniz_strategy, GZIP_MD),
+                GZIP_MD,
+            );
+        }
+    }
+}
@@ -0,0 +1,196 @@
+extern crate itertools;
+extern crate librezip;
+extern crate libz_sys;
+extern crate rand;
+
+mod common;
+
+use std::io::Cursor;
+use std::mem;
+use std::os::raw::c_int;
+use std::os::raw::c_uint;
+use std::os::raw::c_void;
+use std::ptr;
+
+use itertools::Itertools;
+use libz_sys::*;
+
+use librezip::detect;
+use librezip::gzip::GzipFooter;
+use librezip::metadata;
+use librezip::metadata::Stretch;
+use librezip::split::BlockEnd;
+use librezip::Config;
+use librezip::Trace;
+
+use common::random_text;
+
+/// pigz's default `--blocksize`.
+const CHUNK: usize = 128 * 1024;
+const DICT: usize = 32 * 1024;
+
+extern "C" {
+    fn calloc(items: usize, size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+
+    // not in `libz_sys`, but in every zlib since 1.2.6
+    fn deflatePending(strm: z_streamp, pending: *mut c_uint, bits: *mut c_int) -> c_int;
+}
+
+extern "C" fn zalloc(_: voidpf, items: uInt, size: uInt) -> voidpf {
+    unsafe { calloc(items as usize, size as usize) }
+}
+
+extern "C" fn zfree(_: voidpf, ptr: voidpf) {
+    unsafe { free(ptr) }
+}
+
+/// What pigz's `compress_thread` asks of zlib for each chunk, one chunk at a time,
+/// wrapped in the smallest possible gzip header. This stands in for pigz, which isn't
+/// available to run here: it's the same sequence of zlib calls, against the system zlib,
+/// but none of these streams were written by a real pigz.
+fn pigz(level: c_int, chunk: usize, data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut buf = vec![0u8; 2 * chunk + 1024];
+
+    unsafe {
+        let mut strm = z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null_mut(),
+            state: ptr::null_mut(),
+            zalloc,
+            zfree,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        };
+        assert_eq!(
+            Z_OK,
+            deflateInit2_(
+                &mut strm,
+                level,
+                Z_DEFLATED,
+                -15,
+                8,
+                Z_DEFAULT_STRATEGY,
+                zlibVersion(),
+                mem::size_of::<z_stream>() as c_int,
+            )
+        );
+
+        let mut deflate_into = |strm: &mut z_stream, flush: c_int| {
+            strm.next_out = buf.as_mut_ptr();
+            strm.avail_out = buf.len() as uInt;
+            let ret = deflate(strm, flush);
+            assert!(Z_OK == ret || Z_STREAM_END == ret, "deflate: {}", ret);
+            assert_ne!(0, strm.avail_out, "buffer big enough");
+            out.extend_from_slice(&buf[..buf.len() - strm.avail_out as usize]);
+        };
+
+        for start in (0..data.len()).step_by(chunk) {
+            let end = (start + chunk).min(data.len());
+            let more = end < data.len();
+
+            assert_eq!(Z_OK, deflateReset(&mut strm));
+            assert_eq!(Z_OK, deflateParams(&mut strm, level, Z_DEFAULT_STRATEGY));
+            if 0 != start {
+                let dict = &data[start.saturating_sub(DICT)..start];
+                assert_eq!(
+                    Z_OK,
+                    deflateSetDictionary(&mut strm, dict.as_ptr(), dict.len() as uInt)
+                );
+            }
+
+            strm.next_in = data[start..end].as_ptr() as *mut u8;
+            strm.avail_in = (end - start) as uInt;
+
+            if !more {
+                deflate_into(&mut strm, Z_FINISH);
+                continue;
+            }
+
+            deflate_into(&mut strm, Z_BLOCK);
+
+            // add empty blocks to get to a byte boundary
+            let mut bits = 0;
+            assert_eq!(Z_OK, deflatePending(&mut strm, ptr::null_mut(), &mut bits));
+            if 0 != bits & 1 {
+                deflate_into(&mut strm, Z_SYNC_FLUSH);
+            } else if 0 != bits & 7 {
+                while 0 != bits & 7 {
+                    // a static block with nothing in it
+                    assert_eq!(Z_OK, deflatePrime(&mut strm, 10, 2));
+                    assert_eq!(Z_OK, deflatePending(&mut strm, ptr::null_mut(), &mut bits));
+                }
+                deflate_into(&mut strm, Z_BLOCK);
+            }
+        }
+
+        assert_eq!(Z_OK, deflateEnd(&mut strm));
+    }
+
+    GzipFooter::of(data).write(&mut out).unwrap();
+    out
+}
+
+/// Each chunk must be a stretch of its own, with no corrections at all, and no block
+/// ending anywhere unexpected.
+fn assert_all_correct(level: u8, orig: &[u8], data: &[u8]) {
+    let config = Config::pigz(level, CHUNK as u32);
+    let (meta, reduced) = metadata::reduce(Cursor::new(orig), config).unwrap();
+    assert_eq!(data, reduced.as_slice());
+
+    let mut pos = 0;
+    for stretch in meta.members.iter().flat_map(|member| &member.stretches) {
+        if let Stretch::Huffman {
+            len,
+            config,
+            ref trace,
+            ref blocks,
+        } = *stretch
+        {
+            assert_eq!(None, config);
+            assert!(
+                trace.iter().all(|&x| Trace::Correct == x),
+                "must be fully correct: {}",
+                trace.iter().map(|x| format!("{:?}", x)).join("")
+            );
+            assert!(blocks.iter().all(|block| BlockEnd::Predicted == block.end));
+            assert_eq!(0, pos % CHUNK, "stretches start chunks");
+            assert!(len <= CHUNK, "stretches end at the end of the chunk");
+        }
+        pos += stretch.len();
+    }
+
+    let mut rebuilt = Vec::with_capacity(orig.len());
+    metadata::expand(&meta, &reduced, &mut rebuilt).unwrap();
+    assert_eq!(orig, rebuilt.as_slice(), "must be rebuilt exactly");
+}
+
+#[test]
+fn chunks() {
+    // chunks end in a stored block or in empty static blocks, depending on how many bits
+    // are left over, so take a few
+    let data = random_text(3 * CHUNK + 1234);
+    // pigz's -1, -6 (the default), and -9
+    for &level in &[1, 6, 9] {
+        assert_all_correct(level, &pigz(c_int::from(level), CHUNK, &data), &data);
+    }
+}
+
+#[test]
+fn detected() {
+    // only the default blocksize is detected
+    let data = random_text(2 * CHUNK);
+    let orig = pigz(6, CHUNK, &data);
+    let detected = detect::detect(librezip::parse_deflate(Cursor::new(&orig[10..])))
+        .unwrap()
+        .unwrap();
+    assert_eq!(Config::pigz(6, CHUNK as u32), detected.config);
+}
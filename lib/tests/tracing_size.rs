@@ -5,16 +5,23 @@ use std::io;
 
 use itertools::Itertools;
 
+use librezip::serialise_trace;
+use librezip::tracer;
 use librezip::Block;
 use librezip::CircularBuffer;
+use librezip::Code;
+use librezip::Config;
 use librezip::Trace;
 
-fn run_gzip(level: u8, file: &[u8]) -> Vec<Vec<Trace>> {
+/// Where the preroll starts, the preroll, the data, and the codes, of each block in the
+/// gzip `file`.
+fn blocks(file: &[u8]) -> Vec<(usize, Vec<u8>, Vec<u8>, Vec<Code>)> {
     let mut reader = io::Cursor::new(file);
-    librezip::gzip::discard_header(&mut reader).unwrap();
+    librezip::gzip::GzipHeader::read(&mut reader).unwrap();
 
     let mut dictionary = CircularBuffer::new();
-    let mut parts = Vec::new();
+    let mut blocks = Vec::new();
+    let mut pos = 0;
 
     for block in librezip::parse_deflate(&mut reader) {
         let codes = match block.unwrap() {
@@ -22,14 +29,25 @@ fn run_gzip(level: u8, file: &[u8]) -> Vec<Vec<Trace>> {
             Block::DynamicHuffman { codes, .. } | Block::FixedHuffman(codes) => codes,
         };
 
-        let preroll = &dictionary.vec();
+        let preroll = dictionary.vec();
         let mut data: Vec<u8> = Vec::with_capacity(codes.len());
         librezip::decompressed_codes(&mut data, &mut dictionary, &codes).unwrap();
 
-        parts.push(librezip::tracer::try_gzip(level, preroll, &data, &codes));
+        let preroll_start = pos - preroll.len();
+        pos += data.len();
+        blocks.push((preroll_start, preroll, data, codes));
     }
 
-    parts
+    blocks
+}
+
+fn run_gzip(level: u8, file: &[u8]) -> Vec<Vec<Trace>> {
+    blocks(file)
+        .into_iter()
+        .map(|(preroll_start, preroll, data, codes)| {
+            librezip::tracer::try_gzip(level, preroll_start, &preroll, &data, &codes)
+        })
+        .collect()
 }
 
 fn try_gzip(level: u8, file: &[u8]) {
@@ -45,6 +63,13 @@ fn try_gzip(level: u8, file: &[u8]) {
     }
 }
 
+// yes aaaaaaaaaa | tr -d '\n' | head -c 100000 | gzip -6
+// gzip can't reference the first byte, so starts with two literals
+#[test]
+fn first_byte_bug() {
+    try_gzip(6, include_bytes!("data/aaaaaa-twelve-6.gz"))
+}
+
 // tiny-decay:
 // 1abcdef,bcdef-cdef
 // 012345678901234567
@@ -202,3 +227,50 @@ fn blockandabit_hard() {
 fn blockandabit_newlines() {
     try_gzip(1, include_bytes!("data/blockandabitnewlines-sixteen-1.gz"))
 }
+
+#[test]
+fn modelled_corrections_are_smaller() {
+    // level 6's guesses for a level 4 file, so there's plenty to correct, but the
+    // compressor was looking at the same chains
+    let config = Config::gzip(6);
+    for (preroll_start, preroll, data, codes) in blocks(include_bytes!("data/fox-twelve-4.gz")) {
+        let trace = tracer::try_config(config, preroll_start, &preroll, &data, &codes);
+        assert!(trace.iter().any(|&t| Trace::Correct != t));
+
+        let modelled = tracer::encode(config, preroll_start, &preroll, &data, &trace);
+        assert_eq!(
+            trace,
+            tracer::decode(config, preroll_start, &preroll, &data, &modelled).unwrap()
+        );
+
+        // the corrections are nearly always references the compressor could see
+        let absolute = trace
+            .iter()
+            .filter(|t| match t {
+                Trace::Actually(_) => true,
+                _ => false,
+            })
+            .count();
+        let candidates = trace
+            .iter()
+            .filter(|t| match t {
+                Trace::Candidate { .. } => true,
+                _ => false,
+            })
+            .count();
+        assert!(
+            absolute * 10 < candidates,
+            "{} vs. {}",
+            absolute,
+            candidates
+        );
+
+        let runs = serialise_trace::write(&trace);
+        assert!(
+            modelled.len() * 3 < runs.len() * 2,
+            "{} modelled, {} as runs",
+            modelled.len(),
+            runs.len()
+        );
+    }
+}
@@ -0,0 +1,149 @@
+extern crate flate2;
+extern crate itertools;
+extern crate librezip;
+
+use std::io::Cursor;
+
+use flate2::Compress;
+use flate2::Compression;
+use flate2::FlushCompress;
+use itertools::Itertools;
+
+use librezip::split;
+use librezip::split::BlockEnd;
+use librezip::zlib;
+use librezip::Block;
+use librezip::CircularBuffer;
+use librezip::Config;
+use librezip::Strategy;
+use librezip::Trace;
+
+const GZIP_MD: &[u8] = include_bytes!("../../gzip.md");
+const LICENSE: &[u8] = include_bytes!("../../LICENSE");
+
+/// The whole zlib stream must trace in one pass with no corrections at all,
+/// and every block must end where the compressor would have ended it.
+fn assert_all_correct(config: Config, compressed: &[u8], expected: &[u8]) {
+    let mut reader = Cursor::new(compressed);
+    let header = zlib::Header::read(&mut reader).unwrap();
+    let config = config.with_window_bits(header.window_bits());
+
+    let mut codes = Vec::new();
+    let mut lens = Vec::new();
+
+    for block in librezip::parse_deflate(&mut reader) {
+        match block.unwrap() {
+            Block::Uncompressed(_) => panic!("zlib only stores incompressible data"),
+            Block::FixedHuffman(block_codes)
+            | Block::DynamicHuffman {
+                codes: block_codes, ..
+            } => {
+                lens.push(block_codes.len());
+                codes.extend(block_codes);
+            }
+        }
+    }
+
+    let mut data = Vec::new();
+    librezip::decompressed_codes(&mut data, &mut CircularBuffer::new(), &codes).unwrap();
+
+    let trace = librezip::tracer::try_config(config, 0, &[], &data, &codes);
+    assert!(
+        trace.iter().all(|&x| Trace::Correct == x),
+        "must be fully correct: {}",
+        trace.iter().map(|x| format!("{:?}", x)).join("")
+    );
+
+    assert_eq!(
+        vec![BlockEnd::Predicted; lens.len()],
+        split::hint(&config.splitter, &[], &data, &codes, &lens)
+    );
+
+    assert_eq!(expected, data.as_slice());
+    assert_eq!(
+        zlib::adler32(expected),
+        zlib::read_trailer(&mut reader).unwrap()
+    );
+}
+
+fn flate2(level: u32, window_bits: u8, data: &[u8]) -> Vec<u8> {
+    let mut compress = Compress::new_with_window_bits(Compression::new(level), true, window_bits);
+    let mut out = Vec::with_capacity(data.len() + 128);
+    compress
+        .compress_vec(data, &mut out, FlushCompress::Finish)
+        .unwrap();
+    out
+}
+
+#[test]
+fn every_level() {
+    for level in 1..=9 {
+        for &data in &[LICENSE, GZIP_MD] {
+            assert_all_correct(
+                Config::zlib(level, 8, 15, Strategy::Default),
+                &flate2(u32::from(level), 15, data),
+                data,
+            );
+        }
+    }
+}
+
+#[test]
+fn small_windows() {
+    for &window_bits in &[9, 10, 12, 14] {
+        for &level in &[1, 4, 6, 9] {
+            assert_all_correct(
+                Config::zlib(level, 8, window_bits, Strategy::Default),
+                &flate2(u32::from(level), window_bits, GZIP_MD),
+                GZIP_MD,
+            );
+        }
+    }
+}
+
+#[test]
+fn filtered() {
+    assert_all_correct(
+        Config::zlib(6, 8, 15, Strategy::Filtered),
+        include_bytes!("data/gzip-md-filtered-6.zlib"),
+        GZIP_MD,
+    );
+}
+
+#[test]
+fn rle() {
+    assert_all_correct(
+        Config::zlib(6, 8, 15, Strategy::Rle),
+        include_bytes!("data/gzip-md-rle-6.zlib"),
+        GZIP_MD,
+    );
+}
+
+#[test]
+fn huffman_only() {
+    assert_all_correct(
+        Config::zlib(6, 8, 15, Strategy::HuffmanOnly),
+        include_bytes!("data/gzip-md-huffman-only-6.zlib"),
+        GZIP_MD,
+    );
+}
+
+#[test]
+fn mem_levels() {
+    // the hash is smaller, so more collisions use up the chain, and the blocks are tiny
+    assert_all_correct(
+        Config::zlib(3, 1, 9, Strategy::Default),
+        include_bytes!("data/gzip-md-mem-1-window-9-3.zlib"),
+        GZIP_MD,
+    );
+    assert_all_correct(
+        Config::zlib(7, 3, 12, Strategy::Default),
+        include_bytes!("data/gzip-md-mem-3-window-12-7.zlib"),
+        GZIP_MD,
+    );
+    assert_all_correct(
+        Config::zlib(9, 9, 10, Strategy::Default),
+        include_bytes!("data/gzip-md-mem-9-window-10-9.zlib"),
+        GZIP_MD,
+    );
+}
@@ -0,0 +1,98 @@
+extern crate itertools;
+extern crate librezip;
+
+use std::io;
+use std::io::Cursor;
+
+use itertools::Itertools;
+
+use librezip::block_type::TypeHint;
+use librezip::detect;
+use librezip::gzip::GzipHeader;
+use librezip::metadata;
+use librezip::metadata::Stretch;
+use librezip::split::BlockEnd;
+use librezip::trees::Trees;
+use librezip::Config;
+use librezip::Trace;
+
+/// Written by the `zopfli` crate, 0.8.4, with its default options. All but the last have
+/// dynamic blocks, the first three split in two; the last is a single fixed block.
+const CORPUS: [&[u8]; 5] = [
+    include_bytes!("data/license-zopfli.gz"),
+    include_bytes!("data/libcgi-untaint-email-perl-zopfli.gz"),
+    include_bytes!("data/librole-basic-perl-zopfli.gz"),
+    include_bytes!("data/seq-20-zopfli.gz"),
+    include_bytes!("data/hello-zopfli.gz"),
+];
+
+/// Every stretch must trace with no corrections, and every block must end where, be the type,
+/// and have the trees, Zopfli would have picked, so the metadata is nothing but the config.
+fn assert_all_predicted(orig: &[u8]) {
+    let config = Config::zopfli(15);
+    let (meta, data) = metadata::reduce(Cursor::new(orig), config).unwrap();
+
+    let mut rebuilt = Vec::with_capacity(orig.len());
+    metadata::expand(&meta, &data, &mut rebuilt).unwrap();
+    assert_eq!(orig, rebuilt.as_slice(), "must be rebuilt exactly");
+
+    for stretch in &meta.members[0].stretches {
+        match *stretch {
+            Stretch::Huffman {
+                config: None,
+                ref trace,
+                ref blocks,
+                ..
+            } => {
+                assert!(
+                    trace.iter().all(|&x| Trace::Correct == x),
+                    "must be fully correct: {}",
+                    trace.iter().map(|x| format!("{:?}", x)).join("")
+                );
+
+                for block in blocks {
+                    assert_eq!(BlockEnd::Predicted, block.end);
+                    assert_eq!(TypeHint::Predicted, block.block_type);
+                    if let Some(ref trees) = block.trees {
+                        assert_eq!(Trees::Predicted, *trees);
+                    }
+                }
+            }
+            ref other => panic!("Zopfli must explain the whole file: {:?}", other),
+        }
+    }
+}
+
+#[test]
+fn corpus_predicted() {
+    for orig in &CORPUS {
+        assert_all_predicted(orig);
+    }
+}
+
+#[test]
+fn metadata_is_stable() {
+    let orig = CORPUS[0];
+    let (meta, data) = metadata::reduce(Cursor::new(orig), Config::zopfli(15)).unwrap();
+
+    let mut stored = Vec::new();
+    metadata::write(&meta, &data, &mut stored).unwrap();
+    assert_eq!(
+        meta,
+        metadata::read(io::Cursor::new(&stored), &data).unwrap()
+    );
+}
+
+#[test]
+fn detected() {
+    // a fixed block has no trees to give Zopfli away
+    for orig in &CORPUS[..CORPUS.len() - 1] {
+        let mut reader = Cursor::new(orig);
+        GzipHeader::read(&mut reader).unwrap();
+        let detected = detect::detect(librezip::parse_deflate(&mut reader))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Config::zopfli(15), detected.config);
+        assert_eq!(1.0, detected.confidence);
+    }
+}
@@ -1,21 +1,46 @@
 use std::io;
 use std::io::Read;
+use std::io::Write;
 
+use failure::err_msg;
 use failure::Error;
 use failure::ResultExt;
 use librezip;
+use librezip::gzip::GzipFooter;
 use librezip::CircularBuffer;
 
-pub fn run<R: Read>(mut reader: R) -> Result<(), Error> {
-    librezip::gzip::discard_header(&mut reader)?;
-
+pub fn run<R: Read>(reader: R) -> Result<(), Error> {
     let stdout = io::stdout();
     let mut stdout = stdout.lock();
 
-    let mut dictionary = CircularBuffer::new();
+    let mut members = librezip::gzip::members(reader);
+
+    while let Some(member) = members.next_member() {
+        let mut member = member?;
+
+        let mut dictionary = CircularBuffer::new();
+        let mut calculated = GzipFooter::default();
+        let mut buf = Vec::new();
+
+        for block in member.by_ref() {
+            buf.clear();
+            librezip::decompressed_block(&mut buf, &mut dictionary, &block?)?;
+            calculated.extend(&buf);
+            stdout.write_all(&buf)?;
+        }
+
+        member
+            .footer()?
+            .ok_or_else(|| err_msg("truncated file: no footer"))?
+            .check(&calculated)?;
+    }
 
-    for block in librezip::parse_deflate(&mut reader).into_iter() {
-        librezip::decompressed_block(&mut stdout, &mut dictionary, &block?)?;
+    let garbage = members.trailing_garbage();
+    if !garbage.is_empty() {
+        eprintln!(
+            "warning: {} bytes of trailing garbage ignored",
+            garbage.len()
+        );
     }
 
     Ok(())
@@ -7,26 +7,43 @@ use librezip;
 use librezip::Block;
 use librezip::Code;
 
-pub fn run<R: Read>(mut reader: R) -> Result<(), Error> {
-    librezip::gzip::discard_header(&mut reader)?;
-    for (id, block) in librezip::parse_deflate(&mut reader).into_iter().enumerate() {
-        let block = block?;
-
-        println!("block {}:", id);
-        use self::Block::*;
-        match block {
-            Uncompressed(data) => {
-                println!(" - uncompressed: {} bytes", data.len());
-            }
-            FixedHuffman(codes) => {
-                println!(" - fixed huffman:");
-                print(&codes);
-            }
-            DynamicHuffman { trees, codes } => {
-                println!(" - dynamic huffman: {:?}", trees);
-                print(&codes);
+pub fn run<R: Read>(reader: R) -> Result<(), Error> {
+    let mut members = librezip::gzip::members(reader);
+
+    let mut member_id = 0;
+    while let Some(member) = members.next_member() {
+        let mut member = member?;
+
+        println!("member {}:", member_id);
+        member_id += 1;
+
+        for (id, block) in member.by_ref().enumerate() {
+            let block = block?;
+            println!("block {}:", id);
+            use self::Block::*;
+            match block {
+                Uncompressed(data) => {
+                    println!(" - uncompressed: {} bytes", data.len());
+                }
+                FixedHuffman(codes) => {
+                    println!(" - fixed huffman:");
+                    print(&codes);
+                }
+                DynamicHuffman { trees, codes } => {
+                    println!(" - dynamic huffman: {:?}", trees);
+                    print(&codes);
+                }
             }
         }
+
+        if member.footer()?.is_none() {
+            println!("no footer");
+        }
+    }
+
+    let garbage = members.trailing_garbage();
+    if !garbage.is_empty() {
+        println!("trailing garbage: {} bytes", garbage.len());
     }
 
     Ok(())
@@ -43,8 +60,8 @@ fn print(codes: &[Code]) {
             Reference(r) => {
                 println!(
                     "    - backref: {} byte(s) back, {} bytes long",
-                    r.dist,
-                    r.run()
+                    r.wide_dist(),
+                    r.wide_run()
                 );
             }
         }
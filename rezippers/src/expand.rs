@@ -0,0 +1,22 @@
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+use failure::Error;
+use librezip::metadata;
+
+pub fn run<M: Read, D: Read>(metadata: M, mut data: D) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    data.read_to_end(&mut buf)?;
+
+    let metadata = metadata::read(io::BufReader::new(metadata), &buf)?;
+
+    let mut recompressed = Vec::new();
+    metadata::expand(&metadata, &buf, &mut recompressed)?;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(&recompressed)?;
+
+    Ok(())
+}
@@ -1,12 +1,12 @@
 extern crate byteorder;
 extern crate clap;
-extern crate crc;
 
-extern crate flate2;
 extern crate librezip;
 
 mod cat;
 mod dump;
+mod expand;
+mod reduce;
 mod zero;
 
 use std::fs;
@@ -32,12 +32,42 @@ fn main() -> Result<(), Error> {
             clap::SubCommand::with_name("zero")
                 .arg(Arg::with_name("file").index(1).required(false)),
         )
+        .subcommand(
+            clap::SubCommand::with_name("reduce")
+                .about("write the metadata needed to rebuild a gzip or zlib file from its content")
+                .arg(
+                    Arg::with_name("level")
+                        .long("level")
+                        .takes_value(true)
+                        .help("the gzip level the file was made with; detected if missing"),
+                )
+                .arg(Arg::with_name("file").index(1).required(false)),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("expand")
+                .about(
+                    "rebuild the original gzip or zlib file from `reduce` metadata and the content",
+                )
+                .arg(Arg::with_name("metadata").index(1).required(true))
+                .arg(Arg::with_name("data").index(2).required(true)),
+        )
         .get_matches();
 
     match matches.subcommand() {
         ("cat", Some(matches)) => cat::run(open_file(matches)?),
         ("dump", Some(matches)) => dump::run(open_file(matches)?),
         ("zero", Some(matches)) => zero::run(open_file(matches)?),
+        ("reduce", Some(matches)) => reduce::run(
+            open_file(matches)?,
+            match matches.value_of("level") {
+                Some(level) => Some(level.parse()?),
+                None => None,
+            },
+        ),
+        ("expand", Some(matches)) => expand::run(
+            fs::File::open(matches.value_of_os("metadata").unwrap())?,
+            fs::File::open(matches.value_of_os("data").unwrap())?,
+        ),
         _ => unreachable!(),
     }
 }
@@ -0,0 +1,86 @@
+use std::io;
+use std::io::Read;
+
+use failure::ensure;
+use failure::Error;
+use librezip::detect;
+use librezip::gzip::GzipHeader;
+use librezip::metadata;
+use librezip::split::Splitter;
+use librezip::zlib;
+use librezip::Config;
+use librezip::Strategy;
+
+pub fn run<R: Read>(mut reader: R, level: Option<u8>) -> Result<(), Error> {
+    let mut raw = Vec::new();
+    reader.read_to_end(&mut raw)?;
+
+    let config = match level {
+        Some(level) => {
+            ensure!((1..=9).contains(&level), "gzip levels are between 1 and 9");
+            Config::gzip(level)
+        }
+        None => detected(&raw)?,
+    };
+
+    let (metadata, data) = metadata::reduce(io::Cursor::new(&raw), config)?;
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    metadata::write(&metadata, &data, &mut stdout)
+}
+
+fn detected(raw: &[u8]) -> Result<Config, Error> {
+    let mut reader = io::Cursor::new(raw);
+
+    // zlib declares its window, which `metadata::reduce` also applies
+    let window_bits = match *raw {
+        [cmf, flg, ..] if zlib::is_header(cmf, flg) => {
+            zlib::Header::read(&mut reader)?.window_bits()
+        }
+        _ => {
+            GzipHeader::read(&mut reader)?;
+            15
+        }
+    };
+
+    Ok(
+        match detect::detect_window(librezip::parse_deflate(&mut reader), window_bits)? {
+            Some(detected) => {
+                eprintln!(
+                    "detected {} (confidence: {:.3})",
+                    label(&detected.config, window_bits),
+                    detected.confidence
+                );
+                detected.config
+            }
+            None => Config::gzip(6),
+        },
+    )
+}
+
+/// The compressor and level which would have written with this `config`, in a window of
+/// `1 << window_bits` bytes, or, if it's a variation on one, just the compressor.
+fn label(config: &Config, window_bits: u8) -> String {
+    let (family, max_level, at_level): (&str, u8, fn(u8) -> Config) = match config.splitter {
+        Splitter::Zlib { .. } => ("zlib", 9, |level| {
+            Config::zlib(level, 8, 15, Strategy::Default)
+        }),
+        Splitter::Gzip {
+            rsyncable: false, ..
+        } => ("gzip", 9, Config::gzip),
+        Splitter::Gzip {
+            rsyncable: true, ..
+        } => ("gzip --rsyncable", 9, Config::gzip_rsyncable),
+        Splitter::Miniz { .. } => ("miniz", 10, |level| Config::miniz(level, Strategy::Default)),
+        Splitter::Libdeflate { .. } => ("libdeflate", 12, Config::libdeflate),
+        Splitter::Pigz { .. } => ("pigz", 9, |level| Config::pigz(level, 128 * 1024)),
+        Splitter::Zopfli { iterations } => return format!("zopfli --i{}", iterations),
+    };
+
+    match (1..=max_level).find(|&level| at_level(level).with_window_bits(window_bits) == *config) {
+        Some(level) => format!("{} -{}", family, level),
+        None => format!("a variation on {}", family),
+    }
+}